@@ -0,0 +1,592 @@
+use std::fmt;
+
+use chrono::DateTime;
+use csv_async::{AsyncReaderBuilder, StringRecord};
+use futures_util::AsyncRead;
+use serde::de::DeserializeOwned;
+
+/// Annotations requested in the query dialect.
+pub(crate) const DIALECT_ANNOTATIONS: [&str; 3] = ["datatype", "group", "default"];
+
+const DATATYPES: [&str; 10] = [
+    "boolean",
+    "unsignedLong",
+    "long",
+    "double",
+    "string",
+    "base64Binary",
+    "dateTime",
+    "dateTime:RFC3339",
+    "dateTime:RFC3339Nano",
+    "duration",
+];
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// CSV reading or row deserialization error.
+    Csv(csv_async::Error),
+    /// Malformed annotation or data row.
+    Invalid { line: u64, reason: String },
+    /// Error reported by InfluxDB inside the response stream.
+    Query { message: String, reference: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Csv(err) => write!(f, "CSV error: {err}"),
+            Self::Invalid { line, reason } => write!(f, "invalid data at line {line}: {reason}"),
+            Self::Query { message, reference } if reference.is_empty() => {
+                write!(f, "query error: {message}")
+            }
+            Self::Query { message, reference } => {
+                write!(f, "query error: {message} (reference: {reference})")
+            }
+        }
+    }
+}
+
+impl Error {
+    /// Returns whether the error comes from reading the underlying stream.
+    pub(crate) fn is_io(&self) -> bool {
+        matches!(self, Self::Csv(err) if err.is_io_error())
+    }
+}
+
+impl From<csv_async::Error> for Error {
+    fn from(value: csv_async::Error) -> Self {
+        Self::Csv(value)
+    }
+}
+
+fn invalid(line: u64, reason: impl Into<String>) -> Error {
+    Error::Invalid {
+        line,
+        reason: reason.into(),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Table<T> {
+    /// Value of the `result` column, empty if absent.
+    pub(crate) result: String,
+    /// Value of the `table` column, empty if absent.
+    pub(crate) id: String,
+    pub(crate) rows: Vec<T>,
+}
+
+/// Annotation rows preceding a header row.
+#[derive(Default)]
+struct Annotations {
+    datatype: Option<StringRecord>,
+    group: Option<StringRecord>,
+    default: Option<StringRecord>,
+}
+
+impl Annotations {
+    fn is_empty(&self) -> bool {
+        self.datatype.is_none() && self.group.is_none() && self.default.is_none()
+    }
+
+    fn push(&mut self, record: &StringRecord, line: u64) -> Result<(), Error> {
+        let slot = match &record[0] {
+            "#datatype" => &mut self.datatype,
+            "#group" => &mut self.group,
+            "#default" => &mut self.default,
+            other => return Err(invalid(line, format!("unknown annotation `{other}`"))),
+        };
+        if slot.replace(record.clone()).is_some() {
+            return Err(invalid(
+                line,
+                format!("duplicate annotation `{}`", &record[0]),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A header row with its annotations, followed by data rows of one or more tables.
+struct Block {
+    header: StringRecord,
+    annotations: Annotations,
+    result_index: Option<usize>,
+    table_index: Option<usize>,
+}
+
+impl Block {
+    fn new(header: StringRecord, annotations: Annotations, line: u64) -> Result<Self, Error> {
+        if !annotations.is_empty() && !header[0].is_empty() {
+            return Err(invalid(
+                line,
+                "header of annotated table must begin with an empty column",
+            ));
+        }
+        let annotation_rows = [
+            &annotations.datatype,
+            &annotations.group,
+            &annotations.default,
+        ];
+        for annotation in annotation_rows.into_iter().flatten() {
+            if annotation.len() != header.len() {
+                return Err(invalid(
+                    line,
+                    format!(
+                        "annotation `{}` has {} columns, header has {}",
+                        &annotation[0],
+                        annotation.len(),
+                        header.len()
+                    ),
+                ));
+            }
+        }
+        if let Some(datatypes) = &annotations.datatype
+            && let Some(unknown) = datatypes.iter().skip(1).find(|d| !DATATYPES.contains(d))
+        {
+            return Err(invalid(line, format!("unknown datatype `{unknown}`")));
+        }
+        if let Some(groups) = &annotations.group
+            && let Some(unknown) = groups
+                .iter()
+                .skip(1)
+                .find(|g| !["true", "false"].contains(g))
+        {
+            return Err(invalid(line, format!("invalid group value `{unknown}`")));
+        }
+        let result_index = header.iter().position(|name| name == "result");
+        let table_index = header.iter().position(|name| name == "table");
+
+        Ok(Self {
+            header,
+            annotations,
+            result_index,
+            table_index,
+        })
+    }
+
+    fn is_error(&self) -> bool {
+        self.header
+            .iter()
+            .filter(|name| !name.is_empty())
+            .eq(["error", "reference"])
+    }
+
+    /// Validates a data row against the header and the annotations, and
+    /// returns it with default values applied.
+    fn prepare_row(&self, record: &StringRecord, line: u64) -> Result<StringRecord, Error> {
+        if record.len() != self.header.len() {
+            return Err(invalid(
+                line,
+                format!(
+                    "row has {} columns, header has {}",
+                    record.len(),
+                    self.header.len()
+                ),
+            ));
+        }
+        if self.annotations.is_empty() {
+            return Ok(record.clone());
+        }
+        if !record[0].is_empty() {
+            return Err(invalid(
+                line,
+                "row of annotated table must begin with an empty column",
+            ));
+        }
+        let mut row = StringRecord::with_capacity(record.as_slice().len(), record.len());
+        for (i, value) in record.iter().enumerate() {
+            let value = match &self.annotations.default {
+                Some(defaults) if value.is_empty() => &defaults[i],
+                _ => value,
+            };
+            if let Some(datatypes) = &self.annotations.datatype
+                && i > 0
+                && !value.is_empty()
+                && !is_valid_value(&datatypes[i], value)
+            {
+                return Err(invalid(
+                    line,
+                    format!(
+                        "value `{value}` of column `{}` is not a valid {}",
+                        &self.header[i], &datatypes[i]
+                    ),
+                ));
+            }
+            row.push_field(value);
+        }
+        Ok(row)
+    }
+
+    fn table_key<'r>(&self, row: &'r StringRecord) -> (&'r str, &'r str) {
+        let get = |index: Option<usize>| index.and_then(|i| row.get(i)).unwrap_or_default();
+        (get(self.result_index), get(self.table_index))
+    }
+}
+
+fn is_valid_value(datatype: &str, value: &str) -> bool {
+    match datatype {
+        "boolean" => value == "true" || value == "false",
+        "unsignedLong" => value.parse::<u64>().is_ok(),
+        "long" => value.parse::<i64>().is_ok(),
+        "double" => value.parse::<f64>().is_ok(),
+        "dateTime" | "dateTime:RFC3339" | "dateTime:RFC3339Nano" => {
+            DateTime::parse_from_rfc3339(value).is_ok()
+        }
+        _ => true,
+    }
+}
+
+/// Decodes an InfluxDB annotated CSV response into tables.
+///
+/// Annotations are optional, but are validated when present. Tables are split
+/// on blank lines, on new annotations and on `result` or `table` column value
+/// changes. An error table embedded in the stream is turned into
+/// [`Error::Query`].
+pub(crate) async fn decode<R, T>(reader: R) -> Result<Vec<Table<T>>, Error>
+where
+    R: AsyncRead + Unpin + Send,
+    T: DeserializeOwned,
+{
+    let mut reader = AsyncReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .create_reader(reader);
+    let mut record = StringRecord::new();
+    let mut annotations = Annotations::default();
+    let mut block: Option<Block> = None;
+    let mut new_table = true;
+    let mut tables: Vec<Table<T>> = Vec::new();
+
+    while reader.read_record(&mut record).await? {
+        // Blank lines are skipped by the CSV reader, but they are accounted in
+        // the span of the following record.
+        let start_line = record.position().map_or(1, |p| p.line());
+        let end_line = reader.position().line();
+        let embedded_newlines = record
+            .iter()
+            .map(|field| field.matches('\n').count() as u64)
+            .sum::<u64>();
+        let blank_lines = (end_line - start_line).saturating_sub(embedded_newlines + 1);
+        let line = start_line + blank_lines;
+        let after_blank_line = blank_lines > 0;
+
+        if record.get(0).is_some_and(|first| first.starts_with('#')) {
+            block = None;
+            annotations.push(&record, line)?;
+            continue;
+        }
+        if after_blank_line && annotations.is_empty() {
+            block = None;
+        }
+
+        let Some(current) = &block else {
+            let header = record.clone();
+            let new_block = Block::new(header, std::mem::take(&mut annotations), line)?;
+            if new_block.is_error() {
+                let has_row = reader.read_record(&mut record).await?;
+                let column = |name: &str| {
+                    new_block
+                        .header
+                        .iter()
+                        .position(|header_name| header_name == name)
+                        .and_then(|i| record.get(i))
+                        .filter(|_| has_row)
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                return Err(Error::Query {
+                    message: column("error"),
+                    reference: column("reference"),
+                });
+            }
+            block = Some(new_block);
+            new_table = true;
+            continue;
+        };
+
+        let row = current.prepare_row(&record, line)?;
+        let (result, id) = current.table_key(&row);
+        let value = row
+            .deserialize::<T>(Some(&current.header))
+            .map_err(|err| invalid(line, err.to_string()))?;
+        match tables.last_mut() {
+            Some(table) if !new_table && table.result == result && table.id == id => {
+                table.rows.push(value);
+            }
+            _ => {
+                tables.push(Table {
+                    result: result.to_string(),
+                    id: id.to_string(),
+                    rows: vec![value],
+                });
+            }
+        }
+        new_table = false;
+    }
+
+    if !annotations.is_empty() {
+        return Err(invalid(
+            reader.position().line(),
+            "annotations without header",
+        ));
+    }
+
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Row {
+        #[serde(rename = "_value")]
+        value: Option<i64>,
+        tag: String,
+    }
+
+    async fn decode_str<T: DeserializeOwned>(data: &str) -> Result<Vec<Table<T>>, Error> {
+        decode(data.as_bytes()).await
+    }
+
+    fn row(value: Option<i64>, tag: &str) -> Row {
+        Row {
+            value,
+            tag: tag.to_string(),
+        }
+    }
+
+    mod decode {
+        use super::*;
+
+        #[tokio::test]
+        async fn empty() {
+            let tables = decode_str::<Row>("").await.unwrap();
+            assert!(tables.is_empty());
+        }
+
+        #[tokio::test]
+        async fn unknown_annotation() {
+            let data = indoc! {"
+                #datatype,string,long,long,string
+                #unknown,false,false,false,true
+                ,result,table,_value,tag
+                ,_result,0,1,a
+            "};
+            let result = decode_str::<Row>(data).await;
+            assert!(matches!(result, Err(Error::Invalid { line: 2, .. })));
+        }
+
+        #[tokio::test]
+        async fn duplicate_annotation() {
+            let data = indoc! {"
+                #group,false,false,false,true
+                #group,false,false,false,true
+                ,result,table,_value,tag
+                ,_result,0,1,a
+            "};
+            let result = decode_str::<Row>(data).await;
+            assert!(matches!(result, Err(Error::Invalid { line: 2, .. })));
+        }
+
+        #[tokio::test]
+        async fn annotation_length_mismatch() {
+            let data = indoc! {"
+                #datatype,string,long,long
+                ,result,table,_value,tag
+                ,_result,0,1,a
+            "};
+            let result = decode_str::<Row>(data).await;
+            assert!(matches!(result, Err(Error::Invalid { line: 2, .. })));
+        }
+
+        #[tokio::test]
+        async fn unknown_datatype() {
+            let data = indoc! {"
+                #datatype,string,long,integer,string
+                ,result,table,_value,tag
+                ,_result,0,1,a
+            "};
+            let result = decode_str::<Row>(data).await;
+            assert!(matches!(result, Err(Error::Invalid { line: 2, .. })));
+        }
+
+        #[tokio::test]
+        async fn invalid_group() {
+            let data = indoc! {"
+                #group,false,false,no,true
+                ,result,table,_value,tag
+                ,_result,0,1,a
+            "};
+            let result = decode_str::<Row>(data).await;
+            assert!(matches!(result, Err(Error::Invalid { line: 2, .. })));
+        }
+
+        #[tokio::test]
+        async fn annotations_without_header() {
+            let data = "#group,false,false,false,true\n";
+            let result = decode_str::<Row>(data).await;
+            assert!(matches!(result, Err(Error::Invalid { .. })));
+        }
+
+        #[tokio::test]
+        async fn value_not_matching_datatype() {
+            let data = indoc! {"
+                #datatype,string,long,long,string
+                ,result,table,_value,tag
+                ,_result,0,1,a
+                ,_result,0,1.5,a
+            "};
+            let result = decode_str::<Row>(data).await;
+            assert!(matches!(result, Err(Error::Invalid { line: 4, .. })));
+        }
+
+        #[tokio::test]
+        async fn row_length_mismatch() {
+            let data = indoc! {"
+                ,result,table,_value,tag
+                ,_result,0,1,a
+                ,_result,0,1
+            "};
+            let result = decode_str::<Row>(data).await;
+            assert!(matches!(result, Err(Error::Invalid { line: 3, .. })));
+        }
+
+        #[tokio::test]
+        async fn deserialization_error() {
+            let data = indoc! {"
+                ,result,table,_value,tag
+                ,_result,0,one,a
+            "};
+            let result = decode_str::<Row>(data).await;
+            assert!(matches!(result, Err(Error::Invalid { line: 2, .. })));
+        }
+
+        #[tokio::test]
+        async fn error_row() {
+            let data = indoc! {r#"
+                #datatype,string,string
+                #group,true,true
+                #default,,
+                ,error,reference
+                ,"failed to execute query, some reason",897
+            "#};
+            let result = decode_str::<Row>(data).await;
+            let Err(Error::Query { message, reference }) = result else {
+                panic!("unexpected result: {result:?}");
+            };
+            assert_eq!(message, "failed to execute query, some reason");
+            assert_eq!(reference, "897");
+        }
+
+        #[tokio::test]
+        async fn error_row_mid_stream() {
+            let data = indoc! {"
+                #datatype,string,long,long,string
+                #group,false,false,false,true
+                #default,_result,,,
+                ,result,table,_value,tag
+                ,,0,1,a
+
+                #datatype,string,string
+                #group,true,true
+                #default,,
+                ,error,reference
+                ,panic: runtime error,
+            "};
+            let result = decode_str::<Row>(data).await;
+            let Err(Error::Query { message, reference }) = result else {
+                panic!("unexpected result: {result:?}");
+            };
+            assert_eq!(message, "panic: runtime error");
+            assert_eq!(reference, "");
+        }
+
+        #[tokio::test]
+        async fn success_not_annotated() {
+            let data = indoc! {"
+                _value,tag
+                1,a
+                ,b
+            "};
+            let tables = decode_str::<Row>(data).await.unwrap();
+            assert_eq!(
+                tables,
+                [Table {
+                    result: String::new(),
+                    id: String::new(),
+                    rows: vec![row(Some(1), "a"), row(None, "b")],
+                }]
+            );
+        }
+
+        #[tokio::test]
+        async fn success_annotated_multiple_tables() {
+            let data = indoc! {"
+                #datatype,string,long,long,string
+                #group,false,false,false,true
+                #default,_result,,7,
+                ,result,table,_value,tag
+                ,,0,1,a
+                ,,0,,a
+                ,,1,3,b
+
+                #datatype,string,long,dateTime:RFC3339,long,string
+                #group,false,false,false,false,true
+                #default,_result,,,,
+                ,result,table,_time,_value,tag
+                ,,2,2023-09-19T14:00:00Z,4,c
+            "};
+            let tables = decode_str::<Row>(data).await.unwrap();
+            assert_eq!(
+                tables,
+                [
+                    Table {
+                        result: "_result".to_string(),
+                        id: "0".to_string(),
+                        rows: vec![row(Some(1), "a"), row(Some(7), "a")],
+                    },
+                    Table {
+                        result: "_result".to_string(),
+                        id: "1".to_string(),
+                        rows: vec![row(Some(3), "b")],
+                    },
+                    Table {
+                        result: "_result".to_string(),
+                        id: "2".to_string(),
+                        rows: vec![row(Some(4), "c")],
+                    },
+                ]
+            );
+        }
+
+        #[tokio::test]
+        async fn success_not_annotated_blank_line_separated() {
+            let data = indoc! {"
+                ,result,table,_value,tag
+                ,_result,0,1,a
+
+                ,result,table,tag,_value
+                ,_result,0,b,2
+            "};
+            let tables = decode_str::<Row>(data).await.unwrap();
+            assert_eq!(
+                tables,
+                [
+                    Table {
+                        result: "_result".to_string(),
+                        id: "0".to_string(),
+                        rows: vec![row(Some(1), "a")],
+                    },
+                    Table {
+                        result: "_result".to_string(),
+                        id: "0".to_string(),
+                        rows: vec![row(Some(2), "b")],
+                    },
+                ]
+            );
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use std::borrow::Cow;
+
+use axum::Json;
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use tracing::{debug, error};
+use utoipa::{ToResponse, ToSchema};
+
+use crate::channel::RoundtripError;
+use crate::negotiation::Format;
+use crate::upstream::Failure;
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Delay after which overloaded clients are told to retry, in seconds.
+const RETRY_AFTER: HeaderValue = HeaderValue::from_static("1");
+
+/// Authentication scheme expected from unauthorized clients.
+const BEARER_CHALLENGE: HeaderValue = HeaderValue::from_static("Bearer");
+
+/// Machine-readable error code, telling clients what went wrong.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ErrorCode {
+    /// No timezone is configured and none was given by the client.
+    MissingTimezone,
+    /// The partner is unknown to the configuration API.
+    UnknownPartner,
+    /// A configuration does not pass validation.
+    InvalidConfiguration,
+    /// A dependency failed to serve the request.
+    DependencyFailure,
+    /// A dependency could not be reached.
+    DependencyUnreachable,
+    /// A dependency did not answer in time.
+    DependencyTimeout,
+    /// None of the formats accepted by the client is offered.
+    NotAcceptable,
+    /// The request does not carry the expected credentials.
+    Unauthorized,
+    /// The request could not be handed to its handler.
+    Overloaded,
+    /// Unexpected internal failure.
+    Internal,
+}
+
+/// Dependency a failure comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) enum Dependency {
+    #[serde(rename = "config-api")]
+    ConfigApi,
+    #[serde(rename = "influxdb")]
+    InfluxDb,
+    /// Channel between the HTTP API and the request handlers.
+    #[serde(rename = "channel")]
+    Channel,
+}
+
+/// Error responded by the HTTP API, as [RFC 9457] problem details.
+///
+/// [RFC 9457]: https://www.rfc-editor.org/rfc/rfc9457
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    status: StatusCode,
+    code: ErrorCode,
+    dependency: Option<Dependency>,
+    detail: Cow<'static, str>,
+    correlation_id: String,
+}
+
+/// Problem details, along with the error code, failing dependency and
+/// correlation ID.
+#[derive(Serialize, ToSchema, ToResponse)]
+#[response(content_type = "application/problem+json")]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ProblemDetails<'a> {
+    title: &'a str,
+    status: u16,
+    detail: &'a str,
+    code: ErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dependency: Option<Dependency>,
+    correlation_id: &'a str,
+}
+
+impl ApiError {
+    /// Builds an error, logging it along with its correlation ID.
+    fn new(
+        status: StatusCode,
+        code: ErrorCode,
+        dependency: Option<Dependency>,
+        detail: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        let detail = detail.into();
+        let correlation_id = format!("{:032x}", rand::random::<u128>());
+        if status.is_server_error() {
+            error!(kind = "request failure", ?code, ?dependency, %detail, %correlation_id);
+        } else {
+            debug!(msg = "request failure", ?code, %detail, %correlation_id);
+        }
+        Self {
+            status,
+            code,
+            dependency,
+            detail,
+            correlation_id,
+        }
+    }
+
+    pub(crate) fn missing_timezone() -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::MissingTimezone,
+            None,
+            "no timezone configured, `Client-Timezone` header required",
+        )
+    }
+
+    pub(crate) fn unknown_partner(id: &str) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::UnknownPartner,
+            Some(Dependency::ConfigApi),
+            format!("unknown partner `{id}`"),
+        )
+    }
+
+    /// Lists the formats the client could have asked for.
+    pub(crate) fn not_acceptable(offered: &[Format]) -> Self {
+        let offered = offered
+            .iter()
+            .map(|format| format.media_type())
+            .collect::<Vec<_>>()
+            .join(", ");
+        Self::new(
+            StatusCode::NOT_ACCEPTABLE,
+            ErrorCode::NotAcceptable,
+            None,
+            format!("no acceptable format, offered: {offered}"),
+        )
+    }
+
+    pub(crate) fn unauthorized() -> Self {
+        Self::new(
+            StatusCode::UNAUTHORIZED,
+            ErrorCode::Unauthorized,
+            None,
+            "missing or invalid bearer token",
+        )
+    }
+
+    /// Tells which rule a configuration failed, and for which configuration.
+    pub(crate) fn invalid_config(subject: &str, reason: &'static str) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InvalidConfiguration,
+            Some(Dependency::ConfigApi),
+            format!("invalid {subject}: {reason}"),
+        )
+    }
+
+    /// Classifies a failed roundtrip to the handler of a dependency: the
+    /// channel itself is to blame if the request could not be sent.
+    pub(crate) fn roundtrip(dependency: Dependency, kind: &str, err: RoundtripError) -> Self {
+        let (status, code, dependency) = match err {
+            RoundtripError::Full => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorCode::Overloaded,
+                Dependency::Channel,
+            ),
+            RoundtripError::Closed => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::Internal,
+                Dependency::Channel,
+            ),
+            RoundtripError::Timeout => (
+                StatusCode::GATEWAY_TIMEOUT,
+                ErrorCode::DependencyTimeout,
+                dependency,
+            ),
+            RoundtripError::NoReply => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::Internal,
+                dependency,
+            ),
+        };
+        Self::new(status, code, Some(dependency), format!("{kind}: {err}"))
+    }
+
+    /// Classifies a failure reported by the handler of a dependency.
+    pub(crate) fn upstream(dependency: Dependency, kind: &str, failure: Failure) -> Self {
+        let (status, code) = match failure {
+            Failure::Timeout => (StatusCode::GATEWAY_TIMEOUT, ErrorCode::DependencyTimeout),
+            Failure::Unreachable | Failure::CircuitOpen => {
+                (StatusCode::BAD_GATEWAY, ErrorCode::DependencyUnreachable)
+            }
+            Failure::Transient | Failure::Permanent | Failure::NotFound => {
+                (StatusCode::BAD_GATEWAY, ErrorCode::DependencyFailure)
+            }
+            Failure::Invalid(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InvalidConfiguration,
+            ),
+        };
+        Self::new(status, code, Some(dependency), format!("{kind}: {failure}"))
+    }
+
+    /// Unwraps the reply of a dependency handler.
+    pub(crate) fn reply<T>(
+        dependency: Dependency,
+        kind: &str,
+        reply: Result<Result<T, Failure>, RoundtripError>,
+    ) -> Result<T, Self> {
+        reply
+            .map_err(|err| Self::roundtrip(dependency, kind, err))?
+            .map_err(|failure| Self::upstream(dependency, kind, failure))
+    }
+
+    /// Returns the explanation of the error, as responded.
+    pub(crate) fn detail(&self) -> &str {
+        &self.detail
+    }
+
+    pub(crate) fn internal(detail: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::Internal,
+            None,
+            detail,
+        )
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let problem = ProblemDetails {
+            title: self.status.canonical_reason().unwrap_or_default(),
+            status: self.status.as_u16(),
+            detail: &self.detail,
+            code: self.code,
+            dependency: self.dependency,
+            correlation_id: &self.correlation_id,
+        };
+        let mut response = (self.status, Json(problem)).into_response();
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+        match self.code {
+            ErrorCode::Overloaded => {
+                headers.insert(header::RETRY_AFTER, RETRY_AFTER);
+            }
+            ErrorCode::Unauthorized => {
+                headers.insert(header::WWW_AUTHENTICATE, BEARER_CHALLENGE);
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn problem_details() {
+        let error = ApiError::reply::<()>(
+            Dependency::InfluxDb,
+            "timeline channel roundtrip",
+            Ok(Err(Failure::Transient)),
+        )
+        .unwrap_err();
+        let correlation_id = error.correlation_id.clone();
+        let res = error.into_response();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(res.headers()["Content-Type"], PROBLEM_JSON);
+        assert!(!res.headers().contains_key("Retry-After"));
+        let body = to_bytes(res.into_body(), 1024).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            problem,
+            serde_json::json!({
+                "title": "Bad Gateway",
+                "status": 502,
+                "detail": "timeline channel roundtrip: transient failure",
+                "code": "dependency-failure",
+                "dependency": "influxdb",
+                "correlationId": correlation_id,
+            })
+        );
+    }
+
+    #[test]
+    fn roundtrip_classification() {
+        let classify = |err| {
+            let error = ApiError::roundtrip(Dependency::ConfigApi, "test", err);
+            (error.status, error.code, error.dependency)
+        };
+        assert_eq!(
+            classify(RoundtripError::Full),
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorCode::Overloaded,
+                Some(Dependency::Channel)
+            )
+        );
+        assert_eq!(
+            classify(RoundtripError::Closed),
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::Internal,
+                Some(Dependency::Channel)
+            )
+        );
+        assert_eq!(
+            classify(RoundtripError::Timeout),
+            (
+                StatusCode::GATEWAY_TIMEOUT,
+                ErrorCode::DependencyTimeout,
+                Some(Dependency::ConfigApi)
+            )
+        );
+        assert_eq!(
+            classify(RoundtripError::NoReply),
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::Internal,
+                Some(Dependency::ConfigApi)
+            )
+        );
+    }
+
+    #[test]
+    fn upstream_classification() {
+        let classify = |failure| {
+            let error = ApiError::upstream(Dependency::InfluxDb, "test", failure);
+            (error.status, error.code)
+        };
+        assert_eq!(
+            classify(Failure::Timeout),
+            (StatusCode::GATEWAY_TIMEOUT, ErrorCode::DependencyTimeout)
+        );
+        assert_eq!(
+            classify(Failure::Unreachable),
+            (StatusCode::BAD_GATEWAY, ErrorCode::DependencyUnreachable)
+        );
+        assert_eq!(
+            classify(Failure::CircuitOpen),
+            (StatusCode::BAD_GATEWAY, ErrorCode::DependencyUnreachable)
+        );
+        assert_eq!(
+            classify(Failure::Permanent),
+            (StatusCode::BAD_GATEWAY, ErrorCode::DependencyFailure)
+        );
+        assert_eq!(
+            classify(Failure::Invalid("test")),
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InvalidConfiguration
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_after() {
+        let error = ApiError::roundtrip(Dependency::InfluxDb, "test", RoundtripError::Full);
+        let res = error.into_response();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()["Retry-After"], "1");
+    }
+
+    #[test]
+    fn unique_correlation_ids() {
+        let first = ApiError::internal("test");
+        let second = ApiError::internal("test");
+        assert_eq!(first.correlation_id.len(), 32);
+        assert_ne!(first.correlation_id, second.correlation_id);
+    }
+}
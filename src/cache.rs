@@ -0,0 +1,188 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Keyed cache with time-based expiration.
+///
+/// A zero expiration disables the cache. When bounded, the oldest entry is
+/// evicted to make room for a new one. Expired entries are kept during the
+/// grace period, for use as stale values.
+#[derive(Clone)]
+pub(crate) struct TtlCache<K, V> {
+    inner: Arc<Mutex<HashMap<K, (Instant, V)>>>,
+    expiration: Duration,
+    max_entries: Option<NonZeroUsize>,
+    grace_period: Duration,
+}
+
+impl<K, V> TtlCache<K, V>
+where
+    K: Clone + Eq + Hash,
+    V: Clone,
+{
+    pub(crate) fn new(expiration: Duration) -> Self {
+        Self {
+            inner: Default::default(),
+            expiration,
+            max_entries: None,
+            grace_period: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn bounded(expiration: Duration, max_entries: NonZeroUsize) -> Self {
+        Self {
+            max_entries: Some(max_entries),
+            ..Self::new(expiration)
+        }
+    }
+
+    pub(crate) fn with_grace_period(self, grace_period: Duration) -> Self {
+        Self {
+            grace_period,
+            ..self
+        }
+    }
+
+    /// Returns a clone of the cached value, if present and not expired.
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .get(key)
+            .filter(|(cached_at, _)| cached_at.elapsed() < self.expiration)
+            .map(|(_, value)| value.clone())
+    }
+
+    /// Returns a clone of the cached value, if present and not past the grace
+    /// period.
+    pub(crate) fn get_stale<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .get(key)
+            .filter(|(cached_at, _)| cached_at.elapsed() < self.expiration + self.grace_period)
+            .map(|(_, value)| value.clone())
+    }
+
+    /// Inserts a value, purging entries past the grace period.
+    pub(crate) fn insert(&self, key: K, value: V) {
+        if self.expiration.is_zero() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let retention = self.expiration + self.grace_period;
+        inner.retain(|_, (cached_at, _)| cached_at.elapsed() < retention);
+        if let Some(max_entries) = self.max_entries
+            && inner.len() >= max_entries.get()
+            && !inner.contains_key(&key)
+        {
+            let oldest = inner
+                .iter()
+                .min_by_key(|(_, (cached_at, _))| *cached_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                inner.remove(&oldest);
+            }
+        }
+        inner.insert(key, (Instant::now(), value));
+    }
+
+    /// Removes an entry, if present.
+    pub(crate) fn invalidate<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.inner.lock().unwrap().remove(key);
+    }
+
+    /// Removes all entries.
+    pub(crate) fn clear(&self) {
+        self.inner.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled() {
+        let cache = TtlCache::new(Duration::ZERO);
+        cache.insert("key", 1);
+        assert_eq!(cache.get(&"key"), None);
+        assert!(cache.inner.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn missing() {
+        let cache = TtlCache::<&str, u8>::new(Duration::from_secs(60));
+        assert_eq!(cache.get(&"key"), None);
+    }
+
+    #[test]
+    fn hit() {
+        let cache = TtlCache::new(Duration::from_secs(60));
+        cache.insert("key", 1);
+        cache.insert("other", 2);
+        assert_eq!(cache.get(&"key"), Some(1));
+        assert_eq!(cache.get(&"other"), Some(2));
+    }
+
+    #[test]
+    fn expired() {
+        let cache = TtlCache::new(Duration::from_millis(10));
+        cache.insert("key", 1);
+        std::thread::sleep(Duration::from_millis(15));
+        assert_eq!(cache.get(&"key"), None);
+        cache.insert("other", 2);
+        assert_eq!(cache.inner.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn stale() {
+        let cache =
+            TtlCache::new(Duration::from_millis(10)).with_grace_period(Duration::from_secs(60));
+        assert_eq!(cache.get_stale(&"key"), None);
+        cache.insert("key", 1);
+        std::thread::sleep(Duration::from_millis(15));
+        assert_eq!(cache.get(&"key"), None);
+        assert_eq!(cache.get_stale(&"key"), Some(1));
+        cache.insert("other", 2);
+        assert_eq!(cache.inner.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn bounded() {
+        let cache = TtlCache::bounded(Duration::from_secs(60), NonZeroUsize::new(2).unwrap());
+        cache.insert("first", 1);
+        cache.insert("second", 2);
+        cache.insert("first", 3);
+        assert_eq!(cache.inner.lock().unwrap().len(), 2);
+        cache.insert("third", 4);
+        assert_eq!(cache.get(&"first"), Some(3));
+        assert_eq!(cache.get(&"second"), None);
+        assert_eq!(cache.get(&"third"), Some(4));
+    }
+
+    #[test]
+    fn invalidate() {
+        let cache = TtlCache::new(Duration::from_secs(60));
+        cache.insert("key".to_string(), 1);
+        cache.insert("other".to_string(), 2);
+        cache.invalidate("key");
+        assert_eq!(cache.get("key"), None);
+        assert_eq!(cache.get("other"), Some(2));
+        cache.clear();
+        assert!(cache.inner.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,178 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum_extra::headers::authorization::Bearer;
+use axum_extra::headers::{Authorization, HeaderMapExt};
+use clap::Args;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, info, info_span};
+
+use crate::api_error::ApiError;
+use crate::reload::{poll_changes, read_secret};
+
+#[derive(Args)]
+#[group(skip)]
+pub(crate) struct Config {
+    /// Bearer token required by the configuration cache and webhook routes, not served
+    /// without one
+    #[arg(env, long, conflicts_with = "config_cache_token_file")]
+    config_cache_token: Option<String>,
+
+    /// File containing the bearer token required by the configuration cache and webhook
+    /// routes, reloaded on change
+    #[arg(env, long)]
+    config_cache_token_file: Option<PathBuf>,
+
+    /// Interval between configuration cache token file checks
+    #[arg(env, long, default_value = "10s")]
+    config_cache_token_reload_interval: humantime::Duration,
+}
+
+struct TokenFile {
+    path: PathBuf,
+    reload_interval: Duration,
+    token_tx: watch::Sender<Arc<str>>,
+}
+
+/// Bearer token required by the configuration cache and webhook routes.
+#[derive(Clone)]
+pub(crate) struct CacheToken {
+    token: watch::Receiver<Arc<str>>,
+    token_file: Option<Arc<TokenFile>>,
+}
+
+impl CacheToken {
+    /// Returns `None` if no token is configured.
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        let token = config.config_cache_token.as_deref().unwrap_or_default();
+        let (token_tx, token) = watch::channel(Arc::from(token));
+        let token_file = config.config_cache_token_file.as_ref().map(|path| {
+            Arc::new(TokenFile {
+                path: path.clone(),
+                reload_interval: config.config_cache_token_reload_interval.into(),
+                token_tx,
+            })
+        });
+        if config.config_cache_token.is_none() && token_file.is_none() {
+            return None;
+        }
+        Some(Self { token, token_file })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn fixed(token: &str) -> Self {
+        Self {
+            token: watch::channel(Arc::from(token)).1,
+            token_file: None,
+        }
+    }
+
+    /// Loads the token file, if configured, and spawns a task reloading it on
+    /// change, which terminates once all tokens have been dropped.
+    pub(crate) fn handle_token_file(&self) -> anyhow::Result<Option<JoinHandle<()>>> {
+        let Some(token_file) = self.token_file.clone() else {
+            return Ok(None);
+        };
+        let token = read_secret(&token_file.path)?;
+        token_file.token_tx.send_replace(Arc::from(token));
+
+        let task = tokio::spawn(
+            async move {
+                info!(status = "started");
+
+                poll_changes(
+                    &token_file.token_tx,
+                    token_file.reload_interval,
+                    "configuration cache token",
+                    || read_secret(&token_file.path).map(Arc::from),
+                )
+                .await;
+
+                info!(status = "terminating");
+            }
+            .instrument(info_span!("configuration_cache_token_file_handler")),
+        );
+
+        Ok(Some(task))
+    }
+
+    /// Returns whether the given token is the expected one, in constant time
+    /// for tokens of the expected length.
+    fn matches(&self, token: &str) -> bool {
+        let expected = self.token.borrow();
+        !expected.is_empty()
+            && expected.len() == token.len()
+            && expected
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// Rejects requests not carrying the configuration cache token.
+pub(crate) async fn require(State(token): State<CacheToken>, req: Request, next: Next) -> Response {
+    let authorized = req
+        .headers()
+        .typed_get::<Authorization<Bearer>>()
+        .is_some_and(|Authorization(bearer)| token.matches(bearer.token()));
+    if !authorized {
+        return ApiError::unauthorized().into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testing::temp_path;
+
+    fn config(token_file: Option<PathBuf>) -> Config {
+        Config {
+            config_cache_token: None,
+            config_cache_token_file: token_file,
+            config_cache_token_reload_interval: Duration::from_millis(10).into(),
+        }
+    }
+
+    #[test]
+    fn not_configured() {
+        assert!(CacheToken::new(&config(None)).is_none());
+    }
+
+    #[test]
+    fn matches() {
+        let token = CacheToken::fixed("sometoken");
+        assert!(token.matches("sometoken"));
+        assert!(!token.matches("othertoken"));
+        assert!(!token.matches("sometoke"));
+        assert!(!token.matches(""));
+    }
+
+    #[tokio::test]
+    async fn reload() {
+        let path = temp_path("cache-token");
+        fs::write(&path, "firsttoken\n").unwrap();
+        let token = CacheToken::new(&config(Some(path.clone()))).unwrap();
+        let task = token.handle_token_file().unwrap().unwrap();
+        assert!(token.matches("firsttoken"));
+
+        fs::write(&path, "secondtoken\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(token.matches("secondtoken"));
+
+        drop(token);
+        tokio::time::timeout(Duration::from_millis(100), task)
+            .await
+            .expect("task should terminate")
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
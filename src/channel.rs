@@ -1,50 +1,239 @@
-use std::time::Duration;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::mpsc::error::SendTimeoutError;
+use tokio::sync::{Semaphore, mpsc, oneshot};
 use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{Instrument, error, info};
 
 type RequestPayload<S, R> = (S, CancellationToken, oneshot::Sender<R>);
 
+pub(crate) type RequestReceiver<S, R> = mpsc::Receiver<RequestPayload<S, R>>;
+
+type Followers<R> = Vec<oneshot::Sender<Result<R, RoundtripError>>>;
+
+type InFlight<S, R> = Arc<Mutex<HashMap<S, Followers<R>>>>;
+
 const SEND_TIMEOUT: Duration = Duration::from_millis(100);
-const RECEIVE_TIMEOUT: Duration = Duration::from_millis(500);
+pub(crate) const RECEIVE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Returns the instant after which the reply to a request being handled now
+/// would come too late for the sender.
+pub(crate) fn reply_deadline() -> Instant {
+    Instant::now() + RECEIVE_TIMEOUT
+}
+
+/// Reason a roundtrip failed.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub(crate) enum RoundtripError {
+    /// The handler is not keeping up with requests.
+    Full,
+    /// The handler is gone.
+    Closed,
+    /// The handler did not reply in time.
+    Timeout,
+    /// The handler dropped the request, having failed to process it.
+    NoReply,
+}
+
+impl fmt::Display for RoundtripError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::Full => "request sending: channel full",
+            Self::Closed => "request sending: channel closed",
+            Self::Timeout => "reply receiving: timed out",
+            Self::NoReply => "reply receiving: no reply",
+        };
+        f.write_str(message)
+    }
+}
 
 pub(crate) struct RoundtripSender<S, R> {
     inner: mpsc::Sender<RequestPayload<S, R>>,
+    in_flight: InFlight<S, R>,
 }
 
 impl<S, R> Clone for RoundtripSender<S, R> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
+            in_flight: self.in_flight.clone(),
         }
     }
 }
 
 impl<S, R> RoundtripSender<S, R> {
-    pub(crate) async fn roundtrip(&self, request: S) -> Result<R, String> {
+    pub(crate) async fn roundtrip(&self, request: S) -> Result<R, RoundtripError> {
         let (reply_tx, reply_rx) = oneshot::channel();
         let cancellation_token = CancellationToken::new();
         let _drop_guard = cancellation_token.clone().drop_guard();
         self.inner
             .send_timeout((request, cancellation_token, reply_tx), SEND_TIMEOUT)
             .await
-            .map_err(|err| format!("request sending: {err}"))?;
+            .map_err(|err| match err {
+                SendTimeoutError::Timeout(_) => RoundtripError::Full,
+                SendTimeoutError::Closed(_) => RoundtripError::Closed,
+            })?;
         let reply = tokio::time::timeout(RECEIVE_TIMEOUT, reply_rx)
             .await
-            .map_err(|err| format!("reply receiving: {err}"))?
-            .map_err(|err| format!("reply receiving: {err}"))?;
+            .map_err(|_| RoundtripError::Timeout)?
+            .map_err(|_| RoundtripError::NoReply)?;
         Ok(reply)
     }
 }
 
+/// Removes the in-flight entry of a leading request if it is dropped before
+/// completion, letting followers know they must proceed on their own.
+struct LeaderGuard<'a, S, R>
+where
+    S: Eq + Hash,
+{
+    in_flight: &'a Mutex<HashMap<S, Followers<R>>>,
+    request: &'a S,
+    completed: bool,
+}
+
+impl<S, R> LeaderGuard<'_, S, R>
+where
+    S: Eq + Hash,
+    R: Clone,
+{
+    fn complete(mut self, result: &Result<R, RoundtripError>) {
+        self.completed = true;
+        let followers = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(self.request)
+            .unwrap_or_default();
+        for follower in followers {
+            // The follower may have gone away meanwhile.
+            let _ = follower.send(result.clone());
+        }
+    }
+}
+
+impl<S, R> Drop for LeaderGuard<'_, S, R>
+where
+    S: Eq + Hash,
+{
+    fn drop(&mut self) {
+        if !self.completed {
+            self.in_flight.lock().unwrap().remove(self.request);
+        }
+    }
+}
+
+impl<S, R> RoundtripSender<S, R>
+where
+    S: Clone + Eq + Hash,
+    R: Clone,
+{
+    /// Like [`RoundtripSender::roundtrip`], but identical requests made while
+    /// one is in flight wait for, and share, its reply. If the leading request
+    /// is cancelled, its followers elect a new leader among them.
+    pub(crate) async fn coalesced_roundtrip(&self, request: S) -> Result<R, RoundtripError> {
+        loop {
+            let follower_rx = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match in_flight.get_mut(&request) {
+                    Some(followers) => {
+                        let (tx, rx) = oneshot::channel();
+                        followers.push(tx);
+                        Some(rx)
+                    }
+                    None => {
+                        in_flight.insert(request.clone(), Vec::new());
+                        None
+                    }
+                }
+            };
+
+            match follower_rx {
+                Some(rx) => match rx.await {
+                    Ok(result) => return result,
+                    // The leading request has been cancelled.
+                    Err(_) => continue,
+                },
+                None => {
+                    let guard = LeaderGuard {
+                        in_flight: &self.in_flight,
+                        request: &request,
+                        completed: false,
+                    };
+                    let result = self.roundtrip(request.clone()).await;
+                    guard.complete(&result);
+                    return result;
+                }
+            }
+        }
+    }
+}
+
 pub(crate) fn roundtrip_channel<S, R>(
     buffer: usize,
-) -> (RoundtripSender<S, R>, mpsc::Receiver<RequestPayload<S, R>>) {
+) -> (RoundtripSender<S, R>, RequestReceiver<S, R>) {
     let (inner, rx) = mpsc::channel(buffer);
-    let sender = RoundtripSender { inner };
+    let sender = RoundtripSender {
+        inner,
+        in_flight: Default::default(),
+    };
     (sender, rx)
 }
 
+/// Processes received requests concurrently, with at most `limit` of them at a time,
+/// until the channel is closed and all pending requests are done.
+///
+/// Processing is abandoned if the request is cancelled by the sender. Nothing is
+/// replied when `process` returns `None`.
+pub(crate) async fn process_concurrently<S, R, F, Fut>(
+    mut rx: RequestReceiver<S, R>,
+    limit: NonZeroUsize,
+    process: F,
+) where
+    F: Fn(S) -> Fut,
+    Fut: Future<Output = Option<R>> + Send + 'static,
+    R: Send + 'static,
+{
+    let semaphore = Arc::new(Semaphore::new(limit.get()));
+    let tracker = TaskTracker::new();
+
+    while let Some((request, cancellation_token, reply_tx)) = rx.recv().await {
+        let permit = semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        let processing = process(request);
+        tracker.spawn(
+            async move {
+                let _permit = permit;
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => {
+                        info!(msg = "request was cancelled");
+                    },
+                    reply = processing => {
+                        if let Some(reply) = reply
+                            && reply_tx.send(reply).is_err()
+                        {
+                            error!(kind = "response channel sending");
+                        }
+                    },
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    tracker.close();
+    tracker.wait().await;
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -56,7 +245,7 @@ mod tests {
         async fn request_receiver_dropped() {
             let (tx, _) = roundtrip_channel::<(), ()>(1);
             let result = tx.roundtrip(()).await;
-            assert!(result.is_err());
+            assert_eq!(result, Err(RoundtripError::Closed));
         }
 
         #[tokio::test]
@@ -68,7 +257,7 @@ mod tests {
                 .await
                 .unwrap();
             let result = tx.roundtrip(()).await;
-            assert!(result.is_err());
+            assert_eq!(result, Err(RoundtripError::Full));
         }
 
         #[tokio::test]
@@ -78,7 +267,7 @@ mod tests {
                 let (_, _, _) = rx.recv().await.unwrap();
             });
             let result = tx.roundtrip(()).await;
-            assert!(result.is_err());
+            assert_eq!(result, Err(RoundtripError::NoReply));
         }
 
         #[tokio::test]
@@ -89,7 +278,7 @@ mod tests {
                 tokio::time::sleep(RECEIVE_TIMEOUT * 2).await;
             });
             let result = tx.roundtrip(()).await;
-            assert!(result.is_err());
+            assert_eq!(result, Err(RoundtripError::Timeout));
         }
 
         #[tokio::test]
@@ -123,4 +312,178 @@ mod tests {
             assert!(cancelled);
         }
     }
+
+    mod coalesced_roundtrip {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use tokio::task::JoinSet;
+
+        use super::*;
+
+        fn counting_responder(
+            mut rx: mpsc::Receiver<RequestPayload<u8, u8>>,
+            delay: Duration,
+        ) -> Arc<AtomicUsize> {
+            let received = Arc::new(AtomicUsize::new(0));
+            let cloned_received = received.clone();
+            tokio::spawn(async move {
+                while let Some((request, _, reply_tx)) = rx.recv().await {
+                    cloned_received.fetch_add(1, Ordering::SeqCst);
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        let _ = reply_tx.send(request * 2);
+                    });
+                }
+            });
+            received
+        }
+
+        #[tokio::test]
+        async fn identical_requests() {
+            let (tx, rx) = roundtrip_channel::<u8, u8>(10);
+            let received = counting_responder(rx, Duration::from_millis(50));
+            let mut join_set = JoinSet::new();
+            for _ in 0..5 {
+                let tx = tx.clone();
+                join_set.spawn(async move { tx.coalesced_roundtrip(21).await });
+            }
+            while let Some(result) = join_set.join_next().await {
+                assert_eq!(result.unwrap().unwrap(), 42);
+            }
+            assert_eq!(received.load(Ordering::SeqCst), 1);
+            assert!(tx.in_flight.lock().unwrap().is_empty());
+        }
+
+        #[tokio::test]
+        async fn different_requests() {
+            let (tx, rx) = roundtrip_channel::<u8, u8>(10);
+            let received = counting_responder(rx, Duration::from_millis(50));
+            let (first, second) =
+                tokio::join!(tx.coalesced_roundtrip(1), tx.coalesced_roundtrip(2));
+            assert_eq!(first.unwrap(), 2);
+            assert_eq!(second.unwrap(), 4);
+            assert_eq!(received.load(Ordering::SeqCst), 2);
+        }
+
+        #[tokio::test]
+        async fn successive_requests() {
+            let (tx, rx) = roundtrip_channel::<u8, u8>(10);
+            let received = counting_responder(rx, Duration::ZERO);
+            for _ in 0..3 {
+                assert_eq!(tx.coalesced_roundtrip(5).await.unwrap(), 10);
+            }
+            assert_eq!(received.load(Ordering::SeqCst), 3);
+        }
+
+        #[tokio::test]
+        async fn leader_cancelled() {
+            let (tx, rx) = roundtrip_channel::<u8, u8>(10);
+            let received = counting_responder(rx, Duration::from_millis(50));
+            let leader_tx = tx.clone();
+            let leader = tokio::spawn(async move { leader_tx.coalesced_roundtrip(3).await });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let mut followers = JoinSet::new();
+            for _ in 0..5 {
+                let tx = tx.clone();
+                followers.spawn(async move { tx.coalesced_roundtrip(3).await });
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            leader.abort();
+            while let Some(result) = followers.join_next().await {
+                assert_eq!(result.unwrap().unwrap(), 6);
+            }
+            assert_eq!(received.load(Ordering::SeqCst), 2);
+            assert!(tx.in_flight.lock().unwrap().is_empty());
+        }
+    }
+
+    mod process_concurrently {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use tokio::task::JoinSet;
+
+        use super::*;
+
+        #[tokio::test]
+        async fn concurrency_limit() {
+            let (tx, rx) = roundtrip_channel::<u8, u8>(10);
+            let current = Arc::new(AtomicUsize::new(0));
+            let max = Arc::new(AtomicUsize::new(0));
+            let (cloned_current, cloned_max) = (current.clone(), max.clone());
+            let task = tokio::spawn(process_concurrently(
+                rx,
+                NonZeroUsize::new(2).unwrap(),
+                move |request| {
+                    let (current, max) = (cloned_current.clone(), cloned_max.clone());
+                    async move {
+                        let running = current.fetch_add(1, Ordering::SeqCst) + 1;
+                        max.fetch_max(running, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        current.fetch_sub(1, Ordering::SeqCst);
+                        Some(request + 1)
+                    }
+                },
+            ));
+            let mut join_set = JoinSet::new();
+            for request in 0..5 {
+                let tx = tx.clone();
+                join_set.spawn(async move { tx.roundtrip(request).await });
+            }
+            let mut replies = join_set.join_all().await;
+            replies.sort();
+            assert_eq!(replies, [Ok(1), Ok(2), Ok(3), Ok(4), Ok(5)]);
+            assert_eq!(max.load(Ordering::SeqCst), 2);
+            assert!(!task.is_finished());
+            drop(tx);
+            task.await.unwrap();
+        }
+
+        #[tokio::test]
+        async fn slow_request_does_not_block() {
+            let (tx, rx) = roundtrip_channel::<u64, u64>(10);
+            tokio::spawn(process_concurrently(
+                rx,
+                NonZeroUsize::new(2).unwrap(),
+                |delay| async move {
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    Some(delay)
+                },
+            ));
+            let slow_tx = tx.clone();
+            let slow = tokio::spawn(async move { slow_tx.roundtrip(400).await });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let started = std::time::Instant::now();
+            assert_eq!(tx.roundtrip(10).await, Ok(10));
+            assert!(started.elapsed() < Duration::from_millis(200));
+            assert_eq!(slow.await.unwrap(), Ok(400));
+        }
+
+        #[tokio::test]
+        async fn no_reply() {
+            let (tx, rx) = roundtrip_channel::<(), ()>(1);
+            tokio::spawn(process_concurrently(rx, NonZeroUsize::MIN, |_| async {
+                None
+            }));
+            assert!(tx.roundtrip(()).await.is_err());
+        }
+
+        #[tokio::test]
+        async fn cancelled_request() {
+            let (tx, rx) = roundtrip_channel::<(), ()>(1);
+            let finished = Arc::new(AtomicUsize::new(0));
+            let cloned_finished = finished.clone();
+            tokio::spawn(process_concurrently(rx, NonZeroUsize::MIN, move |_| {
+                let finished = cloned_finished.clone();
+                async move {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    finished.fetch_add(1, Ordering::SeqCst);
+                    Some(())
+                }
+            }));
+            let result = tokio::time::timeout(Duration::from_millis(50), tx.roundtrip(())).await;
+            assert!(result.is_err());
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            assert_eq!(finished.load(Ordering::SeqCst), 0);
+        }
+    }
 }
@@ -0,0 +1,611 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context as _, bail};
+use clap::Args;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::{Instrument, error, info, info_span};
+
+use crate::channel::{process_concurrently, roundtrip_channel};
+use crate::config_api::{
+    CommonConfig, CommonConfigChannel, CommonConfigRequest, ListedPartner, PartnerConfig,
+    PartnerConfigChannel, PartnerConfigRequest, PartnersChannel, StatusChannel,
+};
+use crate::upstream::Failure;
+
+#[derive(Args)]
+#[group(skip)]
+pub(crate) struct Config {
+    /// YAML, TOML or JSON file providing the configuration, instead of the
+    /// configuration API
+    #[arg(env, long, conflicts_with = "config_api_url")]
+    config_file: Option<PathBuf>,
+
+    /// Interval between checks for configuration file changes
+    #[arg(env, long, default_value = "10s")]
+    config_file_reload_interval: humantime::Duration,
+}
+
+/// Configuration file contents, using the configuration API schema.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct FileConfig {
+    common: CommonConfig,
+    #[serde(default)]
+    sites: HashMap<String, CommonConfig>,
+    #[serde(default)]
+    partners: HashMap<String, PartnerConfig>,
+}
+
+impl FileConfig {
+    /// Returns the common configuration of a site, `None` standing for the
+    /// default site.
+    fn common_config(&self, site: Option<&str>) -> Option<&CommonConfig> {
+        match site {
+            None => Some(&self.common),
+            Some(site) => self.sites.get(site),
+        }
+    }
+
+    /// Checks that partners only refer to declared named data sources, which
+    /// would otherwise fail every query.
+    fn validate_data_sources(&self, data_sources: &HashSet<String>) -> anyhow::Result<()> {
+        for (id, partner_config) in &self.partners {
+            if let Some(name) = &partner_config.data_source.name
+                && !data_sources.contains(name)
+            {
+                bail!("invalid configuration for partner `{id}`: unknown data source `{name}`");
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse(path: &Path, contents: &str) -> anyhow::Result<FileConfig> {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let file_config: FileConfig = match extension {
+        Some("yaml" | "yml") => serde_norway::from_str(contents)?,
+        Some("toml") => toml::from_str(contents)?,
+        Some("json") => serde_json::from_str(contents)?,
+        _ => bail!("unsupported file extension, expected yaml, yml, toml or json"),
+    };
+    if let Err(reason) = file_config.common.validate() {
+        bail!("invalid common configuration: {reason}");
+    }
+    for (site, common_config) in &file_config.sites {
+        if let Err(reason) = common_config.validate() {
+            bail!("invalid common configuration of site `{site}`: {reason}");
+        }
+    }
+    for (id, partner_config) in &file_config.partners {
+        let site = partner_config.site.as_deref();
+        let Some(common_config) = file_config.common_config(site) else {
+            bail!(
+                "invalid configuration for partner `{id}`: unknown site `{}`",
+                site.unwrap_or_default()
+            );
+        };
+        if let Err(reason) = partner_config
+            .validate()
+            .and_then(|()| partner_config.validate_with(common_config))
+        {
+            bail!("invalid configuration for partner `{id}`: {reason}");
+        }
+    }
+    Ok(file_config)
+}
+
+fn load_file(path: &Path, data_sources: &HashSet<String>) -> anyhow::Result<(String, FileConfig)> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("error reading {}", path.display()))?;
+    let file_config = parse(path, &contents)
+        .and_then(|file_config| {
+            file_config.validate_data_sources(data_sources)?;
+            Ok(file_config)
+        })
+        .with_context(|| format!("error loading {}", path.display()))?;
+    Ok((contents, file_config))
+}
+
+struct WatchedFile {
+    path: PathBuf,
+    /// Names of the declared data sources partners may refer to.
+    data_sources: HashSet<String>,
+    reload_interval: Duration,
+    config_tx: watch::Sender<Arc<FileConfig>>,
+}
+
+/// Configuration provider reading a local file, as an alternative to the
+/// configuration API.
+#[derive(Clone)]
+pub(crate) struct Provider {
+    config_rx: watch::Receiver<Arc<FileConfig>>,
+    file: Arc<WatchedFile>,
+    contents: String,
+}
+
+impl Provider {
+    /// Loads the configuration file, if configured, partners being only
+    /// allowed to refer to the given data sources.
+    pub(crate) fn load(
+        config: &Config,
+        data_sources: &HashSet<String>,
+    ) -> anyhow::Result<Option<Self>> {
+        let Some(path) = config.config_file.clone() else {
+            return Ok(None);
+        };
+        let (contents, file_config) = load_file(&path, data_sources)?;
+        let (config_tx, config_rx) = watch::channel(Arc::new(file_config));
+        let file = Arc::new(WatchedFile {
+            path,
+            data_sources: data_sources.clone(),
+            reload_interval: config.config_file_reload_interval.into(),
+            config_tx,
+        });
+        Ok(Some(Self {
+            config_rx,
+            file,
+            contents,
+        }))
+    }
+
+    /// Spawns a task reloading the configuration file when it changes, which
+    /// terminates once all handlers have terminated.
+    pub(crate) fn handle_reload(&self) -> JoinHandle<()> {
+        let file = Arc::clone(&self.file);
+        let mut contents = self.contents.clone();
+
+        tokio::spawn(
+            async move {
+                info!(status = "started");
+
+                let mut interval = tokio::time::interval(file.reload_interval);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                interval.tick().await;
+                loop {
+                    tokio::select! {
+                        _ = file.config_tx.closed() => break,
+                        _ = interval.tick() => {}
+                    }
+                    match load_file(&file.path, &file.data_sources) {
+                        Ok((new_contents, _)) if new_contents == contents => {}
+                        Ok((new_contents, file_config)) => {
+                            contents = new_contents;
+                            file.config_tx.send_replace(Arc::new(file_config));
+                            info!(msg = "configuration file reloaded");
+                        }
+                        Err(err) => {
+                            error!(
+                                kind = "reloading configuration file",
+                                err = format!("{err:#}")
+                            );
+                        }
+                    }
+                }
+
+                info!(status = "terminating");
+            }
+            .instrument(info_span!("configuration_file_handler")),
+        )
+    }
+
+    pub(crate) fn handle_common_config(
+        &self,
+        concurrency_limit: NonZeroUsize,
+    ) -> (CommonConfigChannel, JoinHandle<()>) {
+        let (tx, rx) = roundtrip_channel(10);
+        let config_rx = self.config_rx.clone();
+
+        let task = tokio::spawn(
+            async move {
+                info!(status = "started");
+
+                process_concurrently(
+                    rx,
+                    concurrency_limit,
+                    move |request: CommonConfigRequest| {
+                        let common_config = config_rx
+                            .borrow()
+                            .common_config(request.site.as_deref())
+                            .cloned()
+                            .ok_or(Failure::NotFound);
+                        if common_config.is_err() {
+                            error!(kind = "unknown site", site = request.site);
+                        }
+                        async move { Some(common_config) }
+                    },
+                )
+                .await;
+
+                info!(status = "terminating");
+            }
+            .instrument(info_span!("common_configuration_handler")),
+        );
+
+        (tx, task)
+    }
+
+    pub(crate) fn handle_partner_config(
+        &self,
+        concurrency_limit: NonZeroUsize,
+    ) -> (PartnerConfigChannel, JoinHandle<()>) {
+        let (tx, rx) = roundtrip_channel(10);
+        let config_rx = self.config_rx.clone();
+
+        let task = tokio::spawn(
+            async move {
+                info!(status = "started");
+
+                process_concurrently(
+                    rx,
+                    concurrency_limit,
+                    move |request: PartnerConfigRequest| {
+                        let partner_config = config_rx
+                            .borrow()
+                            .partners
+                            .get(&request.id)
+                            .cloned()
+                            .ok_or(Failure::NotFound);
+                        if partner_config.is_err() {
+                            error!(kind = "unknown partner", id = request.id);
+                        }
+                        async move { Some(partner_config) }
+                    },
+                )
+                .await;
+
+                info!(status = "terminating");
+            }
+            .instrument(info_span!("partner_configuration_handler")),
+        );
+
+        (tx, task)
+    }
+
+    pub(crate) fn handle_partners(
+        &self,
+        concurrency_limit: NonZeroUsize,
+    ) -> (PartnersChannel, JoinHandle<()>) {
+        let (tx, rx) = roundtrip_channel(10);
+        let config_rx = self.config_rx.clone();
+
+        let task = tokio::spawn(
+            async move {
+                info!(status = "started");
+
+                process_concurrently(rx, concurrency_limit, move |_| {
+                    let mut partners = config_rx
+                        .borrow()
+                        .partners
+                        .iter()
+                        .map(|(id, partner_config)| ListedPartner {
+                            id: id.clone(),
+                            data_source: Some(partner_config.data_source.clone()),
+                        })
+                        .collect::<Vec<_>>();
+                    partners.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+                    async move { Some(Ok(partners)) }
+                })
+                .await;
+
+                info!(status = "terminating");
+            }
+            .instrument(info_span!("partners_configuration_handler")),
+        );
+
+        (tx, task)
+    }
+
+    /// Handles status requests, the configuration file being always
+    /// available once loaded.
+    pub(crate) fn handle_status(
+        &self,
+        concurrency_limit: NonZeroUsize,
+    ) -> (StatusChannel, JoinHandle<()>) {
+        let (tx, rx) = roundtrip_channel(10);
+
+        let task = tokio::spawn(
+            async move {
+                info!(status = "started");
+
+                process_concurrently(rx, concurrency_limit, |_| async { Some(StatusCode::OK) })
+                    .await;
+
+                info!(status = "terminating");
+            }
+            .instrument(info_span!("configuration_status_handler")),
+        );
+
+        (tx, task)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Weekday;
+    use indoc::indoc;
+
+    use crate::config_api::WeekStart;
+    use crate::testing::temp_path;
+
+    use super::*;
+
+    const TOML: &str = indoc! {r#"
+        [common]
+        shiftStartTimes = ["06:00:00", "14:00:00"]
+        pauses = [["10:00:00", "10:20:00"]]
+        weekStart = { day = "Monday", shiftIndex = 0 }
+
+        [partners.id1]
+        targetCycleTime = 21.2
+        targetEfficiency = 0.8
+        shiftEngaged = [true, false]
+    "#};
+
+    fn expected() -> FileConfig {
+        FileConfig {
+            common: CommonConfig {
+                shift_start_times: vec!["06:00:00".parse().unwrap(), "14:00:00".parse().unwrap()],
+                pauses: vec![("10:00:00".parse().unwrap(), "10:20:00".parse().unwrap())],
+                week_start: WeekStart {
+                    day: Weekday::Mon,
+                    shift_index: 0,
+                },
+                timezone: None,
+                stale: false,
+            },
+            sites: HashMap::new(),
+            partners: HashMap::from([(
+                "id1".to_string(),
+                PartnerConfig {
+                    target_cycle_time: 21.2,
+                    target_efficiency: 0.8,
+                    shift_engaged: vec![true, false],
+                    site: None,
+                    timezone: None,
+                    data_source: Default::default(),
+                    stale: false,
+                },
+            )]),
+        }
+    }
+
+    fn config(path: PathBuf) -> Config {
+        Config {
+            config_file: Some(path),
+            config_file_reload_interval: Duration::from_millis(10).into(),
+        }
+    }
+
+    mod parse {
+        use super::*;
+
+        #[test]
+        fn toml() {
+            let file_config = parse(Path::new("config.toml"), TOML).unwrap();
+            assert_eq!(file_config, expected());
+        }
+
+        #[test]
+        fn yaml() {
+            let contents = indoc! {r#"
+                common:
+                  shiftStartTimes: ["06:00:00", "14:00:00"]
+                  pauses: [["10:00:00", "10:20:00"]]
+                  weekStart: { day: Monday, shiftIndex: 0 }
+                partners:
+                  id1:
+                    targetCycleTime: 21.2
+                    targetEfficiency: 0.8
+                    shiftEngaged: [true, false]
+            "#};
+            let file_config = parse(Path::new("config.yaml"), contents).unwrap();
+            assert_eq!(file_config, expected());
+        }
+
+        #[test]
+        fn json() {
+            let contents = indoc! {r#"{
+                "common": {
+                    "shiftStartTimes": ["06:00:00", "14:00:00"],
+                    "pauses": [["10:00:00", "10:20:00"]],
+                    "weekStart": { "day": "Monday", "shiftIndex": 0 }
+                },
+                "partners": {
+                    "id1": {
+                        "targetCycleTime": 21.2,
+                        "targetEfficiency": 0.8,
+                        "shiftEngaged": [true, false]
+                    }
+                }
+            }"#};
+            let file_config = parse(Path::new("config.json"), contents).unwrap();
+            assert_eq!(file_config, expected());
+        }
+
+        #[test]
+        fn unsupported_extension() {
+            let err = parse(Path::new("config.ini"), TOML).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "unsupported file extension, expected yaml, yml, toml or json"
+            );
+        }
+
+        #[test]
+        fn invalid_common_config() {
+            let contents =
+                TOML.replace(r#"["06:00:00", "14:00:00"]"#, r#"["14:00:00", "06:00:00"]"#);
+            let err = parse(Path::new("config.toml"), &contents).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "invalid common configuration: shift start times are not sorted"
+            );
+        }
+
+        #[test]
+        fn sites() {
+            let contents = format!(
+                "{TOML}{}",
+                indoc! {r#"
+                    site = "plant2"
+
+                    [sites.plant2]
+                    shiftStartTimes = ["07:00:00"]
+                    pauses = []
+                    weekStart = { day = "Sunday", shiftIndex = 0 }
+                "#}
+            );
+            let file_config = parse(Path::new("config.toml"), &contents).unwrap();
+            let site_common_config = file_config.common_config(Some("plant2")).unwrap();
+            assert_eq!(site_common_config.week_start.day, Weekday::Sun);
+            assert_eq!(file_config.partners["id1"].site.as_deref(), Some("plant2"));
+            assert!(file_config.common_config(Some("unknown")).is_none());
+        }
+
+        #[test]
+        fn unknown_site() {
+            let contents = format!("{TOML}site = \"unknown\"\n");
+            let err = parse(Path::new("config.toml"), &contents).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "invalid configuration for partner `id1`: unknown site `unknown`"
+            );
+        }
+
+        #[test]
+        fn invalid_partner_config() {
+            let contents = TOML.replace("targetEfficiency = 0.8", "targetEfficiency = 80");
+            let err = parse(Path::new("config.toml"), &contents).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "invalid configuration for partner `id1`: target efficiency is not between 0 and 1"
+            );
+        }
+    }
+
+    #[test]
+    fn not_configured() {
+        let config = Config {
+            config_file: None,
+            config_file_reload_interval: Duration::ZERO.into(),
+        };
+        assert!(Provider::load(&config, &HashSet::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn missing_file() {
+        let config = config("/nonexistent.toml".into());
+        let err = Provider::load(&config, &HashSet::new()).err().unwrap();
+        assert_eq!(err.to_string(), "error reading /nonexistent.toml");
+    }
+
+    #[test]
+    fn data_sources() {
+        let path = temp_path("config-data-sources.toml");
+        let contents = format!("{TOML}dataSource = {{ name = \"plant2\" }}\n");
+        fs::write(&path, contents).unwrap();
+        let config = config(path.clone());
+        let err = Provider::load(&config, &HashSet::new()).err().unwrap();
+        assert_eq!(
+            format!("{err:#}"),
+            format!(
+                "error loading {}: invalid configuration for partner `id1`: unknown data source `plant2`",
+                path.display()
+            )
+        );
+        let data_sources = HashSet::from(["plant2".to_string()]);
+        let provider = Provider::load(&config, &data_sources).unwrap().unwrap();
+        assert_eq!(
+            provider.config_rx.borrow().partners["id1"]
+                .data_source
+                .name
+                .as_deref(),
+            Some("plant2")
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn handlers() {
+        let path = temp_path("config-handlers.toml");
+        fs::write(&path, TOML).unwrap();
+        let provider = Provider::load(&config(path.clone()), &HashSet::new())
+            .unwrap()
+            .unwrap();
+        let (common_config_channel, _) = provider.handle_common_config(NonZeroUsize::MIN);
+        let (partner_config_channel, _) = provider.handle_partner_config(NonZeroUsize::MIN);
+        let (partners_channel, _) = provider.handle_partners(NonZeroUsize::MIN);
+        let (status_channel, _) = provider.handle_status(NonZeroUsize::MIN);
+        let expected = expected();
+        assert_eq!(
+            common_config_channel
+                .roundtrip(CommonConfigRequest { site: None })
+                .await,
+            Ok(Ok(expected.common))
+        );
+        let request = PartnerConfigRequest {
+            id: "id1".to_string(),
+        };
+        assert_eq!(
+            partner_config_channel.roundtrip(request).await,
+            Ok(Ok(expected.partners["id1"].clone()))
+        );
+        let request = PartnerConfigRequest {
+            id: "unknown".to_string(),
+        };
+        assert_eq!(
+            partner_config_channel.roundtrip(request).await,
+            Ok(Err(Failure::NotFound))
+        );
+        let listed_partner = ListedPartner {
+            id: "id1".to_string(),
+            data_source: Some(Default::default()),
+        };
+        assert_eq!(
+            partners_channel.roundtrip(()).await,
+            Ok(Ok(vec![listed_partner]))
+        );
+        assert_eq!(status_channel.roundtrip(()).await.unwrap(), StatusCode::OK);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload() {
+        let path = temp_path("config-reload.toml");
+        fs::write(&path, TOML).unwrap();
+        let provider = Provider::load(&config(path.clone()), &HashSet::new())
+            .unwrap()
+            .unwrap();
+        let task = provider.handle_reload();
+
+        fs::write(&path, TOML.replace("21.2", "30.5")).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            provider.config_rx.borrow().partners["id1"].target_cycle_time,
+            30.5
+        );
+
+        fs::write(&path, "invalid").unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            provider.config_rx.borrow().partners["id1"].target_cycle_time,
+            30.5
+        );
+
+        drop(provider);
+        tokio::time::timeout(Duration::from_millis(100), task)
+            .await
+            .expect("task should terminate")
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
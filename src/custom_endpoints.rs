@@ -0,0 +1,364 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context as _, bail};
+use clap::Args;
+use serde::Deserialize;
+
+use crate::flux::validate_placeholders;
+
+/// Routes of built-in endpoints, that custom endpoints can not use.
+const RESERVED_ROUTES: [&str; 10] = [
+    "health",
+    "live",
+    "ready",
+    "partners",
+    "timeline",
+    "performance",
+    "shift-objective",
+    "week-objective",
+    "config-cache",
+    "config-webhook",
+];
+
+#[derive(Args)]
+#[group(skip)]
+pub(crate) struct Config {
+    /// TOML file declaring custom computed endpoints
+    #[arg(env, long)]
+    custom_endpoints_file: Option<PathBuf>,
+}
+
+/// Row of a custom query result, with raw values.
+pub(crate) type Row = HashMap<String, String>;
+
+/// Reduces the rows of a custom query result to a single value.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub(crate) enum Reducer {
+    /// Value of a column in the last row.
+    Last { column: String },
+    /// Sum of a column.
+    Sum { column: String },
+    /// Mean of a column.
+    Mean { column: String },
+    /// Ratio of the sums of two columns.
+    Ratio {
+        numerator: String,
+        denominator: String,
+    },
+}
+
+fn values<'a>(rows: &'a [Row], column: &'a str) -> impl Iterator<Item = Result<f64, String>> + 'a {
+    rows.iter()
+        .filter_map(move |row| row.get(column).filter(|value| !value.is_empty()))
+        .map(move |value| {
+            value
+                .parse::<f64>()
+                .map_err(|_| format!("non-numeric value `{value}` in column `{column}`"))
+        })
+}
+
+fn sum(rows: &[Row], column: &str) -> Result<f64, String> {
+    values(rows, column).sum()
+}
+
+impl Reducer {
+    /// Returns the reduced value, `None` meaning that there is no value.
+    pub(crate) fn reduce(&self, rows: &[Row]) -> Result<Option<f64>, String> {
+        match self {
+            Self::Last { column } => values(rows, column).last().transpose(),
+            Self::Sum { column } => sum(rows, column).map(Some),
+            Self::Mean { column } => {
+                let values = values(rows, column).collect::<Result<Vec<_>, _>>()?;
+                if values.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(values.iter().sum::<f64>() / values.len() as f64))
+            }
+            Self::Ratio {
+                numerator,
+                denominator,
+            } => {
+                let numerator = sum(rows, numerator)?;
+                let denominator = sum(rows, denominator)?;
+                Ok((denominator != 0.0).then(|| numerator / denominator))
+            }
+        }
+    }
+}
+
+/// Custom endpoint, mounted on `/{route}/{id}`.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Endpoint {
+    pub(crate) route: String,
+    pub(crate) query: Arc<str>,
+    pub(crate) reducer: Reducer,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EndpointsFile {
+    #[serde(default)]
+    endpoints: Vec<Endpoint>,
+}
+
+fn validate(endpoint: &Endpoint) -> Result<(), String> {
+    let valid_route = !endpoint.route.is_empty()
+        && endpoint
+            .route
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid_route {
+        return Err("route must only contain lowercase letters, digits and dashes".to_string());
+    }
+    if RESERVED_ROUTES.contains(&endpoint.route.as_str()) {
+        return Err("route is reserved for a built-in endpoint".to_string());
+    }
+    validate_placeholders(
+        &endpoint.query,
+        &["__bucketplaceholder__", "__idplaceholder__"],
+        &["__measurementplaceholder__", "__startplaceholder__"],
+    )
+}
+
+fn parse(contents: &str) -> anyhow::Result<Vec<Arc<Endpoint>>> {
+    let EndpointsFile { endpoints } = toml::from_str(contents)?;
+    let mut routes = HashSet::new();
+    for endpoint in &endpoints {
+        if let Err(reason) = validate(endpoint) {
+            bail!("invalid endpoint `{}`: {reason}", endpoint.route);
+        }
+        if !routes.insert(endpoint.route.as_str()) {
+            bail!("duplicate endpoint `{}`", endpoint.route);
+        }
+    }
+    Ok(endpoints.into_iter().map(Arc::new).collect())
+}
+
+fn load_file(path: &Path) -> anyhow::Result<Vec<Arc<Endpoint>>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("error reading {}", path.display()))?;
+    parse(&contents).with_context(|| format!("error loading {}", path.display()))
+}
+
+/// Loads the custom endpoints, if a file is configured.
+pub(crate) fn load(config: &Config) -> anyhow::Result<Vec<Arc<Endpoint>>> {
+    config
+        .custom_endpoints_file
+        .as_deref()
+        .map_or_else(|| Ok(Vec::new()), load_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    fn rows(data: &[&[(&str, &str)]]) -> Vec<Row> {
+        data.iter()
+            .map(|row| {
+                row.iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    mod reducer {
+        use super::*;
+
+        fn column(name: &str) -> String {
+            name.to_string()
+        }
+
+        #[test]
+        fn last() {
+            let reducer = Reducer::Last {
+                column: column("v"),
+            };
+            assert_eq!(reducer.reduce(&[]), Ok(None));
+            let rows = rows(&[&[("v", "1")], &[("v", "2.5")], &[("v", "")]]);
+            assert_eq!(reducer.reduce(&rows), Ok(Some(2.5)));
+        }
+
+        #[test]
+        fn sum() {
+            let reducer = Reducer::Sum {
+                column: column("v"),
+            };
+            assert_eq!(reducer.reduce(&[]), Ok(Some(0.0)));
+            let rows = rows(&[&[("v", "1")], &[("v", "2.5")], &[("other", "3")]]);
+            assert_eq!(reducer.reduce(&rows), Ok(Some(3.5)));
+        }
+
+        #[test]
+        fn mean() {
+            let reducer = Reducer::Mean {
+                column: column("v"),
+            };
+            assert_eq!(reducer.reduce(&[]), Ok(None));
+            let rows = rows(&[&[("v", "1")], &[("v", "2")], &[("v", "")]]);
+            assert_eq!(reducer.reduce(&rows), Ok(Some(1.5)));
+        }
+
+        #[test]
+        fn ratio() {
+            let reducer = Reducer::Ratio {
+                numerator: column("n"),
+                denominator: column("d"),
+            };
+            assert_eq!(reducer.reduce(&[]), Ok(None));
+            let rows = rows(&[&[("n", "1"), ("d", "4")], &[("n", "2"), ("d", "4")]]);
+            assert_eq!(reducer.reduce(&rows), Ok(Some(0.375)));
+        }
+
+        #[test]
+        fn non_numeric() {
+            let reducer = Reducer::Sum {
+                column: column("v"),
+            };
+            let rows = rows(&[&[("v", "1")], &[("v", "abc")]]);
+            assert_eq!(
+                reducer.reduce(&rows),
+                Err("non-numeric value `abc` in column `v`".to_string())
+            );
+        }
+    }
+
+    mod parse {
+        use super::*;
+
+        #[test]
+        fn empty() {
+            assert!(parse("").unwrap().is_empty());
+        }
+
+        #[test]
+        fn success() {
+            let endpoints = parse(indoc! {r#"
+                [[endpoints]]
+                route = "scrap-ratio"
+                query = "from(bucket: \"__bucketplaceholder__\") |> filter(fn: (r) => r.id == \"__idplaceholder__\")"
+                reducer = { kind = "ratio", numerator = "scrap", denominator = "total" }
+
+                [[endpoints]]
+                route = "last-speed"
+                query = "__bucketplaceholder__ __idplaceholder__ __startplaceholder__"
+                reducer = { kind = "last", column = "_value" }
+            "#})
+            .unwrap();
+            assert_eq!(endpoints.len(), 2);
+            assert_eq!(endpoints[0].route, "scrap-ratio");
+            assert_eq!(
+                endpoints[0].reducer,
+                Reducer::Ratio {
+                    numerator: "scrap".to_string(),
+                    denominator: "total".to_string()
+                }
+            );
+            assert_eq!(
+                endpoints[1].reducer,
+                Reducer::Last {
+                    column: "_value".to_string()
+                }
+            );
+        }
+
+        #[test]
+        fn unknown_reducer() {
+            let result = parse(indoc! {r#"
+                [[endpoints]]
+                route = "median"
+                query = "__bucketplaceholder__ __idplaceholder__"
+                reducer = { kind = "median", column = "_value" }
+            "#});
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn invalid_route() {
+            let err = parse(indoc! {r#"
+                [[endpoints]]
+                route = "some/route"
+                query = "__bucketplaceholder__ __idplaceholder__"
+                reducer = { kind = "sum", column = "_value" }
+            "#})
+            .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "invalid endpoint `some/route`: route must only contain lowercase letters, \
+                digits and dashes"
+            );
+        }
+
+        #[test]
+        fn reserved_route() {
+            let err = parse(indoc! {r#"
+                [[endpoints]]
+                route = "timeline"
+                query = "__bucketplaceholder__ __idplaceholder__"
+                reducer = { kind = "sum", column = "_value" }
+            "#})
+            .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "invalid endpoint `timeline`: route is reserved for a built-in endpoint"
+            );
+        }
+
+        #[test]
+        fn invalid_query() {
+            let err = parse(indoc! {r#"
+                [[endpoints]]
+                route = "some-route"
+                query = "__bucketplaceholder__ __idplaceholder__ __targetcycletimeplaceholder__"
+                reducer = { kind = "sum", column = "_value" }
+            "#})
+            .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "invalid endpoint `some-route`: unknown `__targetcycletimeplaceholder__` \
+                placeholder"
+            );
+        }
+
+        #[test]
+        fn duplicate_route() {
+            let err = parse(indoc! {r#"
+                [[endpoints]]
+                route = "some-route"
+                query = "__bucketplaceholder__ __idplaceholder__"
+                reducer = { kind = "sum", column = "_value" }
+
+                [[endpoints]]
+                route = "some-route"
+                query = "__bucketplaceholder__ __idplaceholder__"
+                reducer = { kind = "mean", column = "_value" }
+            "#})
+            .unwrap_err();
+            assert_eq!(err.to_string(), "duplicate endpoint `some-route`");
+        }
+    }
+
+    #[test]
+    fn not_configured() {
+        let config = Config {
+            custom_endpoints_file: None,
+        };
+        assert!(load(&config).unwrap().is_empty());
+    }
+
+    #[test]
+    fn missing_file() {
+        let config = Config {
+            custom_endpoints_file: Some("/nonexistent.toml".into()),
+        };
+        let err = load(&config).unwrap_err();
+        assert_eq!(err.to_string(), "error reading /nonexistent.toml");
+    }
+}
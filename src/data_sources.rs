@@ -0,0 +1,229 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, bail};
+use clap::Args;
+use serde::Deserialize;
+use url::Url;
+
+use crate::tls::TlsOptions;
+
+#[derive(Args)]
+#[group(skip)]
+pub(crate) struct Config {
+    /// TOML file declaring the InfluxDB data sources partner configurations can refer to by name
+    #[arg(env, long)]
+    influxdb_data_sources_file: Option<PathBuf>,
+}
+
+/// InfluxDB instance declared locally, which partner configurations can route
+/// queries to by name, with its own credentials and TLS settings.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NamedDataSource {
+    pub(crate) name: String,
+    pub(crate) url: Url,
+    /// Organization name or ID, the command line one if not set.
+    pub(crate) org: Option<String>,
+    pub(crate) api_token: Option<String>,
+    /// File containing the API token, reloaded on change.
+    pub(crate) api_token_file: Option<PathBuf>,
+    pub(crate) ca_cert: Option<PathBuf>,
+    pub(crate) client_cert: Option<PathBuf>,
+    pub(crate) client_key: Option<PathBuf>,
+    #[serde(default)]
+    pub(crate) tls_insecure_skip_verify: bool,
+}
+
+impl NamedDataSource {
+    pub(crate) fn tls_options(&self) -> TlsOptions<'_> {
+        TlsOptions {
+            ca_cert: self.ca_cert.as_deref(),
+            client_cert: self.client_cert.as_deref(),
+            client_key: self.client_key.as_deref(),
+            insecure_skip_verify: self.tls_insecure_skip_verify,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DataSourcesFile {
+    #[serde(default)]
+    data_sources: Vec<NamedDataSource>,
+}
+
+fn validate(data_source: &NamedDataSource) -> Result<(), &'static str> {
+    if data_source.name.is_empty() {
+        return Err("name is empty");
+    }
+    match (&data_source.api_token, &data_source.api_token_file) {
+        (Some(_), Some(_)) => return Err("both API token and API token file are set"),
+        (None, None) => return Err("API token or API token file is required"),
+        (Some(token), None) if token.is_empty() => return Err("API token is empty"),
+        _ => {}
+    }
+    if data_source.client_cert.is_some() != data_source.client_key.is_some() {
+        return Err("client certificate and key must be set together");
+    }
+    Ok(())
+}
+
+fn parse(contents: &str) -> anyhow::Result<Vec<NamedDataSource>> {
+    let DataSourcesFile { data_sources } = toml::from_str(contents)?;
+    let mut names = HashSet::new();
+    for data_source in &data_sources {
+        if let Err(reason) = validate(data_source) {
+            bail!("invalid data source `{}`: {reason}", data_source.name);
+        }
+        if !names.insert(data_source.name.as_str()) {
+            bail!("duplicate data source `{}`", data_source.name);
+        }
+    }
+    Ok(data_sources)
+}
+
+fn load_file(path: &Path) -> anyhow::Result<Vec<NamedDataSource>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("error reading {}", path.display()))?;
+    parse(&contents).with_context(|| format!("error loading {}", path.display()))
+}
+
+/// Loads the named data sources, if a file is configured.
+pub(crate) fn load(config: &Config) -> anyhow::Result<Vec<NamedDataSource>> {
+    config
+        .influxdb_data_sources_file
+        .as_deref()
+        .map_or_else(|| Ok(Vec::new()), load_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    mod parse {
+        use super::*;
+
+        #[test]
+        fn empty() {
+            assert!(parse("").unwrap().is_empty());
+        }
+
+        #[test]
+        fn success() {
+            let data_sources = parse(indoc! {r#"
+                [[data_sources]]
+                name = "plant2"
+                url = "https://influxdb-plant2:8086"
+                org = "plant2"
+                api_token_file = "/run/secrets/plant2-token"
+                ca_cert = "/etc/ssl/plant2-ca.pem"
+
+                [[data_sources]]
+                name = "plant3"
+                url = "http://influxdb-plant3:8086"
+                api_token = "sometoken"
+            "#})
+            .unwrap();
+            assert_eq!(
+                data_sources[0],
+                NamedDataSource {
+                    name: "plant2".to_string(),
+                    url: "https://influxdb-plant2:8086".parse().unwrap(),
+                    org: Some("plant2".to_string()),
+                    api_token: None,
+                    api_token_file: Some("/run/secrets/plant2-token".into()),
+                    ca_cert: Some("/etc/ssl/plant2-ca.pem".into()),
+                    client_cert: None,
+                    client_key: None,
+                    tls_insecure_skip_verify: false,
+                }
+            );
+            assert_eq!(data_sources[1].name, "plant3");
+            assert_eq!(data_sources[1].api_token.as_deref(), Some("sometoken"));
+        }
+
+        #[test]
+        fn missing_token() {
+            let err = parse(indoc! {r#"
+                [[data_sources]]
+                name = "plant2"
+                url = "https://influxdb-plant2:8086"
+            "#})
+            .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "invalid data source `plant2`: API token or API token file is required"
+            );
+        }
+
+        #[test]
+        fn both_tokens() {
+            let err = parse(indoc! {r#"
+                [[data_sources]]
+                name = "plant2"
+                url = "https://influxdb-plant2:8086"
+                api_token = "sometoken"
+                api_token_file = "/run/secrets/plant2-token"
+            "#})
+            .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "invalid data source `plant2`: both API token and API token file are set"
+            );
+        }
+
+        #[test]
+        fn client_cert_without_key() {
+            let err = parse(indoc! {r#"
+                [[data_sources]]
+                name = "plant2"
+                url = "https://influxdb-plant2:8086"
+                api_token = "sometoken"
+                client_cert = "/etc/ssl/client.pem"
+            "#})
+            .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "invalid data source `plant2`: client certificate and key must be set together"
+            );
+        }
+
+        #[test]
+        fn duplicate_name() {
+            let err = parse(indoc! {r#"
+                [[data_sources]]
+                name = "plant2"
+                url = "https://influxdb-plant2:8086"
+                api_token = "sometoken"
+
+                [[data_sources]]
+                name = "plant2"
+                url = "https://other-influxdb:8086"
+                api_token = "othertoken"
+            "#})
+            .unwrap_err();
+            assert_eq!(err.to_string(), "duplicate data source `plant2`");
+        }
+    }
+
+    #[test]
+    fn not_configured() {
+        let config = Config {
+            influxdb_data_sources_file: None,
+        };
+        assert!(load(&config).unwrap().is_empty());
+    }
+
+    #[test]
+    fn missing_file() {
+        let config = Config {
+            influxdb_data_sources_file: Some("/nonexistent.toml".into()),
+        };
+        let err = load(&config).unwrap_err();
+        assert_eq!(err.to_string(), "error reading /nonexistent.toml");
+    }
+}
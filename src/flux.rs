@@ -0,0 +1,471 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Context as _, bail};
+use clap::Args;
+use serde::Deserialize;
+
+const SCHEMA_PLACEHOLDERS: [&str; 7] = [
+    "__idtagplaceholder__",
+    "__goodpartsplaceholder__",
+    "__partrefplaceholder__",
+    "__averagecycletimeplaceholder__",
+    "__cycleplaceholder__",
+    "__cycletimeoverplaceholder__",
+    "__campchangeplaceholder__",
+];
+
+const KEYWORDS: [&str; 12] = [
+    "and", "builtin", "else", "empty", "exists", "if", "import", "not", "option", "or", "package",
+    "return",
+];
+
+/// Flux identifier, safe to substitute into queries.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
+pub(crate) struct Identifier(String);
+
+impl TryFrom<String> for Identifier {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let mut chars = value.chars();
+        let valid_start = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+        let valid_rest = chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_start || !valid_rest || KEYWORDS.contains(&value.as_str()) {
+            return Err(format!("`{value}` is not a valid Flux identifier"));
+        }
+        Ok(Self(value))
+    }
+}
+
+impl FromStr for Identifier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.to_string().try_into()
+    }
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Value safe to substitute into Flux string literals, such as a bucket or
+/// measurement name: it can neither end the literal, escape a character nor
+/// interpolate an expression.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
+pub(crate) struct StringLiteral(String);
+
+impl TryFrom<String> for StringLiteral {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let forbidden_char = value
+            .chars()
+            .any(|c| c == '"' || c == '\\' || c.is_control());
+        if value.is_empty() || forbidden_char || value.contains("${") {
+            return Err(format!(
+                "`{value}` is not a valid Flux string literal value"
+            ));
+        }
+        Ok(Self(value))
+    }
+}
+
+impl FromStr for StringLiteral {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.to_string().try_into()
+    }
+}
+
+impl AsRef<str> for StringLiteral {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Names of the tag and fields read by queries, overriding the default ones.
+#[derive(Args, Clone, Debug, Default, Deserialize, PartialEq, Eq, Hash)]
+#[group(skip)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Schema {
+    /// InfluxDB tag holding the partner ID (`id` if not set)
+    #[arg(env = "INFLUXDB_ID_TAG", long = "influxdb-id-tag")]
+    pub(crate) id_tag: Option<Identifier>,
+
+    /// InfluxDB field holding the good parts counter (`goodParts` if not set)
+    #[arg(env = "INFLUXDB_GOOD_PARTS_FIELD", long = "influxdb-good-parts-field")]
+    pub(crate) good_parts_field: Option<Identifier>,
+
+    /// InfluxDB field holding the part reference (`partRef` if not set)
+    #[arg(env = "INFLUXDB_PART_REF_FIELD", long = "influxdb-part-ref-field")]
+    pub(crate) part_ref_field: Option<Identifier>,
+
+    /// InfluxDB field holding the average cycle time (`averageCycleTime` if not set)
+    #[arg(
+        env = "INFLUXDB_AVERAGE_CYCLE_TIME_FIELD",
+        long = "influxdb-average-cycle-time-field"
+    )]
+    pub(crate) average_cycle_time_field: Option<Identifier>,
+
+    /// InfluxDB field holding the cycle status (`cycle` if not set)
+    #[arg(env = "INFLUXDB_CYCLE_FIELD", long = "influxdb-cycle-field")]
+    pub(crate) cycle_field: Option<Identifier>,
+
+    /// InfluxDB field holding the cycle time over status (`cycleTimeOver` if not set)
+    #[arg(
+        env = "INFLUXDB_CYCLE_TIME_OVER_FIELD",
+        long = "influxdb-cycle-time-over-field"
+    )]
+    pub(crate) cycle_time_over_field: Option<Identifier>,
+
+    /// InfluxDB field holding the campaign change status (`campChange` if not set)
+    #[arg(
+        env = "INFLUXDB_CAMP_CHANGE_FIELD",
+        long = "influxdb-camp-change-field"
+    )]
+    pub(crate) camp_change_field: Option<Identifier>,
+}
+
+impl Schema {
+    /// Returns this schema, with names not set taken from `fallback`.
+    pub(crate) fn or(&self, fallback: &Self) -> Self {
+        fn or(name: &Option<Identifier>, fallback: &Option<Identifier>) -> Option<Identifier> {
+            name.as_ref().or(fallback.as_ref()).cloned()
+        }
+
+        Self {
+            id_tag: or(&self.id_tag, &fallback.id_tag),
+            good_parts_field: or(&self.good_parts_field, &fallback.good_parts_field),
+            part_ref_field: or(&self.part_ref_field, &fallback.part_ref_field),
+            average_cycle_time_field: or(
+                &self.average_cycle_time_field,
+                &fallback.average_cycle_time_field,
+            ),
+            cycle_field: or(&self.cycle_field, &fallback.cycle_field),
+            cycle_time_over_field: or(&self.cycle_time_over_field, &fallback.cycle_time_over_field),
+            camp_change_field: or(&self.camp_change_field, &fallback.camp_change_field),
+        }
+    }
+
+    /// Replaces the tag and field names placeholders in a Flux query.
+    pub(crate) fn apply(&self, flux_query: &str) -> String {
+        let names = [
+            (&self.id_tag, "id"),
+            (&self.good_parts_field, "goodParts"),
+            (&self.part_ref_field, "partRef"),
+            (&self.average_cycle_time_field, "averageCycleTime"),
+            (&self.cycle_field, "cycle"),
+            (&self.cycle_time_over_field, "cycleTimeOver"),
+            (&self.camp_change_field, "campChange"),
+        ];
+        SCHEMA_PLACEHOLDERS.into_iter().zip(names).fold(
+            flux_query.to_string(),
+            |query, (placeholder, (name, default))| {
+                let name = name.as_ref().map_or(default, |name| name.0.as_str());
+                query.replace(placeholder, name)
+            },
+        )
+    }
+}
+
+/// Flux query template.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Template {
+    Timeline,
+    Performance,
+}
+
+impl Template {
+    fn file_name(self) -> &'static str {
+        match self {
+            Self::Timeline => "timeline.flux",
+            Self::Performance => "performance.flux",
+        }
+    }
+
+    fn builtin(self) -> &'static str {
+        match self {
+            Self::Timeline => include_str!("timeline.flux"),
+            Self::Performance => include_str!("performance.flux"),
+        }
+    }
+
+    /// Placeholders that the template must contain.
+    fn required_placeholders(self) -> [&'static str; 3] {
+        let parameter = match self {
+            Self::Timeline => "__targetcycletimeplaceholder__",
+            Self::Performance => "__startplaceholder__",
+        };
+        ["__bucketplaceholder__", "__idplaceholder__", parameter]
+    }
+
+    fn validate(self, flux_query: &str) -> Result<(), String> {
+        validate_placeholders(
+            flux_query,
+            &self.required_placeholders(),
+            &["__measurementplaceholder__"],
+        )
+    }
+}
+
+/// Checks that a query template contains the required placeholders and no
+/// unknown one, tag and field names placeholders being always allowed.
+pub(crate) fn validate_placeholders(
+    flux_query: &str,
+    required: &[&str],
+    optional: &[&str],
+) -> Result<(), String> {
+    const SUFFIX: &str = "placeholder__";
+
+    if let Some(missing) = required.iter().find(|p| !flux_query.contains(*p)) {
+        return Err(format!("missing `{missing}` placeholder"));
+    }
+    for (end, _) in flux_query.match_indices(SUFFIX) {
+        let Some(start) = flux_query[..end].rfind("__") else {
+            continue;
+        };
+        let placeholder = &flux_query[start..end + SUFFIX.len()];
+        let known = required.contains(&placeholder)
+            || optional.contains(&placeholder)
+            || SCHEMA_PLACEHOLDERS.contains(&placeholder);
+        if !known {
+            return Err(format!("unknown `{placeholder}` placeholder"));
+        }
+    }
+    Ok(())
+}
+
+/// Flux query templates in use.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Templates {
+    timeline: Arc<str>,
+    performance: Arc<str>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            timeline: Template::Timeline.builtin().into(),
+            performance: Template::Performance.builtin().into(),
+        }
+    }
+}
+
+impl Templates {
+    pub(crate) fn get(&self, template: Template) -> Arc<str> {
+        match template {
+            Template::Timeline => Arc::clone(&self.timeline),
+            Template::Performance => Arc::clone(&self.performance),
+        }
+    }
+
+    /// Loads templates from files in a directory, missing files falling back
+    /// to the built-in templates.
+    pub(crate) fn load(dir: &Path) -> anyhow::Result<Self> {
+        if !dir.is_dir() {
+            bail!("{} is not a directory", dir.display());
+        }
+        let load = |template: Template| -> anyhow::Result<Arc<str>> {
+            let path = dir.join(template.file_name());
+            let flux_query = match fs::read_to_string(&path) {
+                Ok(flux_query) => flux_query,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    return Ok(template.builtin().into());
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| format!("error reading {}", path.display()));
+                }
+            };
+            if let Err(reason) = template.validate(&flux_query) {
+                bail!("invalid query in {}: {reason}", path.display());
+            }
+            Ok(flux_query.into())
+        };
+
+        Ok(Self {
+            timeline: load(Template::Timeline)?,
+            performance: load(Template::Performance)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod identifier {
+        use super::*;
+
+        #[test]
+        fn valid() {
+            for value in ["id", "_id", "good_count", "goodParts2"] {
+                assert!(value.parse::<Identifier>().is_ok(), "{value}");
+            }
+        }
+
+        #[test]
+        fn invalid() {
+            for value in ["", "2id", "good-count", "r.id", "id\"", "if", "exists"] {
+                assert!(value.parse::<Identifier>().is_err(), "{value}");
+            }
+        }
+    }
+
+    mod string_literal {
+        use super::*;
+
+        #[test]
+        fn valid() {
+            for value in ["bucket", "plant 2/raw", "machines_$1", "{id}"] {
+                assert!(value.parse::<StringLiteral>().is_ok(), "{value}");
+            }
+        }
+
+        #[test]
+        fn invalid() {
+            for value in ["", "bucket\"", "bu\\cket", "bucket\n", "${token}"] {
+                assert!(value.parse::<StringLiteral>().is_err(), "{value:?}");
+            }
+        }
+    }
+
+    mod template {
+        use super::*;
+
+        #[test]
+        fn builtins_are_valid() {
+            for template in [Template::Timeline, Template::Performance] {
+                assert_eq!(template.validate(template.builtin()), Ok(()));
+            }
+        }
+
+        #[test]
+        fn missing_placeholder() {
+            let flux_query = "__bucketplaceholder__ __idplaceholder__";
+            assert_eq!(
+                Template::Performance.validate(flux_query),
+                Err("missing `__startplaceholder__` placeholder".to_string())
+            );
+        }
+
+        #[test]
+        fn unknown_placeholder() {
+            let flux_query = "__bucketplaceholder__ __idplaceholder__ __startplaceholder__ __idtagplaceholder__ \
+                __badpartsplaceholder__";
+            assert_eq!(
+                Template::Performance.validate(flux_query),
+                Err("unknown `__badpartsplaceholder__` placeholder".to_string())
+            );
+        }
+
+        #[test]
+        fn wrong_template_placeholder() {
+            let flux_query = "__bucketplaceholder__ __idplaceholder__ __startplaceholder__ \
+                __targetcycletimeplaceholder__";
+            assert_eq!(
+                Template::Performance.validate(flux_query),
+                Err("unknown `__targetcycletimeplaceholder__` placeholder".to_string())
+            );
+        }
+    }
+
+    mod templates {
+        use super::*;
+        use crate::testing::temp_path;
+
+        #[test]
+        fn builtin_fallback() {
+            let dir = temp_path("flux-empty");
+            fs::create_dir_all(&dir).unwrap();
+            let templates = Templates::load(&dir).unwrap();
+            fs::remove_dir(&dir).unwrap();
+            assert_eq!(templates, Templates::default());
+        }
+
+        #[test]
+        fn missing_dir() {
+            let err = Templates::load(Path::new("/nonexistent")).unwrap_err();
+            assert_eq!(err.to_string(), "/nonexistent is not a directory");
+        }
+
+        #[test]
+        fn override_file() {
+            const TIMELINE: &str = "__bucketplaceholder__ __idplaceholder__ \
+                __targetcycletimeplaceholder__ custom";
+            let dir = temp_path("flux-override");
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("timeline.flux"), TIMELINE).unwrap();
+            let templates = Templates::load(&dir);
+            fs::remove_dir_all(&dir).unwrap();
+            let templates = templates.unwrap();
+            assert_eq!(templates.get(Template::Timeline).as_ref(), TIMELINE);
+            assert_eq!(
+                templates.get(Template::Performance).as_ref(),
+                Template::Performance.builtin()
+            );
+        }
+
+        #[test]
+        fn invalid_file() {
+            let dir = temp_path("flux-invalid");
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("performance.flux"), "from(bucket: \"b\")").unwrap();
+            let result = Templates::load(&dir);
+            fs::remove_dir_all(&dir).unwrap();
+            let err = result.unwrap_err().to_string();
+            assert!(err.starts_with("invalid query in "), "{err}");
+            assert!(
+                err.ends_with("performance.flux: missing `__bucketplaceholder__` placeholder"),
+                "{err}"
+            );
+        }
+    }
+
+    mod schema {
+        use super::*;
+
+        #[test]
+        fn apply_defaults() {
+            let query = Schema::default().apply(
+                "r.__idtagplaceholder__ r.__goodpartsplaceholder__ r.__partrefplaceholder__ \
+                r.__averagecycletimeplaceholder__ r.__cycleplaceholder__ \
+                r.__cycletimeoverplaceholder__ r.__campchangeplaceholder__",
+            );
+            assert_eq!(
+                query,
+                "r.id r.goodParts r.partRef r.averageCycleTime r.cycle r.cycleTimeOver \
+                r.campChange"
+            );
+        }
+
+        #[test]
+        fn or_apply() {
+            let partner = Schema {
+                id_tag: Some("machine".parse().unwrap()),
+                ..Default::default()
+            };
+            let global = Schema {
+                id_tag: Some("station".parse().unwrap()),
+                good_parts_field: Some("good_count".parse().unwrap()),
+                ..Default::default()
+            };
+            let query = partner
+                .or(&global)
+                .apply("r.__idtagplaceholder__ r.__goodpartsplaceholder__ r.__cycleplaceholder__");
+            assert_eq!(query, "r.machine r.good_count r.cycle");
+        }
+    }
+}
@@ -3,7 +3,22 @@ use axum_extra::headers::{self, Header};
 use chrono_tz::Tz;
 
 static CLIENT_TIMEZONE_HEADER_NAME: HeaderName = HeaderName::from_static("client-timezone");
+static TIMEZONE_HEADER_NAME: HeaderName = HeaderName::from_static("timezone");
 
+fn decode_timezone<'i, I>(values: &mut I) -> Result<Tz, headers::Error>
+where
+    I: Iterator<Item = &'i HeaderValue>,
+{
+    values
+        .next()
+        .ok_or_else(headers::Error::invalid)?
+        .to_str()
+        .map_err(|_| headers::Error::invalid())?
+        .parse::<Tz>()
+        .map_err(|_| headers::Error::invalid())
+}
+
+/// Timezone requested by the client, overriding the configured one.
 pub(crate) struct ClientTimezone(Tz);
 
 impl ClientTimezone {
@@ -22,17 +37,31 @@ impl Header for ClientTimezone {
         Self: Sized,
         I: Iterator<Item = &'i HeaderValue>,
     {
-        values
-            .next()
-            .ok_or_else(headers::Error::invalid)?
-            .to_str()
-            .map_err(|_| headers::Error::invalid())?
-            .parse::<Tz>()
-            .map_err(|_| headers::Error::invalid())
-            .map(Self)
+        decode_timezone(values).map(Self)
     }
 
     fn encode<E: Extend<HeaderValue>>(&self, _values: &mut E) {
         unimplemented!();
     }
 }
+
+/// Timezone a response was computed in.
+pub(crate) struct Timezone(pub(crate) Tz);
+
+impl Header for Timezone {
+    fn name() -> &'static HeaderName {
+        &TIMEZONE_HEADER_NAME
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, headers::Error>
+    where
+        Self: Sized,
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        decode_timezone(values).map(Self)
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend(std::iter::once(HeaderValue::from_static(self.0.name())));
+    }
+}
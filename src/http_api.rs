@@ -1,61 +1,272 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Instant;
+
 use axum::extract::{Path, State};
 use axum::http::HeaderValue;
-use axum::response::{IntoResponse, Response};
-use axum::{Json, Router, routing};
+use axum::response::{IntoResponseParts, ResponseParts};
+use axum::{Extension, Json, Router, middleware, routing};
 use axum_extra::TypedHeader;
-use bytes::{BufMut, BytesMut};
+use axum_extra::headers::CacheControl;
+use chrono_tz::Tz;
+use futures_util::future::{join_all, try_join_all};
+use futures_util::{StreamExt, stream};
 use reqwest::{StatusCode, header};
-use tracing::{error, instrument};
+use serde::Serialize;
+use tracing::{Instrument, error, info_span, instrument, warn};
+use utoipa::{OpenApi, ToSchema};
 
+use crate::api_error::{ApiError, Dependency, ErrorCode, ProblemDetails};
+use crate::cache_token::{self, CacheToken};
+use crate::channel::RoundtripSender;
 use crate::config_api::{
-    CommonConfig, CommonConfigChannel, PartnerConfig, PartnerConfigChannel, PartnerConfigRequest,
+    CacheInvalidator, CommonConfig, CommonConfigChannel, CommonConfigRequest, ConfigChange,
+    DataSource, PartnerConfig, PartnerConfigChannel, PartnerConfigRequest, PartnersChannel,
+    StatusChannel,
 };
-use crate::headers::ClientTimezone;
+use crate::custom_endpoints::Endpoint;
+use crate::headers::{ClientTimezone, Timezone};
 use crate::influxdb::{
-    HealthChannel, PerformanceChannel, PerformanceRequest, TimelineChannel, TimelineRequest,
-    TimelineResponse,
+    CustomChannel, CustomRequest, DiscoveryChannel, HealthChannel, PerformanceChannel,
+    PerformanceRequest, TimelineChannel, TimelineRequest, TimelineResponse, TimelineSlot,
 };
+use crate::negotiation::{Accept, Body, Format, Negotiated, OBJECT_FORMATS};
 use crate::production_objective::{
-    ObjectiveData, ShiftObjectiveChannel, ShiftObjectiveRequest, WeekObjectiveChannel,
-    WeekObjectiveRequest,
+    ObjectiveData, ObjectivePoint, ShiftObjectiveChannel, ShiftObjectiveRequest,
+    WeekObjectiveChannel, WeekObjectiveRequest,
 };
+use crate::upstream::{BreakerState, CircuitBreaker, Failure};
 
-type HandlerError = (StatusCode, &'static str);
-
-const INTERNAL_ERROR: HandlerError = (StatusCode::INTERNAL_SERVER_ERROR, "internal server error");
-
-impl IntoResponse for TimelineResponse {
-    // Taken from axum::Json::into_response
-    fn into_response(self) -> Response {
-        let mut buf = BytesMut::with_capacity(128).writer();
-        match rmp_serde::encode::write(&mut buf, &self.into_inner()) {
-            Ok(()) => (
-                [(
-                    header::CONTENT_TYPE,
-                    HeaderValue::from_static(mime::APPLICATION_MSGPACK.as_ref()),
-                )],
-                buf.into_inner().freeze(),
-            )
-                .into_response(),
-            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+/// Result of handlers computing from the configuration, in a timezone.
+type ComputedResult<T> = Result<(Staleness, TypedHeader<Timezone>, Negotiated<T>), ApiError>;
+
+/// Marks responses computed from a stale configuration with a `Warning`
+/// header.
+struct Staleness(bool);
+
+impl IntoResponseParts for Staleness {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        if self.0 {
+            res.headers_mut().insert(
+                header::WARNING,
+                HeaderValue::from_static(r#"110 - "Response is Stale""#),
+            );
         }
+        Ok(res)
     }
 }
 
 #[derive(Clone)]
 pub(crate) struct AppState {
     pub(crate) health_channel: HealthChannel,
+    pub(crate) config_api_status_channel: StatusChannel,
     pub(crate) common_config_channel: CommonConfigChannel,
     pub(crate) partner_config_channel: PartnerConfigChannel,
+    pub(crate) partners_channel: PartnersChannel,
+    pub(crate) discovery_channel: DiscoveryChannel,
     pub(crate) timeline_channel: TimelineChannel,
     pub(crate) performance_channel: PerformanceChannel,
     pub(crate) shift_objective_channel: ShiftObjectiveChannel,
     pub(crate) week_objective_channel: WeekObjectiveChannel,
+    pub(crate) custom_channel: CustomChannel,
+    pub(crate) custom_endpoints: Arc<[Arc<Endpoint>]>,
+    /// Names of the InfluxDB data sources partner configurations can refer to.
+    pub(crate) data_sources: Arc<HashSet<String>>,
+    pub(crate) influxdb_circuit_breaker: CircuitBreaker,
+    /// Circuit breakers of the InfluxDB data sources, by name.
+    pub(crate) data_source_circuit_breakers: Arc<BTreeMap<String, CircuitBreaker>>,
+    pub(crate) config_api_circuit_breaker: CircuitBreaker,
+    /// Set for configuration providers caching configurations, the cache
+    /// routes being only served then.
+    pub(crate) config_cache_invalidator: Option<CacheInvalidator>,
+    pub(crate) config_cache_token: Option<CacheToken>,
+}
+
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct CircuitBreakerStates {
+    influxdb: BreakerState,
+    config_api: BreakerState,
+    data_sources: BTreeMap<String, BreakerState>,
+}
+
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct HealthResponse {
+    circuit_breakers: CircuitBreakerStates,
+}
+
+impl Body for HealthResponse {
+    const FORMATS: &'static [Format] = OBJECT_FORMATS;
+}
+
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum DependencyState {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct DependencyStatus {
+    state: DependencyState,
+    latency_ms: f64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct Dependencies {
+    influxdb: DependencyStatus,
+    config_api: DependencyStatus,
+    data_sources: BTreeMap<String, DependencyStatus>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ReadinessResponse {
+    dependencies: Dependencies,
+}
+
+impl Body for ReadinessResponse {
+    const FORMATS: &'static [Format] = OBJECT_FORMATS;
+}
+
+/// Configured partner whose configuration could not be obtained, and whose
+/// data is therefore not checked.
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+struct PartnerConfigError {
+    id: String,
+    detail: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct PartnersResponse {
+    partners: Vec<String>,
+    without_config: Vec<String>,
+    without_data: Vec<String>,
+    config_errors: Vec<PartnerConfigError>,
+}
+
+impl Body for PartnersResponse {
+    const FORMATS: &'static [Format] = OBJECT_FORMATS;
+}
+
+/// Maximum number of partner configurations fetched at once when listing
+/// partners, for their handler channel not to be filled up.
+const PARTNER_CONFIGS_CONCURRENCY: usize = 5;
+
+/// Path of custom endpoints in the OpenAPI description, replaced by the path
+/// of each configured endpoint.
+const CUSTOM_PATH: &str = "/{route}/{id}";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        description = "Computes production indicators from InfluxDB data.",
+        license(name = "MIT", identifier = "MIT"),
+    ),
+    paths(
+        health_handler,
+        live_handler,
+        ready_handler,
+        partners_handler,
+        timeline_handler,
+        performance_handler,
+        shift_objective_handler,
+        week_objective_handler,
+        custom_handler,
+        config_webhook_handler,
+        invalidate_partner_configs_handler,
+        invalidate_partner_config_handler,
+        openapi_handler,
+    ),
+    components(schemas(ErrorCode, Dependency), responses(ProblemDetails))
+)]
+struct ApiDoc;
+
+/// Path of the route applying configuration changes to caches, requiring the
+/// configuration cache token.
+const CONFIG_WEBHOOK_PATH: &str = "/config-webhook";
+
+/// Paths of the routes removing cache entries, requiring the configuration
+/// cache token.
+const CONFIG_CACHE_PATHS: [&str; 2] = ["/config-cache/partners", "/config-cache/partners/{id}"];
+
+/// Builds the OpenAPI description of the HTTP API, with a path per custom
+/// endpoint, and the configuration cache paths if served.
+fn openapi(state: &AppState) -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    if state.config_cache_invalidator.is_none() || state.config_cache_token.is_none() {
+        openapi.paths.paths.remove(CONFIG_WEBHOOK_PATH);
+        for path in CONFIG_CACHE_PATHS {
+            openapi.paths.paths.remove(path);
+        }
+    }
+    if let Some(custom_item) = openapi.paths.paths.remove(CUSTOM_PATH) {
+        for endpoint in state.custom_endpoints.iter() {
+            let mut item = custom_item.clone();
+            if let Some(operation) = &mut item.get {
+                operation.operation_id = Some(endpoint.route.clone());
+            }
+            openapi
+                .paths
+                .paths
+                .insert(format!("/{}/{{id}}", endpoint.route), item);
+        }
+    }
+    openapi
+}
+
+/// Builds the routes invalidating cached configurations, served only for
+/// configuration providers caching them, along with a configuration cache
+/// token, to clients sending it.
+fn config_cache_routes(state: &AppState) -> Router<AppState> {
+    let (Some(invalidator), Some(token)) = (
+        state.config_cache_invalidator.clone(),
+        state.config_cache_token.clone(),
+    ) else {
+        return Router::new();
+    };
+    let [partners_path, partner_path] = CONFIG_CACHE_PATHS;
+    Router::new()
+        .route(CONFIG_WEBHOOK_PATH, routing::post(config_webhook_handler))
+        .route(
+            partners_path,
+            routing::delete(invalidate_partner_configs_handler),
+        )
+        .route(
+            partner_path,
+            routing::delete(invalidate_partner_config_handler),
+        )
+        .route_layer(middleware::from_fn_with_state(token, cache_token::require))
+        .with_state(invalidator)
 }
 
 pub(crate) fn app(state: AppState) -> Router {
+    let openapi = Arc::new(openapi(&state));
+    let config_cache_routes = config_cache_routes(&state);
+    let custom_routes = state
+        .custom_endpoints
+        .iter()
+        .fold(Router::new(), |router, endpoint| {
+            router.route(
+                &format!("/{}/{{id}}", endpoint.route),
+                routing::get(custom_handler).layer(Extension(Arc::clone(endpoint))),
+            )
+        });
     Router::new()
+        .route(
+            "/openapi.json",
+            routing::get(openapi_handler).layer(Extension(openapi)),
+        )
         .route("/health", routing::get(health_handler))
+        .route("/live", routing::get(live_handler))
+        .route("/ready", routing::get(ready_handler))
+        .route("/partners", routing::get(partners_handler))
         .route("/timeline/{id}", routing::get(timeline_handler))
         .route("/performance/{id}", routing::get(performance_handler))
         .route(
@@ -63,130 +274,590 @@ pub(crate) fn app(state: AppState) -> Router {
             routing::get(shift_objective_handler),
         )
         .route("/week-objective/{id}", routing::get(week_objective_handler))
+        .merge(custom_routes)
+        .merge(config_cache_routes)
         .with_state(state)
 }
 
+/// Returns whether the client asked to bypass results caches.
+fn bypass_cache(cache_control: Option<TypedHeader<CacheControl>>) -> bool {
+    cache_control.is_some_and(|TypedHeader(cache_control)| cache_control.no_cache())
+}
+
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    operation_id = "openapi",
+    description = "Returns this OpenAPI description.",
+    responses((status = 200, description = "OpenAPI description", body = Object)),
+)]
+async fn openapi_handler(
+    Extension(openapi): Extension<Arc<utoipa::openapi::OpenApi>>,
+) -> Json<Arc<utoipa::openapi::OpenApi>> {
+    Json(openapi)
+}
+
+#[utoipa::path(
+    get,
+    path = "/health",
+    operation_id = "health",
+    description = "Returns the service health status.",
+    params(("Accept" = Option<String>, Header, description = "Accepted media types")),
+    responses(
+        (status = 200, description = "Service is healthy", content(
+            (HealthResponse = "application/json"),
+            (HealthResponse = "application/msgpack"),
+        )),
+        (status = 406, response = ProblemDetails),
+        (status = 500, description = "Service is unhealthy", content(
+            (HealthResponse = "application/json"),
+            (HealthResponse = "application/msgpack"),
+        )),
+    ),
+)]
 #[instrument(name = "health_api_handler", skip_all)]
-async fn health_handler(State(state): State<AppState>) -> Result<StatusCode, HandlerError> {
-    state.health_channel.roundtrip(()).await.map_err(|err| {
-        error!(kind = "health channel roundtrip", %err);
-        INTERNAL_ERROR
-    })
+async fn health_handler(
+    State(state): State<AppState>,
+    accept: Accept,
+) -> Result<(StatusCode, Negotiated<HealthResponse>), ApiError> {
+    let format = accept.negotiate::<HealthResponse>()?;
+    let status_code = state.health_channel.roundtrip(None).await.map_err(|err| {
+        ApiError::roundtrip(Dependency::InfluxDb, "health channel roundtrip", err)
+    })?;
+    let circuit_breakers = CircuitBreakerStates {
+        influxdb: state.influxdb_circuit_breaker.state(),
+        config_api: state.config_api_circuit_breaker.state(),
+        data_sources: state
+            .data_source_circuit_breakers
+            .iter()
+            .map(|(name, circuit_breaker)| (name.clone(), circuit_breaker.state()))
+            .collect(),
+    };
+    let body = HealthResponse { circuit_breakers };
+    Ok((status_code, Negotiated { format, body }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/live",
+    operation_id = "live",
+    description = "Returns whether the service process is running, without checking dependencies.",
+    responses((status = 204, description = "Service is live")),
+)]
+async fn live_handler() -> StatusCode {
+    StatusCode::NO_CONTENT
+}
+
+#[utoipa::path(
+    post,
+    path = "/config-webhook",
+    operation_id = "config_webhook",
+    description = "Invalidates cached configurations, for the configuration API to notify changes right away.",
+    params(("Authorization" = String, Header, description = "Configuration cache bearer token")),
+    request_body = ConfigChange,
+    responses(
+        (status = 204, description = "Cached configuration has been removed"),
+        (status = 401, response = ProblemDetails),
+        (status = 422, description = "Invalid configuration change object"),
+    ),
+)]
+#[instrument(name = "config_webhook_api_handler", skip_all)]
+async fn config_webhook_handler(
+    State(invalidator): State<CacheInvalidator>,
+    Json(change): Json<ConfigChange>,
+) -> StatusCode {
+    invalidator.apply(&change).await;
+    StatusCode::NO_CONTENT
+}
+
+#[utoipa::path(
+    delete,
+    path = "/config-cache/partners",
+    operation_id = "invalidate_partner_configs",
+    description = "Removes all partner configurations from cache.",
+    params(("Authorization" = String, Header, description = "Configuration cache bearer token")),
+    responses(
+        (status = 204, description = "Cache entries have been removed"),
+        (status = 401, response = ProblemDetails),
+    ),
+)]
+#[instrument(name = "invalidate_partner_configs_api_handler", skip_all)]
+async fn invalidate_partner_configs_handler(
+    State(invalidator): State<CacheInvalidator>,
+) -> StatusCode {
+    invalidator.invalidate_partner_configs();
+    StatusCode::NO_CONTENT
+}
+
+#[utoipa::path(
+    delete,
+    path = "/config-cache/partners/{id}",
+    operation_id = "invalidate_partner_config",
+    description = "Removes a partner configuration from cache.",
+    params(
+        ("id" = String, Path, description = "Partner ID"),
+        ("Authorization" = String, Header, description = "Configuration cache bearer token"),
+    ),
+    responses(
+        (status = 204, description = "Cache entry has been removed"),
+        (status = 401, response = ProblemDetails),
+    ),
+)]
+#[instrument(name = "invalidate_partner_config_api_handler", skip_all)]
+async fn invalidate_partner_config_handler(
+    State(invalidator): State<CacheInvalidator>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    invalidator.invalidate_partner_config(&id);
+    StatusCode::NO_CONTENT
+}
+
+/// Checks a dependency through its status channel, timing the roundtrip.
+async fn check_dependency<T>(
+    channel: &RoundtripSender<T, StatusCode>,
+    request: T,
+    kind: &'static str,
+) -> DependencyStatus {
+    let start = Instant::now();
+    let state = match channel.roundtrip(request).await {
+        Ok(status_code) if status_code.is_success() => DependencyState::Up,
+        Ok(status_code) => {
+            error!(kind, %status_code);
+            DependencyState::Down
+        }
+        Err(err) => {
+            error!(kind, %err);
+            DependencyState::Down
+        }
+    };
+    DependencyStatus {
+        state,
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/ready",
+    operation_id = "ready",
+    description = "Returns whether the service dependencies are available.",
+    params(("Accept" = Option<String>, Header, description = "Accepted media types")),
+    responses(
+        (status = 200, description = "All dependencies are up", content(
+            (ReadinessResponse = "application/json"),
+            (ReadinessResponse = "application/msgpack"),
+        )),
+        (status = 406, response = ProblemDetails),
+        (status = 503, description = "Some dependency is down", content(
+            (ReadinessResponse = "application/json"),
+            (ReadinessResponse = "application/msgpack"),
+        )),
+    ),
+)]
+#[instrument(name = "ready_api_handler", skip_all)]
+async fn ready_handler(
+    State(state): State<AppState>,
+    accept: Accept,
+) -> Result<(StatusCode, Negotiated<ReadinessResponse>), ApiError> {
+    let format = accept.negotiate::<ReadinessResponse>()?;
+    let (influxdb, config_api, data_sources) = tokio::join!(
+        check_dependency(&state.health_channel, None, "InfluxDB readiness"),
+        check_dependency(
+            &state.config_api_status_channel,
+            (),
+            "configuration API readiness"
+        ),
+        join_all(state.data_sources.iter().map(|name| {
+            let health_channel = &state.health_channel;
+            async move {
+                let data_source =
+                    check_dependency(health_channel, Some(name.clone()), "data source readiness")
+                        .instrument(info_span!("data_source", name))
+                        .await;
+                (name.clone(), data_source)
+            }
+        })),
+    );
+    let data_sources = data_sources.into_iter().collect::<BTreeMap<_, _>>();
+    let status_code = if [&influxdb, &config_api]
+        .into_iter()
+        .chain(data_sources.values())
+        .all(|dependency| dependency.state == DependencyState::Up)
+    {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let dependencies = Dependencies {
+        influxdb,
+        config_api,
+        data_sources,
+    };
+    let body = ReadinessResponse { dependencies };
+    Ok((status_code, Negotiated { format, body }))
 }
 
+#[utoipa::path(
+    get,
+    path = "/partners",
+    operation_id = "partners",
+    description = "Lists the configured partners, compared with the partners discovered in InfluxDB.",
+    params(("Accept" = Option<String>, Header, description = "Accepted media types")),
+    responses(
+        (status = 200, description = "Partners lists", content(
+            (PartnersResponse = "application/json"),
+            (PartnersResponse = "application/msgpack"),
+        )),
+        (status = 406, response = ProblemDetails),
+        (status = 500, response = ProblemDetails),
+        (status = 502, response = ProblemDetails),
+        (status = 503, response = ProblemDetails),
+        (status = 504, response = ProblemDetails),
+    ),
+)]
+#[instrument(name = "partners_api_handler", skip_all)]
+async fn partners_handler(
+    State(state): State<AppState>,
+    accept: Accept,
+) -> Result<Negotiated<PartnersResponse>, ApiError> {
+    let format = accept.negotiate::<PartnersResponse>()?;
+    let mut listed = ApiError::reply(
+        Dependency::ConfigApi,
+        "partners channel roundtrip",
+        state.partners_channel.roundtrip(()).await,
+    )?;
+    listed.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+    // Only the configurations not already known to the provider are fetched.
+    let data_sources = stream::iter(listed.clone())
+        .map(|partner| {
+            let state = &state;
+            async move {
+                match partner.data_source {
+                    Some(data_source) => {
+                        check_data_source(state, &partner.id, &data_source).map(|()| data_source)
+                    }
+                    None => partner_config(state, &partner.id)
+                        .await
+                        .map(|partner_config| partner_config.data_source),
+                }
+            }
+        })
+        .buffered(PARTNER_CONFIGS_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+    let partners = listed
+        .into_iter()
+        .map(|partner| partner.id)
+        .collect::<Vec<_>>();
+    let mut config_errors = Vec::new();
+    let mut checked = Vec::new();
+    for (id, data_source) in partners.iter().zip(data_sources) {
+        match data_source {
+            Ok(data_source) => checked.push((id, data_source)),
+            Err(err) => {
+                warn!(msg = "partner data not checked", id, detail = err.detail());
+                config_errors.push(PartnerConfigError {
+                    id: id.clone(),
+                    detail: err.detail().to_string(),
+                });
+            }
+        }
+    }
+    let distinct_data_sources = checked
+        .iter()
+        .map(|(_, data_source)| data_source.clone())
+        .chain([DataSource::default()])
+        .collect::<HashSet<_>>();
+    let discovered = try_join_all(distinct_data_sources.into_iter().map(|data_source| async {
+        let ids = ApiError::reply(
+            Dependency::InfluxDb,
+            "discovery channel roundtrip",
+            state.discovery_channel.roundtrip(data_source.clone()).await,
+        )?;
+        Ok::<_, ApiError>((data_source, ids.into_iter().collect::<BTreeSet<_>>()))
+    }))
+    .await?
+    .into_iter()
+    .collect::<HashMap<_, _>>();
+    let configured = partners.iter().cloned().collect::<BTreeSet<_>>();
+    let without_config = discovered
+        .values()
+        .flatten()
+        .filter(|id| !configured.contains(*id))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    let without_data = checked
+        .iter()
+        .filter(|(id, data_source)| !discovered[data_source].contains(*id))
+        .map(|(id, _)| id.to_string())
+        .collect();
+    let body = PartnersResponse {
+        partners,
+        without_config: without_config.into_iter().collect(),
+        without_data,
+        config_errors,
+    };
+    Ok(Negotiated { format, body })
+}
+
+#[utoipa::path(
+    get,
+    path = "/timeline/{id}",
+    operation_id = "timeline",
+    description = "Returns the timeline data, slots being `[start, color]` arrays in MessagePack.",
+    params(("id" = String, Path, description = "Partner ID"), ("Cache-Control" = Option<String>, Header, description = "`no-cache` bypasses the query results cache"), ("Accept" = Option<String>, Header, description = "Accepted media types")),
+    responses(
+        (status = 200, description = "Timeline data", content(
+            (Vec<(i64, Option<u8>)> = "application/msgpack"),
+            (Vec<TimelineSlot> = "application/json"),
+            (String = "text/csv"),
+        ), headers(
+            ("Warning" = String, description = "Set if computed from a stale configuration"),
+        )),
+        (status = 404, response = ProblemDetails),
+        (status = 406, response = ProblemDetails),
+        (status = 500, response = ProblemDetails),
+        (status = 502, response = ProblemDetails),
+        (status = 503, response = ProblemDetails),
+        (status = 504, response = ProblemDetails),
+    ),
+)]
 #[instrument(name = "timeline_api_handler", skip_all)]
 async fn timeline_handler(
     State(state): State<AppState>,
     Path(id): Path<String>,
-) -> Result<TimelineResponse, HandlerError> {
-    let config_request = PartnerConfigRequest { id: id.clone() };
+    cache_control: Option<TypedHeader<CacheControl>>,
+    accept: Accept,
+) -> Result<(Staleness, Negotiated<TimelineResponse>), ApiError> {
+    let format = accept.negotiate::<TimelineResponse>()?;
+    let partner_config = partner_config(&state, &id).await?;
     let PartnerConfig {
-        target_cycle_time, ..
-    } = state
-        .partner_config_channel
-        .roundtrip(config_request)
-        .await
-        .map_err(|err| {
-            error!(kind = "partner config channel roundtrip", %err);
-            INTERNAL_ERROR
-        })?;
+        target_cycle_time,
+        data_source,
+        stale,
+        ..
+    } = partner_config;
     let timeline_request = TimelineRequest {
         id,
         target_cycle_time,
+        data_source,
+        bypass_cache: bypass_cache(cache_control),
     };
-    state
-        .timeline_channel
-        .roundtrip(timeline_request)
+    let body = ApiError::reply(
+        Dependency::InfluxDb,
+        "timeline channel roundtrip",
+        state
+            .timeline_channel
+            .coalesced_roundtrip(timeline_request)
+            .await,
+    )?;
+    Ok((Staleness(stale), Negotiated { format, body }))
+}
+
+/// Gets a partner configuration, telling apart unknown partners, and
+/// rejecting references to undeclared data sources.
+async fn partner_config(state: &AppState, id: &str) -> Result<PartnerConfig, ApiError> {
+    let config_request = PartnerConfigRequest { id: id.to_string() };
+    let partner_config = match state
+        .partner_config_channel
+        .coalesced_roundtrip(config_request)
         .await
-        .map_err(|err| {
-            error!(kind = "timeline channel roundtrip", %err);
-            INTERNAL_ERROR
-        })
+    {
+        Ok(Err(Failure::NotFound)) => return Err(ApiError::unknown_partner(id)),
+        Ok(Err(Failure::Invalid(reason))) => {
+            return Err(ApiError::invalid_config(
+                &format!("configuration for partner `{id}`"),
+                reason,
+            ));
+        }
+        reply => ApiError::reply(
+            Dependency::ConfigApi,
+            "partner config channel roundtrip",
+            reply,
+        )?,
+    };
+    check_data_source(state, id, &partner_config.data_source)?;
+    Ok(partner_config)
 }
 
-#[instrument(name = "performance_api_handler", skip_all)]
-async fn performance_handler(
-    State(state): State<AppState>,
-    Path(id): Path<String>,
-    TypedHeader(client_timezone): TypedHeader<ClientTimezone>,
-) -> Result<Json<f32>, HandlerError> {
-    let CommonConfig {
-        shift_start_times,
-        pauses,
-        ..
-    } = state
+/// Rejects partner data sources referring to undeclared named data sources.
+fn check_data_source(state: &AppState, id: &str, data_source: &DataSource) -> Result<(), ApiError> {
+    if let Some(name) = &data_source.name
+        && !state.data_sources.contains(name)
+    {
+        return Err(ApiError::invalid_config(
+            &format!("configuration for partner `{id}`"),
+            "unknown data source",
+        ));
+    }
+    Ok(())
+}
+
+/// Gets the common and partner configurations, checking them against each
+/// other, along with the timezone to compute in: the client one if given, else
+/// the partner one, else the site one.
+async fn validated_configs(
+    state: &AppState,
+    id: &str,
+    client_timezone: Option<TypedHeader<ClientTimezone>>,
+) -> Result<(CommonConfig, PartnerConfig, Tz), ApiError> {
+    let partner_config = partner_config(state, id).await?;
+
+    let config_request = CommonConfigRequest {
+        site: partner_config.site.clone(),
+    };
+    let common_config = match state
         .common_config_channel
-        .roundtrip(())
+        .coalesced_roundtrip(config_request)
         .await
-        .map_err(|err| {
-            error!(kind = "common config channel roundtrip", %err);
-            INTERNAL_ERROR
+    {
+        Ok(Err(Failure::Invalid(reason))) => {
+            return Err(ApiError::invalid_config("common configuration", reason));
+        }
+        reply => ApiError::reply(
+            Dependency::ConfigApi,
+            "common config channel roundtrip",
+            reply,
+        )?,
+    };
+    partner_config
+        .validate_with(&common_config)
+        .map_err(|reason| {
+            ApiError::invalid_config(&format!("configuration for partner `{id}`"), reason)
         })?;
 
-    let config_request = PartnerConfigRequest { id: id.clone() };
-    let PartnerConfig {
-        target_cycle_time, ..
-    } = state
-        .partner_config_channel
-        .roundtrip(config_request)
-        .await
-        .map_err(|err| {
-            error!(kind = "partner config channel roundtrip", %err);
-            INTERNAL_ERROR
-        })?;
+    let timezone = client_timezone
+        .map(|TypedHeader(client_timezone)| client_timezone.into_inner())
+        .or(partner_config.timezone)
+        .or(common_config.timezone)
+        .ok_or_else(ApiError::missing_timezone)?;
+
+    Ok((common_config, partner_config, timezone))
+}
+
+#[utoipa::path(
+    get,
+    path = "/performance/{id}",
+    operation_id = "performance",
+    description = "Returns the performance ratio of the current shift.",
+    params(("id" = String, Path, description = "Partner ID"), ("Client-Timezone" = Option<String>, Header, description = "Client timezone, overriding the configured one"), ("Cache-Control" = Option<String>, Header, description = "`no-cache` bypasses the query results cache"), ("Accept" = Option<String>, Header, description = "Accepted media types")),
+    responses(
+        (status = 200, description = "Performance ratio", content(
+            (f32 = "application/json"),
+            (f32 = "application/msgpack"),
+            (String = "text/csv"),
+        ), headers(
+            ("Timezone" = String, description = "Timezone computed in"),
+            ("Warning" = String, description = "Set if computed from a stale configuration"),
+        )),
+        (status = 400, response = ProblemDetails),
+        (status = 404, response = ProblemDetails),
+        (status = 406, response = ProblemDetails),
+        (status = 500, response = ProblemDetails),
+        (status = 502, response = ProblemDetails),
+        (status = 503, response = ProblemDetails),
+        (status = 504, response = ProblemDetails),
+    ),
+)]
+#[instrument(name = "performance_api_handler", skip_all)]
+async fn performance_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    client_timezone: Option<TypedHeader<ClientTimezone>>,
+    cache_control: Option<TypedHeader<CacheControl>>,
+    accept: Accept,
+) -> ComputedResult<f32> {
+    let format = accept.negotiate::<f32>()?;
+    let (
+        CommonConfig {
+            shift_start_times,
+            pauses,
+            stale: common_stale,
+            ..
+        },
+        PartnerConfig {
+            target_cycle_time,
+            data_source,
+            stale: partner_stale,
+            ..
+        },
+        timezone,
+    ) = validated_configs(&state, &id, client_timezone).await?;
     let performance_request = PerformanceRequest {
         id,
         shift_start_times,
         pauses,
-        timezone: client_timezone.into_inner(),
+        timezone,
         target_cycle_time,
+        data_source,
+        bypass_cache: bypass_cache(cache_control),
     };
-    state
-        .performance_channel
-        .roundtrip(performance_request)
-        .await
-        .map(Json)
-        .map_err(|err| {
-            error!(kind = "performance channel roundtrip", %err);
-            INTERNAL_ERROR
-        })
+    let body = ApiError::reply(
+        Dependency::InfluxDb,
+        "performance channel roundtrip",
+        state
+            .performance_channel
+            .coalesced_roundtrip(performance_request)
+            .await,
+    )?;
+    Ok((
+        Staleness(common_stale || partner_stale),
+        TypedHeader(Timezone(timezone)),
+        Negotiated { format, body },
+    ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/shift-objective/{id}",
+    operation_id = "shift_objective",
+    description = "Returns data to draw the production objective graphics for the current shift.",
+    params(("id" = String, Path, description = "Partner ID"), ("Client-Timezone" = Option<String>, Header, description = "Client timezone, overriding the configured one"), ("Accept" = Option<String>, Header, description = "Accepted media types")),
+    responses(
+        (status = 200, description = "Graphics data", content(
+            (Vec<ObjectivePoint> = "application/json"),
+            (Vec<ObjectivePoint> = "application/msgpack"),
+            (String = "text/csv"),
+        ), headers(
+            ("Timezone" = String, description = "Timezone computed in"),
+            ("Warning" = String, description = "Set if computed from a stale configuration"),
+        )),
+        (status = 400, response = ProblemDetails),
+        (status = 404, response = ProblemDetails),
+        (status = 406, response = ProblemDetails),
+        (status = 500, response = ProblemDetails),
+        (status = 502, response = ProblemDetails),
+        (status = 503, response = ProblemDetails),
+        (status = 504, response = ProblemDetails),
+    ),
+)]
 #[instrument(name = "shift_objective_api_handler", skip_all)]
 async fn shift_objective_handler(
     State(state): State<AppState>,
     Path(id): Path<String>,
-    TypedHeader(client_timezone): TypedHeader<ClientTimezone>,
-) -> Result<Json<ObjectiveData>, HandlerError> {
-    let CommonConfig {
-        shift_start_times,
-        pauses,
-        ..
-    } = state
-        .common_config_channel
-        .roundtrip(())
-        .await
-        .map_err(|err| {
-            error!(kind = "common config channel roundtrip", %err);
-            INTERNAL_ERROR
-        })?;
-    let partner_config_request = PartnerConfigRequest { id };
-    let PartnerConfig {
-        target_cycle_time,
-        target_efficiency,
-        ..
-    } = state
-        .partner_config_channel
-        .roundtrip(partner_config_request)
-        .await
-        .map_err(|err| {
-            error!(kind = "partner config channel roundtrip", %err);
-            INTERNAL_ERROR
-        })?;
+    client_timezone: Option<TypedHeader<ClientTimezone>>,
+    accept: Accept,
+) -> ComputedResult<ObjectiveData> {
+    let format = accept.negotiate::<ObjectiveData>()?;
+    let (
+        CommonConfig {
+            shift_start_times,
+            pauses,
+            stale: common_stale,
+            ..
+        },
+        PartnerConfig {
+            target_cycle_time,
+            target_efficiency,
+            stale: partner_stale,
+            ..
+        },
+        timezone,
+    ) = validated_configs(&state, &id, client_timezone).await?;
     let objective_request = ShiftObjectiveRequest {
         shift_start_times,
         pauses,
-        timezone: client_timezone.into_inner(),
+        timezone,
         target_cycle_time,
         target_efficiency,
     };
@@ -195,49 +866,76 @@ async fn shift_objective_handler(
         .roundtrip(objective_request)
         .await
         .map_err(|err| {
-            error!(kind = "shift objective channel roundtrip", %err);
-            INTERNAL_ERROR
+            ApiError::roundtrip(
+                Dependency::Channel,
+                "shift objective channel roundtrip",
+                err,
+            )
+        })
+        .map(|body| {
+            (
+                Staleness(common_stale || partner_stale),
+                TypedHeader(Timezone(timezone)),
+                Negotiated { format, body },
+            )
         })
-        .map(Json)
 }
 
+#[utoipa::path(
+    get,
+    path = "/week-objective/{id}",
+    operation_id = "week_objective",
+    description = "Returns data to draw the production objective graphics for the current week.",
+    params(("id" = String, Path, description = "Partner ID"), ("Client-Timezone" = Option<String>, Header, description = "Client timezone, overriding the configured one"), ("Accept" = Option<String>, Header, description = "Accepted media types")),
+    responses(
+        (status = 200, description = "Graphics data", content(
+            (Vec<ObjectivePoint> = "application/json"),
+            (Vec<ObjectivePoint> = "application/msgpack"),
+            (String = "text/csv"),
+        ), headers(
+            ("Timezone" = String, description = "Timezone computed in"),
+            ("Warning" = String, description = "Set if computed from a stale configuration"),
+        )),
+        (status = 400, response = ProblemDetails),
+        (status = 404, response = ProblemDetails),
+        (status = 406, response = ProblemDetails),
+        (status = 500, response = ProblemDetails),
+        (status = 502, response = ProblemDetails),
+        (status = 503, response = ProblemDetails),
+        (status = 504, response = ProblemDetails),
+    ),
+)]
 #[instrument(name = "week_objective_api_handler", skip_all)]
 async fn week_objective_handler(
     State(state): State<AppState>,
     Path(id): Path<String>,
-    TypedHeader(client_timezone): TypedHeader<ClientTimezone>,
-) -> Result<Json<ObjectiveData>, HandlerError> {
-    let CommonConfig {
-        shift_start_times,
-        pauses,
-        week_start,
-    } = state
-        .common_config_channel
-        .roundtrip(())
-        .await
-        .map_err(|err| {
-            error!(kind = "common config channel roundtrip", %err);
-            INTERNAL_ERROR
-        })?;
-    let partner_config_request = PartnerConfigRequest { id };
-    let PartnerConfig {
-        target_cycle_time,
-        target_efficiency,
-        shift_engaged,
-    } = state
-        .partner_config_channel
-        .roundtrip(partner_config_request)
-        .await
-        .map_err(|err| {
-            error!(kind = "partner config channel roundtrip", %err);
-            INTERNAL_ERROR
-        })?;
+    client_timezone: Option<TypedHeader<ClientTimezone>>,
+    accept: Accept,
+) -> ComputedResult<ObjectiveData> {
+    let format = accept.negotiate::<ObjectiveData>()?;
+    let (
+        CommonConfig {
+            shift_start_times,
+            pauses,
+            week_start,
+            stale: common_stale,
+            ..
+        },
+        PartnerConfig {
+            target_cycle_time,
+            target_efficiency,
+            shift_engaged,
+            stale: partner_stale,
+            ..
+        },
+        timezone,
+    ) = validated_configs(&state, &id, client_timezone).await?;
     let objective_request = WeekObjectiveRequest {
         shift_start_times,
         shift_engaged,
         pauses,
         week_start,
-        timezone: client_timezone.into_inner(),
+        timezone,
         target_cycle_time,
         target_efficiency,
     };
@@ -246,26 +944,129 @@ async fn week_objective_handler(
         .roundtrip(objective_request)
         .await
         .map_err(|err| {
-            error!(kind = "shift objective channel roundtrip", %err);
-            INTERNAL_ERROR
+            ApiError::roundtrip(Dependency::Channel, "week objective channel roundtrip", err)
+        })
+        .map(|body| {
+            (
+                Staleness(common_stale || partner_stale),
+                TypedHeader(Timezone(timezone)),
+                Negotiated { format, body },
+            )
         })
-        .map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = CUSTOM_PATH,
+    description = "Returns the value computed by a custom endpoint, `null` if there is none.",
+    params(("id" = String, Path, description = "Partner ID"), ("Client-Timezone" = Option<String>, Header, description = "Client timezone, overriding the configured one"), ("Accept" = Option<String>, Header, description = "Accepted media types")),
+    responses(
+        (status = 200, description = "Computed value", content(
+            (Option<f64> = "application/json"),
+            (Option<f64> = "application/msgpack"),
+            (String = "text/csv"),
+        ), headers(
+            ("Timezone" = String, description = "Timezone computed in"),
+            ("Warning" = String, description = "Set if computed from a stale configuration"),
+        )),
+        (status = 400, response = ProblemDetails),
+        (status = 404, response = ProblemDetails),
+        (status = 406, response = ProblemDetails),
+        (status = 500, response = ProblemDetails),
+        (status = 502, response = ProblemDetails),
+        (status = 503, response = ProblemDetails),
+        (status = 504, response = ProblemDetails),
+    ),
+)]
+#[instrument(name = "custom_api_handler", skip_all, fields(route = endpoint.route))]
+async fn custom_handler(
+    State(state): State<AppState>,
+    Extension(endpoint): Extension<Arc<Endpoint>>,
+    Path(id): Path<String>,
+    client_timezone: Option<TypedHeader<ClientTimezone>>,
+    accept: Accept,
+) -> ComputedResult<Option<f64>> {
+    let format = accept.negotiate::<Option<f64>>()?;
+    let (
+        CommonConfig {
+            shift_start_times,
+            stale: common_stale,
+            ..
+        },
+        PartnerConfig {
+            data_source,
+            stale: partner_stale,
+            ..
+        },
+        timezone,
+    ) = validated_configs(&state, &id, client_timezone).await?;
+    let custom_request = CustomRequest {
+        id,
+        query: Arc::clone(&endpoint.query),
+        reducer: endpoint.reducer.clone(),
+        shift_start_times,
+        timezone,
+        data_source,
+    };
+    let body = ApiError::reply(
+        Dependency::InfluxDb,
+        "custom channel roundtrip",
+        state
+            .custom_channel
+            .coalesced_roundtrip(custom_request)
+            .await,
+    )?;
+    Ok((
+        Staleness(common_stale || partner_stale),
+        TypedHeader(Timezone(timezone)),
+        Negotiated { format, body },
+    ))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fmt::Debug;
+    use std::time::Duration;
+
     use axum::body::{Body, to_bytes};
     use axum::http::Request;
+    use axum::response::Response;
     use chrono::Weekday;
     use tower::ServiceExt;
 
-    use crate::channel::{RoundtripSender, roundtrip_channel};
-    use crate::config_api::WeekStart;
+    use crate::channel::roundtrip_channel;
+    use crate::config_api::{DataSource, ListedPartner, WeekStart};
     use crate::production_objective::ObjectivePoint;
 
     use super::*;
 
-    fn successful_common_config_tx() -> RoundtripSender<(), CommonConfig> {
+    /// Returns a state whose channels are closed, and whose circuit breakers
+    /// and cache invalidator are disabled, for tests to override the fields
+    /// they use.
+    fn closed_state() -> AppState {
+        AppState {
+            health_channel: roundtrip_channel(1).0,
+            config_api_status_channel: roundtrip_channel(1).0,
+            common_config_channel: roundtrip_channel(1).0,
+            partner_config_channel: roundtrip_channel(1).0,
+            partners_channel: roundtrip_channel(1).0,
+            discovery_channel: roundtrip_channel(1).0,
+            timeline_channel: roundtrip_channel(1).0,
+            performance_channel: roundtrip_channel(1).0,
+            shift_objective_channel: roundtrip_channel(1).0,
+            week_objective_channel: roundtrip_channel(1).0,
+            custom_channel: roundtrip_channel(1).0,
+            custom_endpoints: Arc::new([]),
+            data_sources: Default::default(),
+            influxdb_circuit_breaker: CircuitBreaker::disabled(),
+            data_source_circuit_breakers: Default::default(),
+            config_api_circuit_breaker: CircuitBreaker::disabled(),
+            config_cache_invalidator: None,
+            config_cache_token: None,
+        }
+    }
+
+    fn successful_common_config_tx() -> CommonConfigChannel {
         let (tx, mut rx) = roundtrip_channel(1);
         tokio::spawn(async move {
             let (_, _, reply_tx) = rx.recv().await.expect("channel has been closed");
@@ -276,22 +1077,51 @@ mod tests {
                     day: Weekday::Mon,
                     shift_index: 0,
                 },
+                timezone: None,
+                stale: false,
             };
-            reply_tx.send(config).expect("error sending response");
+            reply_tx.send(Ok(config)).expect("error sending response");
+        });
+        tx
+    }
+
+    /// Returns a channel whose handler replies with an upstream failure.
+    fn failing_tx<S: Send + 'static, R: Debug + Send + 'static>(
+        failure: Failure,
+    ) -> RoundtripSender<S, Result<R, Failure>> {
+        let (tx, mut rx) = roundtrip_channel(1);
+        tokio::spawn(async move {
+            let (_, _, reply_tx) = rx.recv().await.expect("channel has been closed");
+            reply_tx.send(Err(failure)).expect("error sending response");
         });
         tx
     }
 
-    fn successful_partner_config_tx() -> RoundtripSender<PartnerConfigRequest, PartnerConfig> {
+    /// Checks the response is problem details and returns them.
+    async fn problem_details(res: Response) -> serde_json::Value {
+        assert_eq!(res.headers()["Content-Type"], "application/problem+json");
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn successful_partner_config_tx() -> PartnerConfigChannel {
+        partner_config_tx(false)
+    }
+
+    fn partner_config_tx(stale: bool) -> PartnerConfigChannel {
         let (tx, mut rx) = roundtrip_channel(1);
         tokio::spawn(async move {
             let (_, _, reply_tx) = rx.recv().await.expect("channel has been closed");
             let config = PartnerConfig {
-                target_cycle_time: Default::default(),
-                target_efficiency: Default::default(),
+                target_cycle_time: 1.0,
+                target_efficiency: 1.0,
                 shift_engaged: Default::default(),
+                site: None,
+                timezone: None,
+                data_source: Default::default(),
+                stale,
             };
-            reply_tx.send(config).expect("error sending response");
+            reply_tx.send(Ok(config)).expect("error sending response");
         });
         tx
     }
@@ -302,20 +1132,9 @@ mod tests {
         use super::*;
 
         fn testing_fixture(health_channel: HealthChannel) -> (Router, Request<Body>) {
-            let (common_config_channel, _) = roundtrip_channel(1);
-            let (partner_config_channel, _) = roundtrip_channel(1);
-            let (timeline_channel, _) = roundtrip_channel(1);
-            let (performance_channel, _) = roundtrip_channel(1);
-            let (shift_objective_channel, _) = roundtrip_channel(1);
-            let (week_objective_channel, _) = roundtrip_channel(1);
             let app = app(AppState {
                 health_channel,
-                common_config_channel,
-                partner_config_channel,
-                timeline_channel,
-                performance_channel,
-                shift_objective_channel,
-                week_objective_channel,
+                ..closed_state()
             });
             let req = Request::builder()
                 .uri("/health")
@@ -358,13 +1177,572 @@ mod tests {
             let (app, req) = testing_fixture(tx);
             let res = app.oneshot(req).await.unwrap();
             assert_eq!(res.status(), StatusCode::OK);
+            let body = to_bytes(res.into_body(), 1024).await.unwrap();
+            assert_eq!(
+                body,
+                r#"{"circuitBreakers":{"influxdb":"closed","configApi":"closed","dataSources":{}}}"#
+            );
+        }
+
+        #[tokio::test]
+        async fn data_source_circuit_breakers() {
+            let (tx, mut rx) = roundtrip_channel(1);
+            tokio::spawn(async move {
+                let (_, _, reply_tx) = rx.recv().await.expect("channel has been closed");
+                reply_tx
+                    .send(StatusCode::OK)
+                    .expect("error sending response");
+            });
+            let app = app(AppState {
+                health_channel: tx,
+                data_source_circuit_breakers: Arc::new(BTreeMap::from([(
+                    "plant2".to_string(),
+                    CircuitBreaker::disabled(),
+                )])),
+                ..closed_state()
+            });
+            let req = Request::builder()
+                .uri("/health")
+                .body(Body::empty())
+                .unwrap();
+            let res = app.oneshot(req).await.unwrap();
+            let body = to_bytes(res.into_body(), 1024).await.unwrap();
+            let health = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+            assert_eq!(health["circuitBreakers"]["dataSources"]["plant2"], "closed");
+        }
+
+        #[tokio::test]
+        async fn not_acceptable() {
+            let (tx, _) = roundtrip_channel(1);
+            let (app, mut req) = testing_fixture(tx);
+            req.headers_mut()
+                .insert(header::ACCEPT, HeaderValue::from_static("text/csv"));
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+            let problem = problem_details(res).await;
+            assert_eq!(problem["code"], "not-acceptable");
+            assert_eq!(
+                problem["detail"],
+                "no acceptable format, offered: application/json, application/msgpack"
+            );
+        }
+    }
+
+    fn status_tx<T: Send + 'static>(status_code: StatusCode) -> RoundtripSender<T, StatusCode> {
+        let (tx, mut rx) = roundtrip_channel(1);
+        tokio::spawn(async move {
+            let (_, _, reply_tx) = rx.recv().await.expect("channel has been closed");
+            reply_tx.send(status_code).expect("error sending response");
+        });
+        tx
+    }
+
+    fn probes_fixture(
+        health_channel: HealthChannel,
+        config_api_status_channel: StatusChannel,
+    ) -> Router {
+        app(AppState {
+            health_channel,
+            config_api_status_channel,
+            ..closed_state()
+        })
+    }
+
+    mod partners_handler {
+        use super::*;
+
+        fn listed_tx(partners: Vec<ListedPartner>) -> PartnersChannel {
+            let (tx, mut rx) = roundtrip_channel(1);
+            tokio::spawn(async move {
+                let (_, _, reply_tx) = rx.recv().await.expect("channel has been closed");
+                reply_tx.send(Ok(partners)).expect("error sending response");
+            });
+            tx
+        }
+
+        /// Returns a channel listing partners whose configuration is not
+        /// cached.
+        fn ids_tx(ids: &'static [&'static str]) -> PartnersChannel {
+            let partners = ids
+                .iter()
+                .map(|id| ListedPartner {
+                    id: id.to_string(),
+                    data_source: None,
+                })
+                .collect();
+            listed_tx(partners)
+        }
+
+        /// Returns a channel replying with the configuration of known
+        /// partners, whose data source bucket is given if overridden.
+        fn partner_configs_tx(
+            partners: &'static [(&'static str, Option<&'static str>)],
+        ) -> PartnerConfigChannel {
+            let (tx, mut rx) = roundtrip_channel(10);
+            tokio::spawn(async move {
+                while let Some((request, _, reply_tx)) = rx.recv().await {
+                    let request: PartnerConfigRequest = request;
+                    let reply = partners
+                        .iter()
+                        .find(|(id, _)| *id == request.id)
+                        .map(|(_, bucket)| PartnerConfig {
+                            target_cycle_time: 1.0,
+                            target_efficiency: 0.5,
+                            shift_engaged: Vec::new(),
+                            site: None,
+                            timezone: None,
+                            data_source: DataSource {
+                                bucket: bucket.map(|bucket| bucket.parse().unwrap()),
+                                ..Default::default()
+                            },
+                            stale: false,
+                        })
+                        .ok_or(Failure::NotFound);
+                    reply_tx.send(reply).expect("error sending response");
+                }
+            });
+            tx
+        }
+
+        /// Returns a channel replying with the partners discovered in each
+        /// bucket, the default one being `None`.
+        fn discovery_tx(
+            discovered: &'static [(Option<&'static str>, &'static [&'static str])],
+        ) -> DiscoveryChannel {
+            let (tx, mut rx) = roundtrip_channel(10);
+            tokio::spawn(async move {
+                while let Some((data_source, _, reply_tx)) = rx.recv().await {
+                    let data_source: DataSource = data_source;
+                    let (_, ids) = discovered
+                        .iter()
+                        .find(|(bucket, _)| {
+                            data_source.bucket.as_ref().map(AsRef::as_ref) == *bucket
+                        })
+                        .expect("unexpected data source");
+                    let ids = ids.iter().map(|id| id.to_string()).collect();
+                    reply_tx.send(Ok(ids)).expect("error sending response");
+                }
+            });
+            tx
+        }
+
+        fn testing_fixture(
+            partners_channel: PartnersChannel,
+            partner_config_channel: PartnerConfigChannel,
+            discovery_channel: DiscoveryChannel,
+        ) -> (Router, Request<Body>) {
+            let app = app(AppState {
+                partners_channel,
+                partner_config_channel,
+                discovery_channel,
+                ..closed_state()
+            });
+            let req = Request::builder()
+                .uri("/partners")
+                .body(Body::empty())
+                .unwrap();
+            (app, req)
+        }
+
+        #[tokio::test]
+        async fn partners_roundtrip_error() {
+            let (partners_tx, _) = roundtrip_channel(1);
+            let (app, req) = testing_fixture(
+                partners_tx,
+                partner_configs_tx(&[]),
+                discovery_tx(&[(None, &[])]),
+            );
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+
+        #[tokio::test]
+        async fn discovery_roundtrip_error() {
+            let (discovery_tx, _) = roundtrip_channel(1);
+            let (app, req) = testing_fixture(ids_tx(&[]), partner_configs_tx(&[]), discovery_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+
+        #[tokio::test]
+        async fn success() {
+            let partners_tx = ids_tx(&["id3", "id1", "id2"]);
+            let partner_configs_tx =
+                partner_configs_tx(&[("id1", None), ("id2", None), ("id3", None)]);
+            let discovery_tx = discovery_tx(&[(None, &["id4", "id2", "id1"])]);
+            let (app, req) = testing_fixture(partners_tx, partner_configs_tx, discovery_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(
+                body,
+                r#"{"partners":["id1","id2","id3"],"withoutConfig":["id4"],"withoutData":["id3"],"configErrors":[]}"#
+            );
+        }
+
+        #[tokio::test]
+        async fn data_source_overrides() {
+            let partners_tx = ids_tx(&["id1", "id2", "id3", "id4"]);
+            let partner_configs_tx = partner_configs_tx(&[
+                ("id1", None),
+                ("id2", Some("otherbucket")),
+                ("id4", Some("otherbucket")),
+            ]);
+            let discovery_tx = discovery_tx(&[
+                (None, &["id1", "id2", "id5"]),
+                (Some("otherbucket"), &["id2", "id6"]),
+            ]);
+            let (app, req) = testing_fixture(partners_tx, partner_configs_tx, discovery_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(
+                body,
+                r#"{"partners":["id1","id2","id3","id4"],"withoutConfig":["id5","id6"],"withoutData":["id4"],"configErrors":[{"id":"id3","detail":"unknown partner `id3`"}]}"#
+            );
+        }
+
+        #[tokio::test]
+        async fn cached_data_sources() {
+            let partners_tx = listed_tx(vec![
+                ListedPartner {
+                    id: "id2".to_string(),
+                    data_source: Some(DataSource {
+                        bucket: Some("otherbucket".parse().unwrap()),
+                        ..Default::default()
+                    }),
+                },
+                ListedPartner {
+                    id: "id1".to_string(),
+                    data_source: Some(Default::default()),
+                },
+                ListedPartner {
+                    id: "id3".to_string(),
+                    data_source: Some(DataSource {
+                        name: Some("undeclared".to_string()),
+                        ..Default::default()
+                    }),
+                },
+            ]);
+            // Cached configurations are not fetched again.
+            let (partner_configs_tx, _) = roundtrip_channel(1);
+            let discovery_tx = discovery_tx(&[(None, &["id1"]), (Some("otherbucket"), &["id4"])]);
+            let (app, req) = testing_fixture(partners_tx, partner_configs_tx, discovery_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(
+                body,
+                r#"{"partners":["id1","id2","id3"],"withoutConfig":["id4"],"withoutData":["id2"],"configErrors":[{"id":"id3","detail":"invalid configuration for partner `id3`: unknown data source"}]}"#
+            );
+        }
+    }
+
+    mod live_handler {
+        use super::*;
+
+        #[tokio::test]
+        async fn live() {
+            let (health_tx, _) = roundtrip_channel(1);
+            let (status_tx, _) = roundtrip_channel(1);
+            let app = probes_fixture(health_tx, status_tx);
+            let req = Request::builder().uri("/live").body(Body::empty()).unwrap();
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        }
+    }
+
+    mod openapi_handler {
+        use super::*;
+
+        async fn get_json(app: Router, uri: &str) -> serde_json::Value {
+            let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["Content-Type"], "application/json");
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        #[tokio::test]
+        async fn paths() {
+            let (health_tx, _) = roundtrip_channel(1);
+            let (status_tx, _) = roundtrip_channel(1);
+            let app = probes_fixture(health_tx, status_tx);
+            let openapi = get_json(app, "/openapi.json").await;
+            assert_eq!(openapi["openapi"], "3.1.0");
+            let paths = openapi["paths"].as_object().unwrap();
+            assert!(paths["/timeline/{id}"]["get"].is_object());
+            assert!(!paths.contains_key("/config-webhook"));
+            assert!(!paths.contains_key(CUSTOM_PATH));
+            let problem = &openapi["components"]["responses"]["ProblemDetails"];
+            assert!(problem["content"]["application/problem+json"].is_object());
+        }
+
+        #[tokio::test]
+        async fn health_response_schema() {
+            let health_tx = status_tx(StatusCode::OK);
+            let (status_tx, _) = roundtrip_channel(1);
+            let app = probes_fixture(health_tx, status_tx);
+            let openapi = get_json(app.clone(), "/openapi.json").await;
+            let health = get_json(app, "/health").await;
+            let schemas = &openapi["components"]["schemas"];
+            for (schema, value) in [
+                ("HealthResponse", &health),
+                ("CircuitBreakerStates", &health["circuitBreakers"]),
+            ] {
+                let properties = schemas[schema]["properties"].as_object().unwrap();
+                let keys = value.as_object().unwrap().keys();
+                assert!(keys.eq(properties.keys()), "{schema} keys differ");
+            }
+            let states = schemas["BreakerState"]["enum"].as_array().unwrap();
+            assert!(states.contains(&health["circuitBreakers"]["influxdb"]));
+        }
+    }
+
+    mod invalidate_handlers {
+        use super::*;
+
+        fn testing_app() -> Router {
+            app(AppState {
+                config_cache_invalidator: Some(CacheInvalidator::disabled()),
+                config_cache_token: Some(CacheToken::fixed("sometoken")),
+                ..closed_state()
+            })
+        }
+
+        fn delete_request(uri: &str, token: Option<&str>) -> Request<Body> {
+            let mut req = Request::builder().method("DELETE").uri(uri);
+            if let Some(token) = token {
+                req = req.header("Authorization", format!("Bearer {token}"));
+            }
+            req.body(Body::empty()).unwrap()
+        }
+
+        async fn delete(uri: &str, token: Option<&str>) -> StatusCode {
+            let req = delete_request(uri, token);
+            testing_app().oneshot(req).await.unwrap().status()
+        }
+
+        fn webhook_request(body: &'static str, token: Option<&str>) -> Request<Body> {
+            let mut req = Request::builder()
+                .method("POST")
+                .uri("/config-webhook")
+                .header("Content-Type", "application/json");
+            if let Some(token) = token {
+                req = req.header("Authorization", format!("Bearer {token}"));
+            }
+            req.body(Body::from(body)).unwrap()
+        }
+
+        async fn webhook(body: &'static str) -> StatusCode {
+            let req = webhook_request(body, Some("sometoken"));
+            testing_app().oneshot(req).await.unwrap().status()
+        }
+
+        #[tokio::test]
+        async fn webhook_common() {
+            assert_eq!(
+                webhook(r#"{"kind":"common"}"#).await,
+                StatusCode::NO_CONTENT
+            );
+        }
+
+        #[tokio::test]
+        async fn webhook_partner() {
+            assert_eq!(
+                webhook(r#"{"kind":"partner","id":"someid"}"#).await,
+                StatusCode::NO_CONTENT
+            );
+        }
+
+        #[tokio::test]
+        async fn webhook_invalid() {
+            assert_eq!(
+                webhook(r#"{"kind":"partner"}"#).await,
+                StatusCode::UNPROCESSABLE_ENTITY
+            );
+        }
+
+        #[tokio::test]
+        async fn webhook_missing_token() {
+            let req = webhook_request(r#"{"kind":"common"}"#, None);
+            let res = testing_app().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(res.headers()["WWW-Authenticate"], "Bearer");
+            let problem = problem_details(res).await;
+            assert_eq!(problem["code"], "unauthorized");
+        }
+
+        #[tokio::test]
+        async fn webhook_wrong_token() {
+            let req = webhook_request(r#"{"kind":"common"}"#, Some("othertoken"));
+            let res = testing_app().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        #[tokio::test]
+        async fn partner_configs() {
+            assert_eq!(
+                delete("/config-cache/partners", Some("sometoken")).await,
+                StatusCode::NO_CONTENT
+            );
+        }
+
+        #[tokio::test]
+        async fn partner_config() {
+            assert_eq!(
+                delete("/config-cache/partners/someid", Some("sometoken")).await,
+                StatusCode::NO_CONTENT
+            );
+        }
+
+        #[tokio::test]
+        async fn missing_token() {
+            let req = delete_request("/config-cache/partners", None);
+            let res = testing_app().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(res.headers()["WWW-Authenticate"], "Bearer");
+            let problem = problem_details(res).await;
+            assert_eq!(problem["code"], "unauthorized");
+        }
+
+        #[tokio::test]
+        async fn wrong_token() {
+            assert_eq!(
+                delete("/config-cache/partners/someid", Some("othertoken")).await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+
+        #[tokio::test]
+        async fn token_not_configured() {
+            let app = app(AppState {
+                config_cache_invalidator: Some(CacheInvalidator::disabled()),
+                ..closed_state()
+            });
+            let req = delete_request("/config-cache/partners", Some("sometoken"));
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            let req = webhook_request(r#"{"kind":"common"}"#, Some("sometoken"));
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+
+        #[tokio::test]
+        async fn not_caching() {
+            let app = app(AppState {
+                config_cache_token: Some(CacheToken::fixed("sometoken")),
+                ..closed_state()
+            });
+            let req = delete_request("/config-cache/partners", Some("sometoken"));
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            let req = webhook_request(r#"{"kind":"common"}"#, Some("sometoken"));
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+
+        #[tokio::test]
+        async fn openapi_paths() {
+            let req = Request::builder()
+                .uri("/openapi.json")
+                .body(Body::empty())
+                .unwrap();
+            let res = testing_app().oneshot(req).await.unwrap();
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            let openapi: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert!(openapi["paths"][CONFIG_WEBHOOK_PATH].is_object());
+            for path in CONFIG_CACHE_PATHS {
+                assert!(openapi["paths"][path].is_object(), "{path} missing");
+            }
+        }
+    }
+
+    mod ready_handler {
+        use super::*;
+
+        async fn ready(health_tx: HealthChannel, status_tx: StatusChannel) -> (StatusCode, String) {
+            let app = probes_fixture(health_tx, status_tx);
+            let req = Request::builder()
+                .uri("/ready")
+                .body(Body::empty())
+                .unwrap();
+            let res = app.oneshot(req).await.unwrap();
+            let status_code = res.status();
+            let body = to_bytes(res.into_body(), 1024).await.unwrap();
+            (status_code, String::from_utf8(body.to_vec()).unwrap())
+        }
+
+        #[tokio::test]
+        async fn roundtrip_error() {
+            let (health_tx, _) = roundtrip_channel(1);
+            let status_tx = status_tx(StatusCode::NO_CONTENT);
+            let (status_code, body) = ready(health_tx, status_tx).await;
+            assert_eq!(status_code, StatusCode::SERVICE_UNAVAILABLE);
+            assert!(body.starts_with(r#"{"dependencies":{"influxdb":{"state":"down","#));
+            assert!(body.contains(r#""configApi":{"state":"up","latencyMs":"#));
+        }
+
+        #[tokio::test]
+        async fn not_ready() {
+            let health_tx = status_tx(StatusCode::OK);
+            let status_tx = status_tx(StatusCode::BAD_GATEWAY);
+            let (status_code, body) = ready(health_tx, status_tx).await;
+            assert_eq!(status_code, StatusCode::SERVICE_UNAVAILABLE);
+            assert!(body.starts_with(r#"{"dependencies":{"influxdb":{"state":"up","#));
+            assert!(body.contains(r#""configApi":{"state":"down","latencyMs":"#));
+        }
+
+        #[tokio::test]
+        async fn all_up() {
+            let health_tx = status_tx(StatusCode::OK);
+            let status_tx = status_tx(StatusCode::NO_CONTENT);
+            let (status_code, body) = ready(health_tx, status_tx).await;
+            assert_eq!(status_code, StatusCode::OK);
+            assert!(body.starts_with(r#"{"dependencies":{"influxdb":{"state":"up","#));
+            assert!(body.contains(r#""configApi":{"state":"up","latencyMs":"#));
+            assert!(body.ends_with(r#""dataSources":{}}}"#));
+        }
+
+        #[tokio::test]
+        async fn data_source_down() {
+            let (health_tx, mut rx) = roundtrip_channel(10);
+            tokio::spawn(async move {
+                while let Some((name, _, reply_tx)) = rx.recv().await {
+                    let status_code = match name {
+                        Some(name) if name == "plant2" => StatusCode::SERVICE_UNAVAILABLE,
+                        _ => StatusCode::OK,
+                    };
+                    reply_tx.send(status_code).expect("error sending response");
+                }
+            });
+            let app = app(AppState {
+                health_channel: health_tx,
+                config_api_status_channel: status_tx(StatusCode::NO_CONTENT),
+                data_sources: Arc::new(HashSet::from(["plant2".to_string(), "plant3".to_string()])),
+                ..closed_state()
+            });
+            let req = Request::builder()
+                .uri("/ready")
+                .body(Body::empty())
+                .unwrap();
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let body = to_bytes(res.into_body(), 1024).await.unwrap();
+            let readiness = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+            let dependencies = &readiness["dependencies"];
+            assert_eq!(dependencies["influxdb"]["state"], "up");
+            assert_eq!(dependencies["dataSources"]["plant2"]["state"], "down");
+            assert_eq!(dependencies["dataSources"]["plant3"]["state"], "up");
+            assert!(dependencies["dataSources"]["plant2"]["latencyMs"].is_f64());
         }
     }
 
     mod timeline_handler {
         use std::vec;
 
-        use crate::channel::{RoundtripSender, roundtrip_channel};
+        use crate::channel::roundtrip_channel;
         use crate::influxdb::TimelineSlot;
 
         use super::*;
@@ -373,19 +1751,10 @@ mod tests {
             partner_config_channel: PartnerConfigChannel,
             timeline_channel: TimelineChannel,
         ) -> (Router, Request<Body>) {
-            let (common_config_channel, _) = roundtrip_channel(1);
-            let (health_channel, _) = roundtrip_channel(1);
-            let (performance_channel, _) = roundtrip_channel(1);
-            let (shift_objective_channel, _) = roundtrip_channel(1);
-            let (week_objective_channel, _) = roundtrip_channel(1);
             let app = app(AppState {
-                health_channel,
-                common_config_channel,
                 partner_config_channel,
                 timeline_channel,
-                performance_channel,
-                shift_objective_channel,
-                week_objective_channel,
+                ..closed_state()
             });
             let req = Request::builder()
                 .uri("/timeline/someid")
@@ -394,7 +1763,7 @@ mod tests {
             (app, req)
         }
 
-        fn successful_timeline_tx() -> RoundtripSender<TimelineRequest, TimelineResponse> {
+        fn successful_timeline_tx() -> TimelineChannel {
             let (tx, mut rx) = roundtrip_channel(1);
             tokio::spawn(async move {
                 let (_, _, reply_tx) = rx.recv().await.expect("channel has been closed");
@@ -408,7 +1777,9 @@ mod tests {
                         color: Some(5),
                     },
                 ];
-                reply_tx.send(slots.into()).expect("error sending response");
+                reply_tx
+                    .send(Ok(slots.into()))
+                    .expect("error sending response");
             });
             tx
         }
@@ -422,6 +1793,60 @@ mod tests {
             assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
         }
 
+        fn data_source_partner_config_tx(name: &str) -> PartnerConfigChannel {
+            let (tx, mut rx) = roundtrip_channel(1);
+            let data_source = DataSource {
+                name: Some(name.to_string()),
+                ..Default::default()
+            };
+            tokio::spawn(async move {
+                let (_, _, reply_tx) = rx.recv().await.expect("channel has been closed");
+                let config = PartnerConfig {
+                    target_cycle_time: 1.0,
+                    target_efficiency: 1.0,
+                    shift_engaged: Default::default(),
+                    site: None,
+                    timezone: None,
+                    data_source,
+                    stale: false,
+                };
+                reply_tx.send(Ok(config)).expect("error sending response");
+            });
+            tx
+        }
+
+        #[tokio::test]
+        async fn data_source() {
+            let app = app(AppState {
+                partner_config_channel: data_source_partner_config_tx("plant2"),
+                timeline_channel: successful_timeline_tx(),
+                data_sources: Arc::new(HashSet::from(["plant2".to_string()])),
+                ..closed_state()
+            });
+            let req = Request::builder()
+                .uri("/timeline/someid")
+                .body(Body::empty())
+                .unwrap();
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        #[tokio::test]
+        async fn unknown_data_source() {
+            let (app, req) = testing_fixture(
+                data_source_partner_config_tx("plant2"),
+                successful_timeline_tx(),
+            );
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            let problem = problem_details(res).await;
+            assert_eq!(problem["code"], "invalid-configuration");
+            assert_eq!(
+                problem["detail"],
+                "invalid configuration for partner `someid`: unknown data source"
+            );
+        }
+
         #[tokio::test]
         async fn timeline_roundtrip_error() {
             let partner_config_tx = successful_partner_config_tx();
@@ -445,6 +1870,38 @@ mod tests {
             ];
             assert_eq!(body.to_vec(), expected);
         }
+
+        #[tokio::test]
+        async fn json() {
+            let partner_config_tx = successful_partner_config_tx();
+            let timeline_tx = successful_timeline_tx();
+            let (app, mut req) = testing_fixture(partner_config_tx, timeline_tx);
+            req.headers_mut()
+                .insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["Content-Type"], "application/json");
+            assert_eq!(res.headers()["Vary"], "accept");
+            let body = to_bytes(res.into_body(), 1024).await.unwrap();
+            assert_eq!(
+                body,
+                r#"[{"start":0,"color":null},{"start":471414600,"color":5}]"#
+            );
+        }
+
+        #[tokio::test]
+        async fn csv() {
+            let partner_config_tx = successful_partner_config_tx();
+            let timeline_tx = successful_timeline_tx();
+            let (app, mut req) = testing_fixture(partner_config_tx, timeline_tx);
+            req.headers_mut()
+                .insert(header::ACCEPT, HeaderValue::from_static("text/csv"));
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["Content-Type"], "text/csv; charset=utf-8");
+            let body = to_bytes(res.into_body(), 1024).await.unwrap();
+            assert_eq!(body, "start,color\n0,\n471414600,5\n");
+        }
     }
 
     mod performance_handler {
@@ -455,18 +1912,11 @@ mod tests {
             partner_config_channel: PartnerConfigChannel,
             performance_channel: PerformanceChannel,
         ) -> (Router, Request<Body>) {
-            let (health_channel, _) = roundtrip_channel(1);
-            let (timeline_channel, _) = roundtrip_channel(1);
-            let (shift_objective_channel, _) = roundtrip_channel(1);
-            let (week_objective_channel, _) = roundtrip_channel(1);
             let app = app(AppState {
-                health_channel,
                 common_config_channel,
                 partner_config_channel,
-                timeline_channel,
                 performance_channel,
-                shift_objective_channel,
-                week_objective_channel,
+                ..closed_state()
             });
             let req = Request::builder()
                 .uri("/performance/anid")
@@ -476,11 +1926,11 @@ mod tests {
             (app, req)
         }
 
-        fn successful_performance_tx() -> RoundtripSender<PerformanceRequest, f32> {
+        fn successful_performance_tx() -> PerformanceChannel {
             let (tx, mut rx) = roundtrip_channel(1);
             tokio::spawn(async move {
                 let (_, _, reply_tx) = rx.recv().await.expect("channel has been closed");
-                reply_tx.send(42.4242).expect("error sending response");
+                reply_tx.send(Ok(42.4242)).expect("error sending response");
             });
             tx
         }
@@ -515,6 +1965,74 @@ mod tests {
             assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
         }
 
+        #[tokio::test]
+        async fn unknown_partner() {
+            let common_config_tx = successful_common_config_tx();
+            let partner_config_tx = failing_tx(Failure::NotFound);
+            let performance_tx = successful_performance_tx();
+            let (app, req) = testing_fixture(common_config_tx, partner_config_tx, performance_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            let problem = problem_details(res).await;
+            assert_eq!(problem["code"], "unknown-partner");
+            assert_eq!(problem["dependency"], "config-api");
+        }
+
+        #[tokio::test]
+        async fn unreachable_config_api() {
+            let common_config_tx = successful_common_config_tx();
+            let partner_config_tx = failing_tx(Failure::Unreachable);
+            let performance_tx = successful_performance_tx();
+            let (app, req) = testing_fixture(common_config_tx, partner_config_tx, performance_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+            let problem = problem_details(res).await;
+            assert_eq!(problem["code"], "dependency-unreachable");
+            assert_eq!(problem["dependency"], "config-api");
+        }
+
+        #[tokio::test]
+        async fn influxdb_timeout() {
+            let common_config_tx = successful_common_config_tx();
+            let partner_config_tx = successful_partner_config_tx();
+            let performance_tx = failing_tx(Failure::Timeout);
+            let (app, req) = testing_fixture(common_config_tx, partner_config_tx, performance_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+            let problem = problem_details(res).await;
+            assert_eq!(problem["code"], "dependency-timeout");
+            assert_eq!(problem["dependency"], "influxdb");
+        }
+
+        #[tokio::test]
+        async fn full_channel() {
+            let common_config_tx = successful_common_config_tx();
+            let partner_config_tx = successful_partner_config_tx();
+            let (performance_tx, _performance_rx) = roundtrip_channel(1);
+            let pending_tx = performance_tx.clone();
+            // Fills the channel, its request being never received.
+            tokio::spawn(async move {
+                let request = PerformanceRequest {
+                    id: "pending".to_string(),
+                    shift_start_times: Vec::new(),
+                    pauses: Vec::new(),
+                    timezone: Tz::UTC,
+                    target_cycle_time: 1.0,
+                    data_source: Default::default(),
+                    bypass_cache: false,
+                };
+                pending_tx.roundtrip(request).await
+            });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let (app, req) = testing_fixture(common_config_tx, partner_config_tx, performance_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(res.headers()["Retry-After"], "1");
+            let problem = problem_details(res).await;
+            assert_eq!(problem["code"], "overloaded");
+            assert_eq!(problem["dependency"], "channel");
+        }
+
         #[tokio::test]
         async fn success() {
             let common_config_tx = successful_common_config_tx();
@@ -524,9 +2042,21 @@ mod tests {
             let res = app.oneshot(req).await.unwrap();
             assert_eq!(res.status(), StatusCode::OK);
             assert_eq!(res.headers()["Content-Type"], "application/json");
+            assert!(!res.headers().contains_key("Warning"));
             let body = to_bytes(res.into_body(), 1024).await.unwrap();
             assert_eq!(body, "42.4242");
         }
+
+        #[tokio::test]
+        async fn stale_config() {
+            let common_config_tx = successful_common_config_tx();
+            let partner_config_tx = partner_config_tx(true);
+            let performance_tx = successful_performance_tx();
+            let (app, req) = testing_fixture(common_config_tx, partner_config_tx, performance_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["Warning"], r#"110 - "Response is Stale""#);
+        }
     }
 
     mod shift_objective_handler {
@@ -537,18 +2067,11 @@ mod tests {
             partner_config_channel: PartnerConfigChannel,
             shift_objective_channel: ShiftObjectiveChannel,
         ) -> (Router, Request<Body>) {
-            let (health_channel, _) = roundtrip_channel(1);
-            let (timeline_channel, _) = roundtrip_channel(1);
-            let (performance_channel, _) = roundtrip_channel(1);
-            let (week_objective_channel, _) = roundtrip_channel(1);
             let app = app(AppState {
-                health_channel,
                 common_config_channel,
                 partner_config_channel,
-                timeline_channel,
-                performance_channel,
                 shift_objective_channel,
-                week_objective_channel,
+                ..closed_state()
             });
             let req = Request::builder()
                 .uri("/shift-objective/anotherid")
@@ -622,8 +2145,149 @@ mod tests {
             let res = app.oneshot(req).await.unwrap();
             assert_eq!(res.status(), StatusCode::OK);
             assert_eq!(res.headers()["Content-Type"], "application/json");
+            let res_timezone = res.headers()["timezone"].clone();
             let body = to_bytes(res.into_body(), 1024).await.unwrap();
             assert_eq!(body, r#"[{"t":0,"v":0},{"t":123,"v":456}]"#);
+            assert_eq!(res_timezone, "Europe/Paris");
+        }
+
+        #[tokio::test]
+        async fn msgpack() {
+            let common_config_tx = successful_common_config_tx();
+            let partner_config_tx = successful_partner_config_tx();
+            let shift_objective_tx = successful_shift_objective_tx();
+            let (app, mut req) =
+                testing_fixture(common_config_tx, partner_config_tx, shift_objective_tx);
+            req.headers_mut().insert(
+                header::ACCEPT,
+                HeaderValue::from_static("application/msgpack, application/json;q=0.5"),
+            );
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["Content-Type"], "application/msgpack");
+            let body = to_bytes(res.into_body(), 1024).await.unwrap();
+            let objective: serde_json::Value = rmp_serde::from_slice(&body).unwrap();
+            assert_eq!(
+                objective,
+                serde_json::json!([{"t": 0, "v": 0}, {"t": 123, "v": 456}])
+            );
+        }
+
+        #[tokio::test]
+        async fn csv() {
+            let common_config_tx = successful_common_config_tx();
+            let partner_config_tx = successful_partner_config_tx();
+            let shift_objective_tx = successful_shift_objective_tx();
+            let (app, mut req) =
+                testing_fixture(common_config_tx, partner_config_tx, shift_objective_tx);
+            req.headers_mut()
+                .insert(header::ACCEPT, HeaderValue::from_static("text/csv"));
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["Content-Type"], "text/csv; charset=utf-8");
+            let body = to_bytes(res.into_body(), 1024).await.unwrap();
+            assert_eq!(body, "t,v\n0,0\n123,456\n");
+        }
+
+        fn timezone_config_txs(
+            common_timezone: Option<Tz>,
+            partner_timezone: Option<Tz>,
+        ) -> (CommonConfigChannel, PartnerConfigChannel) {
+            let (common_config_tx, mut common_config_rx) = roundtrip_channel(1);
+            tokio::spawn(async move {
+                let (_, _, reply_tx) = common_config_rx.recv().await.unwrap();
+                let config = CommonConfig {
+                    shift_start_times: vec!["02:03:04".parse().unwrap()],
+                    pauses: vec![],
+                    week_start: WeekStart {
+                        day: Weekday::Mon,
+                        shift_index: 0,
+                    },
+                    timezone: common_timezone,
+                    stale: false,
+                };
+                reply_tx.send(Ok(config)).unwrap();
+            });
+            let (partner_config_tx, mut partner_config_rx) = roundtrip_channel(1);
+            tokio::spawn(async move {
+                let (_, _, reply_tx) = partner_config_rx.recv().await.unwrap();
+                let config = PartnerConfig {
+                    target_cycle_time: 1.0,
+                    target_efficiency: 1.0,
+                    shift_engaged: Default::default(),
+                    site: None,
+                    timezone: partner_timezone,
+                    data_source: Default::default(),
+                    stale: false,
+                };
+                reply_tx.send(Ok(config)).unwrap();
+            });
+            (common_config_tx, partner_config_tx)
+        }
+
+        async fn used_timezone(
+            common_timezone: Option<Tz>,
+            partner_timezone: Option<Tz>,
+            client_timezone: Option<&str>,
+        ) -> Result<String, StatusCode> {
+            let (common_config_tx, partner_config_tx) =
+                timezone_config_txs(common_timezone, partner_timezone);
+            let shift_objective_tx = successful_shift_objective_tx();
+            let (app, mut req) =
+                testing_fixture(common_config_tx, partner_config_tx, shift_objective_tx);
+            req.headers_mut().remove("client-timezone");
+            if let Some(client_timezone) = client_timezone {
+                req.headers_mut()
+                    .insert("client-timezone", client_timezone.parse().unwrap());
+            }
+            let res = app.oneshot(req).await.unwrap();
+            if res.status() != StatusCode::OK {
+                return Err(res.status());
+            }
+            Ok(res.headers()["timezone"].to_str().unwrap().to_string())
+        }
+
+        #[tokio::test]
+        async fn timezone_resolution() {
+            let site = Some(Tz::Europe__Paris);
+            let partner = Some(Tz::America__New_York);
+            assert_eq!(
+                used_timezone(site, None, None).await.as_deref(),
+                Ok("Europe/Paris")
+            );
+            assert_eq!(
+                used_timezone(site, partner, None).await.as_deref(),
+                Ok("America/New_York")
+            );
+            assert_eq!(
+                used_timezone(site, partner, Some("Asia/Tokyo"))
+                    .await
+                    .as_deref(),
+                Ok("Asia/Tokyo")
+            );
+            assert_eq!(
+                used_timezone(None, None, Some("Asia/Tokyo"))
+                    .await
+                    .as_deref(),
+                Ok("Asia/Tokyo")
+            );
+        }
+
+        #[tokio::test]
+        async fn missing_timezone() {
+            assert_eq!(
+                used_timezone(None, None, None).await,
+                Err(StatusCode::BAD_REQUEST)
+            );
+        }
+
+        #[tokio::test]
+        async fn invalid_client_timezone() {
+            let site = Some(Tz::Europe__Paris);
+            assert_eq!(
+                used_timezone(site, None, Some("Mars/Olympus_Mons")).await,
+                Err(StatusCode::BAD_REQUEST)
+            );
         }
     }
 
@@ -635,18 +2299,11 @@ mod tests {
             partner_config_channel: PartnerConfigChannel,
             week_objective_channel: WeekObjectiveChannel,
         ) -> (Router, Request<Body>) {
-            let (health_channel, _) = roundtrip_channel(1);
-            let (timeline_channel, _) = roundtrip_channel(1);
-            let (performance_channel, _) = roundtrip_channel(1);
-            let (shift_objective_channel, _) = roundtrip_channel(1);
             let app = app(AppState {
-                health_channel,
                 common_config_channel,
                 partner_config_channel,
-                timeline_channel,
-                performance_channel,
-                shift_objective_channel,
                 week_objective_channel,
+                ..closed_state()
             });
             let req = Request::builder()
                 .uri("/week-objective/yetanotherid")
@@ -698,6 +2355,120 @@ mod tests {
             assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
         }
 
+        #[tokio::test]
+        async fn invalid_common_config() {
+            let common_config_tx = failing_tx(Failure::Invalid("shift start times are empty"));
+            let partner_config_tx = successful_partner_config_tx();
+            let week_objective_tx = successful_week_objective_tx();
+            let (app, req) =
+                testing_fixture(common_config_tx, partner_config_tx, week_objective_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            let problem = problem_details(res).await;
+            assert_eq!(problem["code"], "invalid-configuration");
+            assert_eq!(problem["dependency"], "config-api");
+            assert_eq!(
+                problem["detail"],
+                "invalid common configuration: shift start times are empty"
+            );
+        }
+
+        #[tokio::test]
+        async fn invalid_fetched_partner_config() {
+            let common_config_tx = successful_common_config_tx();
+            let partner_config_tx =
+                failing_tx(Failure::Invalid("target efficiency is not between 0 and 1"));
+            let week_objective_tx = successful_week_objective_tx();
+            let (app, req) =
+                testing_fixture(common_config_tx, partner_config_tx, week_objective_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            let problem = problem_details(res).await;
+            assert_eq!(problem["code"], "invalid-configuration");
+            assert_eq!(
+                problem["detail"],
+                "invalid configuration for partner `yetanotherid`: \
+                target efficiency is not between 0 and 1"
+            );
+        }
+
+        #[tokio::test]
+        async fn invalid_partner_config() {
+            let common_config_tx = successful_common_config_tx();
+            let (partner_config_tx, mut rx) = roundtrip_channel(1);
+            tokio::spawn(async move {
+                let (_, _, reply_tx) = rx.recv().await.expect("channel has been closed");
+                let config = PartnerConfig {
+                    target_cycle_time: 1.0,
+                    target_efficiency: 1.0,
+                    shift_engaged: vec![true; 8],
+                    site: None,
+                    timezone: None,
+                    data_source: Default::default(),
+                    stale: false,
+                };
+                reply_tx.send(Ok(config)).expect("error sending response");
+            });
+            let week_objective_tx = successful_week_objective_tx();
+            let (app, req) =
+                testing_fixture(common_config_tx, partner_config_tx, week_objective_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            let problem = problem_details(res).await;
+            assert_eq!(problem["code"], "invalid-configuration");
+            assert_eq!(
+                problem["detail"],
+                "invalid configuration for partner `yetanotherid`: \
+                shift engaged has more entries than shifts in a week"
+            );
+        }
+
+        #[tokio::test]
+        async fn site_common_config() {
+            let (common_config_tx, mut rx) = roundtrip_channel(1);
+            tokio::spawn(async move {
+                let (request, _, reply_tx): (CommonConfigRequest, _, _) =
+                    rx.recv().await.expect("channel has been closed");
+                assert_eq!(request.site.as_deref(), Some("plant2"));
+                let config = CommonConfig {
+                    shift_start_times: vec!["02:03:04".parse().unwrap()],
+                    pauses: Vec::new(),
+                    week_start: WeekStart {
+                        day: Weekday::Sun,
+                        shift_index: 0,
+                    },
+                    timezone: None,
+                    stale: false,
+                };
+                reply_tx.send(Ok(config)).expect("error sending response");
+            });
+            let (partner_config_tx, mut rx) = roundtrip_channel(1);
+            tokio::spawn(async move {
+                let (_, _, reply_tx) = rx.recv().await.expect("channel has been closed");
+                let config = PartnerConfig {
+                    target_cycle_time: 1.0,
+                    target_efficiency: 1.0,
+                    shift_engaged: Vec::new(),
+                    site: Some("plant2".to_string()),
+                    timezone: None,
+                    data_source: Default::default(),
+                    stale: false,
+                };
+                reply_tx.send(Ok(config)).expect("error sending response");
+            });
+            let (week_objective_tx, mut rx) = roundtrip_channel(1);
+            tokio::spawn(async move {
+                let (request, _, reply_tx): (WeekObjectiveRequest, _, _) =
+                    rx.recv().await.expect("channel has been closed");
+                assert_eq!(request.week_start.day, Weekday::Sun);
+                reply_tx.send(Vec::new()).expect("error sending response");
+            });
+            let (app, req) =
+                testing_fixture(common_config_tx, partner_config_tx, week_objective_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
         #[tokio::test]
         async fn week_objective_roundtrip_error() {
             let common_config_tx = successful_common_config_tx();
@@ -723,4 +2494,111 @@ mod tests {
             assert_eq!(body, r#"[{"t":0,"v":0},{"t":345,"v":678}]"#);
         }
     }
+
+    mod custom_handler {
+        use crate::custom_endpoints::Reducer;
+
+        use super::*;
+
+        fn testing_fixture(
+            common_config_channel: CommonConfigChannel,
+            partner_config_channel: PartnerConfigChannel,
+            custom_channel: CustomChannel,
+        ) -> (Router, Request<Body>) {
+            let endpoint = Endpoint {
+                route: "scrap-ratio".to_string(),
+                query: "some query".into(),
+                reducer: Reducer::Ratio {
+                    numerator: "scrap".to_string(),
+                    denominator: "total".to_string(),
+                },
+            };
+            let app = app(AppState {
+                common_config_channel,
+                partner_config_channel,
+                custom_channel,
+                custom_endpoints: Arc::new([Arc::new(endpoint)]),
+                ..closed_state()
+            });
+            let req = Request::builder()
+                .uri("/scrap-ratio/anid")
+                .header("client-timezone", "Europe/Paris")
+                .body(Body::empty())
+                .unwrap();
+            (app, req)
+        }
+
+        #[tokio::test]
+        async fn openapi_path() {
+            let (common_config_tx, _) = roundtrip_channel(1);
+            let (partner_config_tx, _) = roundtrip_channel(1);
+            let (custom_tx, _) = roundtrip_channel(1);
+            let (app, _) = testing_fixture(common_config_tx, partner_config_tx, custom_tx);
+            let req = Request::builder()
+                .uri("/openapi.json")
+                .body(Body::empty())
+                .unwrap();
+            let res = app.oneshot(req).await.unwrap();
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            let openapi: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let operation = &openapi["paths"]["/scrap-ratio/{id}"]["get"];
+            assert_eq!(operation["operationId"], "scrap-ratio");
+        }
+
+        fn successful_custom_tx() -> CustomChannel {
+            let (tx, mut rx) = roundtrip_channel(1);
+            tokio::spawn(async move {
+                let (request, _, reply_tx): (CustomRequest, _, _) =
+                    rx.recv().await.expect("channel has been closed");
+                assert_eq!(&*request.query, "some query");
+                reply_tx
+                    .send(Ok(Some(0.125)))
+                    .expect("error sending response");
+            });
+            tx
+        }
+
+        #[tokio::test]
+        async fn common_config_roundtrip_error() {
+            let (common_config_tx, _) = roundtrip_channel(1);
+            let partner_config_tx = successful_partner_config_tx();
+            let custom_tx = successful_custom_tx();
+            let (app, req) = testing_fixture(common_config_tx, partner_config_tx, custom_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+
+        #[tokio::test]
+        async fn partner_config_roundtrip_error() {
+            let common_config_tx = successful_common_config_tx();
+            let (partner_config_tx, _) = roundtrip_channel(1);
+            let custom_tx = successful_custom_tx();
+            let (app, req) = testing_fixture(common_config_tx, partner_config_tx, custom_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+
+        #[tokio::test]
+        async fn custom_roundtrip_error() {
+            let common_config_tx = successful_common_config_tx();
+            let partner_config_tx = successful_partner_config_tx();
+            let (custom_tx, _) = roundtrip_channel(1);
+            let (app, req) = testing_fixture(common_config_tx, partner_config_tx, custom_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+
+        #[tokio::test]
+        async fn success() {
+            let common_config_tx = successful_common_config_tx();
+            let partner_config_tx = successful_partner_config_tx();
+            let custom_tx = successful_custom_tx();
+            let (app, req) = testing_fixture(common_config_tx, partner_config_tx, custom_tx);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["Content-Type"], "application/json");
+            let body = to_bytes(res.into_body(), 1024).await.unwrap();
+            assert_eq!(body, "0.125");
+        }
+    }
 }
@@ -1,21 +1,49 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
 use std::io;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time;
 
+use anyhow::Context as _;
 use chrono::serde::ts_seconds;
 use chrono::{DateTime, Duration, NaiveTime, Utc};
 use chrono_tz::Tz;
 use clap::Args;
-use csv_async::AsyncReaderBuilder;
-use futures_util::TryStreamExt;
+use futures_util::future::join_all;
+use futures_util::{Stream, StreamExt, TryStreamExt};
 use reqwest::{Client as HttpClient, StatusCode, header};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
-use tracing::{Instrument, error, info, info_span, instrument};
+use tracing::{Instrument, debug, error, info, info_span, instrument};
 use url::Url;
-
-use crate::channel::{RoundtripSender, roundtrip_channel};
+use utoipa::ToSchema;
+
+use crate::annotated_csv::{self, DIALECT_ANNOTATIONS};
+use crate::cache::TtlCache;
+use crate::channel::{RoundtripSender, process_concurrently, reply_deadline, roundtrip_channel};
+use crate::config_api::DataSource;
+use crate::custom_endpoints::{Reducer, Row};
+use crate::data_sources::NamedDataSource;
+use crate::flux::{Schema, Template, Templates};
+use crate::negotiation::{Body, Format};
+use crate::reload::{poll_changes, read_secret};
 use crate::time::{apply_time_spans, find_shift_bounds};
+use crate::tls::{self, TlsOptions};
+use crate::upstream::{CircuitBreaker, Failure, Resilience, Timeouts};
+
+/// Lists the partner ID tag values in the measurement.
+const DISCOVERY_QUERY: &str = r#"import "influxdata/influxdb/schema"
+
+schema.measurementTagValues(
+  bucket: "__bucketplaceholder__",
+  measurement: "__measurementplaceholder__",
+  tag: "__idtagplaceholder__",
+)
+"#;
 
 #[derive(Args)]
 #[group(skip)]
@@ -25,8 +53,16 @@ pub(crate) struct Config {
     influxdb_url: Url,
 
     /// InfluxDB API token with read permission on configured bucket
-    #[arg(env, long)]
-    influxdb_api_token: String,
+    #[arg(env, long, required_unless_present = "influxdb_api_token_file")]
+    influxdb_api_token: Option<String>,
+
+    /// File containing the InfluxDB API token, reloaded on change
+    #[arg(env, long, conflicts_with = "influxdb_api_token")]
+    influxdb_api_token_file: Option<PathBuf>,
+
+    /// Interval between InfluxDB API token file checks
+    #[arg(env, long, default_value = "10s")]
+    influxdb_api_token_reload_interval: humantime::Duration,
 
     /// InfluxDB organization name or ID
     #[arg(env, long)]
@@ -39,23 +75,105 @@ pub(crate) struct Config {
     /// InfluxDB measurement
     #[arg(env, long)]
     influxdb_measurement: String,
+
+    #[command(flatten)]
+    schema: Schema,
+
+    /// Directory of Flux query files overriding the built-in ones (reloaded on SIGHUP)
+    #[arg(env, long)]
+    flux_queries_dir: Option<PathBuf>,
+
+    /// Expiration time for timeline query results cache (zero disables it)
+    #[arg(env, long, default_value = "30s")]
+    timeline_cache_expiration: humantime::Duration,
+
+    /// Expiration time for performance query results cache (zero disables it)
+    #[arg(env, long, default_value = "30s")]
+    performance_cache_expiration: humantime::Duration,
 }
 
-pub(crate) type HealthChannel = RoundtripSender<(), StatusCode>;
+impl Config {
+    /// Returns whether Flux queries are loaded from a directory, to be
+    /// reloaded on demand.
+    pub(crate) fn reloads_flux_queries(&self) -> bool {
+        self.flux_queries_dir.is_some()
+    }
+}
 
+#[derive(Args)]
+#[group(skip)]
+pub(crate) struct TlsConfig {
+    /// PEM bundle of CA certificates trusted for InfluxDB connections
+    #[arg(env, long)]
+    influxdb_ca_cert: Option<PathBuf>,
+
+    /// PEM client certificate for InfluxDB mutual TLS
+    #[arg(env, long, requires = "influxdb_client_key")]
+    influxdb_client_cert: Option<PathBuf>,
+
+    /// PEM private key of the InfluxDB client certificate
+    #[arg(env, long, requires = "influxdb_client_cert")]
+    influxdb_client_key: Option<PathBuf>,
+
+    /// Skip InfluxDB server certificate verification (for development only)
+    #[arg(env, long)]
+    influxdb_tls_insecure_skip_verify: bool,
+}
+
+impl TlsConfig {
+    pub(crate) fn options(&self) -> TlsOptions<'_> {
+        TlsOptions {
+            ca_cert: self.influxdb_ca_cert.as_deref(),
+            client_cert: self.influxdb_client_cert.as_deref(),
+            client_key: self.influxdb_client_key.as_deref(),
+            insecure_skip_verify: self.influxdb_tls_insecure_skip_verify,
+        }
+    }
+}
+
+/// Channel checking the health of an InfluxDB instance, the command line one
+/// unless a data source name is given.
+pub(crate) type HealthChannel = RoundtripSender<Option<String>, StatusCode>;
+
+#[derive(Clone)]
 pub(crate) struct TimelineRequest {
     pub(crate) id: String,
     pub(crate) target_cycle_time: f32,
+    pub(crate) data_source: DataSource,
+    pub(crate) bypass_cache: bool,
+}
+
+// Needed for requests coalescing, floating point values are compared bitwise.
+impl PartialEq for TimelineRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+            && self.target_cycle_time.to_bits() == other.target_cycle_time.to_bits()
+            && self.data_source == other.data_source
+            && self.bypass_cache == other.bypass_cache
+    }
 }
 
-#[derive(Debug, PartialEq, Serialize)]
+impl Eq for TimelineRequest {}
+
+impl Hash for TimelineRequest {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+        self.target_cycle_time.to_bits().hash(state);
+        self.data_source.hash(state);
+        self.bypass_cache.hash(state);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
 pub(crate) struct TimelineSlot {
     #[serde(with = "ts_seconds")]
+    #[schema(value_type = i64)]
     pub(crate) start: DateTime<Utc>,
     pub(crate) color: Option<u8>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(transparent)]
 pub(crate) struct TimelineResponse(Vec<TimelineSlot>);
 
 impl From<Vec<TimelineSlot>> for TimelineResponse {
@@ -65,36 +183,114 @@ impl From<Vec<TimelineSlot>> for TimelineResponse {
 }
 
 impl TimelineResponse {
+    #[cfg(test)]
     pub(crate) fn into_inner(self) -> Vec<TimelineSlot> {
         self.0
     }
 }
 
-pub(crate) type TimelineChannel = RoundtripSender<TimelineRequest, TimelineResponse>;
+impl Body for TimelineResponse {
+    const FORMATS: &'static [Format] = &[Format::MessagePack, Format::Json, Format::Csv];
 
+    /// Encodes slots as `[start, color]` arrays, as clients always got them.
+    fn to_msgpack(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+        rmp_serde::to_vec(self)
+    }
+
+    /// Writes a record per slot.
+    fn write_csv<W: io::Write>(&self, writer: &mut csv::Writer<W>) -> csv::Result<()> {
+        self.0.write_csv(writer)
+    }
+}
+
+pub(crate) type TimelineChannel =
+    RoundtripSender<TimelineRequest, Result<TimelineResponse, Failure>>;
+
+#[derive(Clone)]
 pub(crate) struct PerformanceRequest {
     pub(crate) id: String,
     pub(crate) shift_start_times: Vec<NaiveTime>,
     pub(crate) pauses: Vec<(NaiveTime, NaiveTime)>,
     pub(crate) timezone: Tz,
     pub(crate) target_cycle_time: f32,
+    pub(crate) data_source: DataSource,
+    pub(crate) bypass_cache: bool,
+}
+
+// Needed for requests coalescing, floating point values are compared bitwise.
+impl PartialEq for PerformanceRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+            && self.shift_start_times == other.shift_start_times
+            && self.pauses == other.pauses
+            && self.timezone == other.timezone
+            && self.target_cycle_time.to_bits() == other.target_cycle_time.to_bits()
+            && self.data_source == other.data_source
+            && self.bypass_cache == other.bypass_cache
+    }
+}
+
+impl Eq for PerformanceRequest {}
+
+impl Hash for PerformanceRequest {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+        self.shift_start_times.hash(state);
+        self.pauses.hash(state);
+        self.timezone.hash(state);
+        self.target_cycle_time.to_bits().hash(state);
+        self.data_source.hash(state);
+        self.bypass_cache.hash(state);
+    }
+}
+
+pub(crate) type PerformanceChannel = RoundtripSender<PerformanceRequest, Result<f32, Failure>>;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CustomRequest {
+    pub(crate) id: String,
+    pub(crate) query: Arc<str>,
+    pub(crate) reducer: Reducer,
+    pub(crate) shift_start_times: Vec<NaiveTime>,
+    pub(crate) timezone: Tz,
+    pub(crate) data_source: DataSource,
 }
 
-pub(crate) type PerformanceChannel = RoundtripSender<PerformanceRequest, f32>;
+pub(crate) type CustomChannel = RoundtripSender<CustomRequest, Result<Option<f64>, Failure>>;
+
+/// Channel listing identifiers of partners having data in a data source.
+pub(crate) type DiscoveryChannel = RoundtripSender<DataSource, Result<Vec<String>, Failure>>;
+
+#[derive(Serialize)]
+struct QueryRequest<'a> {
+    query: &'a str,
+    dialect: Dialect,
+}
+
+#[derive(Serialize)]
+struct Dialect {
+    annotations: &'static [&'static str],
+}
 
 #[derive(Deserialize)]
 struct QueryResponse {
     message: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 struct TimelineRow {
     #[serde(rename = "_time")]
     time: DateTime<Utc>,
     color: Option<u8>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
+struct TagValueRow {
+    #[serde(rename = "_value")]
+    value: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct PerformanceRow {
     /// Number of elapsed minutes.
@@ -107,56 +303,228 @@ struct PerformanceRow {
     _part_ref: String,
 }
 
+fn auth_header_value(token: &str) -> Arc<str> {
+    Arc::from(format!("Token {token}").as_str())
+}
+
 #[derive(Clone)]
-pub(crate) struct Client {
+struct TokenFile {
+    path: PathBuf,
+    reload_interval: time::Duration,
+    subject: String,
+    auth_header_tx: watch::Sender<Arc<str>>,
+}
+
+/// InfluxDB instance queries are sent to, with its own credentials, TLS
+/// settings and circuit breaker.
+#[derive(Clone)]
+struct Instance {
     base_url: Arc<Url>,
-    auth_header: Arc<str>,
     org: Arc<str>,
+    auth_header: watch::Receiver<Arc<str>>,
+    http_client: HttpClient,
+    resilience: Resilience,
+}
+
+struct FluxQueriesDir {
+    path: PathBuf,
+    templates_tx: watch::Sender<Arc<Templates>>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Client {
+    default_instance: Instance,
+    /// Instances of the named data sources.
+    instances: Arc<HashMap<String, Instance>>,
+    token_files: Arc<[TokenFile]>,
+    token_reload_interval: time::Duration,
+    templates: watch::Receiver<Arc<Templates>>,
+    flux_queries_dir: Option<Arc<FluxQueriesDir>>,
     bucket: Arc<str>,
     measurement: Arc<str>,
-    http_client: HttpClient,
+    schema: Arc<Schema>,
+    timeline_cache: TtlCache<(DataSource, String), Vec<TimelineRow>>,
+    performance_cache: TtlCache<(DataSource, String), Vec<PerformanceRow>>,
 }
 
 impl Client {
-    pub(crate) fn new(config: &Config, http_client: HttpClient) -> Self {
-        let base_url = Arc::new(config.influxdb_url.clone());
-        let auth_header = Arc::from(format!("Token {}", config.influxdb_api_token).as_str());
-        let org = Arc::from(config.influxdb_org.as_str());
+    pub(crate) fn new(config: &Config, http_client: HttpClient, resilience: Resilience) -> Self {
+        let token = config.influxdb_api_token.as_deref().unwrap_or_default();
+        let (auth_header_tx, auth_header) = watch::channel(auth_header_value(token));
+        let token_reload_interval = config.influxdb_api_token_reload_interval.into();
+        let token_files = config
+            .influxdb_api_token_file
+            .iter()
+            .map(|path| TokenFile {
+                path: path.clone(),
+                reload_interval: token_reload_interval,
+                subject: "API token".to_string(),
+                auth_header_tx: auth_header_tx.clone(),
+            })
+            .collect();
+        let default_instance = Instance {
+            base_url: Arc::new(config.influxdb_url.clone()),
+            org: Arc::from(config.influxdb_org.as_str()),
+            auth_header,
+            http_client,
+            resilience,
+        };
+        let (templates_tx, templates) = watch::channel(Default::default());
+        let flux_queries_dir = config.flux_queries_dir.as_ref().map(|path| {
+            Arc::new(FluxQueriesDir {
+                path: path.clone(),
+                templates_tx,
+            })
+        });
         let bucket = Arc::from(config.influxdb_bucket.as_str());
         let measurement = Arc::from(config.influxdb_measurement.as_str());
+        let schema = Arc::new(config.schema.clone());
+        let timeline_cache = TtlCache::new(config.timeline_cache_expiration.into());
+        let performance_cache = TtlCache::new(config.performance_cache_expiration.into());
 
         Self {
-            base_url,
-            auth_header,
-            org,
+            default_instance,
+            instances: Default::default(),
+            token_files,
+            token_reload_interval,
+            templates,
+            flux_queries_dir,
             bucket,
             measurement,
-            http_client,
+            schema,
+            timeline_cache,
+            performance_cache,
         }
     }
 
+    /// Adds the named data sources partner configurations can route queries
+    /// to, each with its own HTTP client and circuit breaker, their API token
+    /// files being reloaded like the command line one.
+    pub(crate) fn with_data_sources(
+        mut self,
+        data_sources: &[NamedDataSource],
+        timeouts: Timeouts,
+    ) -> anyhow::Result<Self> {
+        let mut instances = HashMap::new();
+        let mut token_files = Vec::new();
+        for data_source in data_sources {
+            let upstream = format!("InfluxDB data source `{}`", data_source.name);
+            let http_client = tls::http_client(&upstream, &data_source.tls_options(), timeouts)
+                .with_context(|| format!("error setting up data source `{}`", data_source.name))?;
+            let token = data_source.api_token.as_deref().unwrap_or_default();
+            let (auth_header_tx, auth_header) = watch::channel(auth_header_value(token));
+            if let Some(path) = &data_source.api_token_file {
+                token_files.push(TokenFile {
+                    path: path.clone(),
+                    reload_interval: self.token_reload_interval,
+                    subject: format!("API token of data source `{}`", data_source.name),
+                    auth_header_tx,
+                });
+            }
+            let org = data_source
+                .org
+                .as_deref()
+                .map_or_else(|| self.default_instance.org.clone(), Arc::from);
+            let resilience = self
+                .default_instance
+                .resilience
+                .for_upstream(&format!("influxdb data source `{}`", data_source.name));
+            let instance = Instance {
+                base_url: Arc::new(data_source.url.clone()),
+                org,
+                auth_header,
+                http_client,
+                resilience,
+            };
+            instances.insert(data_source.name.clone(), instance);
+        }
+        self.instances = Arc::new(instances);
+        self.token_files = self
+            .token_files
+            .iter()
+            .cloned()
+            .chain(token_files)
+            .collect();
+        Ok(self)
+    }
+
+    /// Returns the circuit breakers of the named data sources.
+    pub(crate) fn data_source_circuit_breakers(&self) -> BTreeMap<String, CircuitBreaker> {
+        self.instances
+            .iter()
+            .map(|(name, instance)| (name.clone(), instance.resilience.circuit_breaker()))
+            .collect()
+    }
+
+    /// Runs a query, routed to the data source overrides if any, through the
+    /// circuit breaker of the instance it is sent to.
     #[instrument(skip_all, name = "influxdb_query")]
-    async fn query<T>(&self, flux_query: &str) -> Result<Vec<T>, ()>
+    async fn query<T>(&self, flux_query: &str, data_source: &DataSource) -> Result<Vec<T>, Failure>
     where
         T: DeserializeOwned,
     {
-        let mut url = self.base_url.join("/api/v2/query").unwrap();
-        url.query_pairs_mut().append_pair("org", self.org.as_ref());
-        let body = flux_query
-            .replace("__bucketplaceholder__", &self.bucket)
-            .replace("__measurementplaceholder__", &self.measurement);
+        let instance = match &data_source.name {
+            Some(name) => self.instances.get(name).ok_or_else(|| {
+                error!(kind = "unknown data source", name);
+                Failure::Permanent
+            })?,
+            None => &self.default_instance,
+        };
+        instance
+            .resilience
+            .run(reply_deadline(), || {
+                self.try_query(instance, flux_query, data_source)
+            })
+            .await
+    }
 
-        let response = self
+    async fn try_query<T>(
+        &self,
+        instance: &Instance,
+        flux_query: &str,
+        data_source: &DataSource,
+    ) -> Result<Vec<T>, Failure>
+    where
+        T: DeserializeOwned,
+    {
+        let bucket = data_source
+            .bucket
+            .as_ref()
+            .map_or(&*self.bucket, AsRef::as_ref);
+        let measurement = data_source
+            .measurement
+            .as_ref()
+            .map_or(&*self.measurement, AsRef::as_ref);
+        let mut url = instance.base_url.join("/api/v2/query").map_err(|err| {
+            error!(kind = "joining InfluxDB URL", %err);
+            Failure::Permanent
+        })?;
+        url.query_pairs_mut().append_pair("org", &instance.org);
+        let query = data_source
+            .schema
+            .or(&self.schema)
+            .apply(flux_query)
+            .replace("__bucketplaceholder__", bucket)
+            .replace("__measurementplaceholder__", measurement);
+        let body = QueryRequest {
+            query: &query,
+            dialect: Dialect {
+                annotations: &DIALECT_ANNOTATIONS,
+            },
+        };
+
+        let auth_header = instance.auth_header.borrow().clone();
+        let response = instance
             .http_client
             .post(url)
             .header(header::ACCEPT, "application/csv")
-            .header(header::AUTHORIZATION, self.auth_header.as_ref())
-            .header(header::CONTENT_TYPE, "application/vnd.flux")
-            .body(body)
+            .header(header::AUTHORIZATION, auth_header.as_ref())
+            .json(&body)
             .send()
             .await
             .map_err(|err| {
                 error!(kind = "request sending", %err);
+                Failure::from_request_error(&err)
             })?;
 
         let status_code = response.status();
@@ -167,7 +535,7 @@ impl Client {
                 .map(|QueryResponse { message }| message)
                 .unwrap_or_default();
             error!(kind = "response status", %status_code, message);
-            return Err(());
+            return Err(Failure::from_status(status_code));
         }
 
         let reader = response
@@ -175,87 +543,275 @@ impl Client {
             .map_err(io::Error::other)
             .into_async_read();
 
-        AsyncReaderBuilder::new()
-            .comment(Some(b'#'))
-            .create_deserializer(reader)
-            .into_deserialize::<T>()
-            .try_collect()
-            .await
-            .map_err(|err| {
-                error!(kind="CSV data processing",%err);
-            })
+        let tables = annotated_csv::decode::<_, T>(reader).await.map_err(|err| {
+            error!(kind = "annotated CSV decoding", %err);
+            if err.is_io() {
+                Failure::Transient
+            } else {
+                Failure::Permanent
+            }
+        })?;
+
+        Ok(tables.into_iter().flat_map(|table| table.rows).collect())
+    }
+
+    /// Runs a query through a results cache, keyed by the data source and
+    /// the Flux query.
+    #[instrument(skip_all)]
+    async fn cached_query<T>(
+        &self,
+        cache: &TtlCache<(DataSource, String), Vec<T>>,
+        data_source: DataSource,
+        flux_query: String,
+        bypass_cache: bool,
+    ) -> Result<Vec<T>, Failure>
+    where
+        T: Clone + DeserializeOwned,
+    {
+        let key = (data_source, flux_query);
+        if bypass_cache {
+            debug!(msg = "cache bypassed");
+        } else if let Some(rows) = cache.get(&key) {
+            debug!(msg = "cache hit");
+            return Ok(rows);
+        } else {
+            debug!(msg = "cache miss");
+        }
+        let (data_source, flux_query) = &key;
+        let rows = self.query::<T>(flux_query, data_source).await?;
+        cache.insert(key, rows.clone());
+        Ok(rows)
+    }
+
+    /// Computes the timeline, merging consecutive slots of the same color.
+    async fn timeline(&self, request: TimelineRequest) -> Result<TimelineResponse, Failure> {
+        let template = self.templates.borrow().get(Template::Timeline);
+        let flux_query = template.replace("__idplaceholder__", &request.id).replace(
+            "__targetcycletimeplaceholder__",
+            &request.target_cycle_time.to_string(),
+        );
+        let mut rows = self
+            .cached_query(
+                &self.timeline_cache,
+                request.data_source,
+                flux_query,
+                request.bypass_cache,
+            )
+            .await?;
+        if let Some(last_row) = rows.pop() {
+            rows.dedup_by_key(|row| row.color);
+            rows.push(last_row);
+        };
+        let slots = rows
+            .into_iter()
+            .map(|TimelineRow { time: start, color }| TimelineSlot { start, color })
+            .collect::<Vec<_>>();
+        Ok(slots.into())
+    }
+
+    /// Computes the performance ratio of the current shift.
+    async fn performance(&self, request: PerformanceRequest) -> Result<f32, Failure> {
+        let (start_time, _) = find_shift_bounds(&request.timezone, &request.shift_start_times);
+        let template = self.templates.borrow().get(Template::Performance);
+        let flux_query = template
+            .replace("__idplaceholder__", &request.id)
+            .replace("__startplaceholder__", &start_time.to_rfc3339());
+        let rows = self
+            .cached_query(
+                &self.performance_cache,
+                request.data_source,
+                flux_query,
+                request.bypass_cache,
+            )
+            .await?;
+        let (expected_parts, done_parts) = rows
+            .into_iter()
+            .filter(|row| row.elapsed.is_positive())
+            .fold((0.0, 0), |(expected, done), row| {
+                let end = row.end.with_timezone(&request.timezone).naive_local();
+                let duration = Duration::minutes(row.elapsed);
+                let start = end - duration;
+                let pause_duration = apply_time_spans(start..end, &request.pauses)
+                    .into_iter()
+                    .fold(Duration::zero(), |acc, (span_start, span_end)| {
+                        acc + (span_end - span_start)
+                    });
+                let effective_duration = duration - pause_duration;
+                let effective_seconds = effective_duration.num_seconds() as f32;
+                let expected_parts = effective_seconds / request.target_cycle_time;
+                (expected + expected_parts, done + row.good_parts)
+            });
+        Ok(f32::from(done_parts) / expected_parts * 100.0)
+    }
+
+    /// Runs a custom endpoint query, and reduces its result.
+    async fn custom(&self, request: CustomRequest) -> Result<Option<f64>, Failure> {
+        let (start_time, _) = find_shift_bounds(&request.timezone, &request.shift_start_times);
+        let flux_query = request
+            .query
+            .replace("__idplaceholder__", &request.id)
+            .replace("__startplaceholder__", &start_time.to_rfc3339());
+        let rows = self.query::<Row>(&flux_query, &request.data_source).await?;
+        request.reducer.reduce(&rows).map_err(|err| {
+            error!(kind = "reducing custom query result", err);
+            Failure::Permanent
+        })
+    }
+
+    /// Lists the partner IDs found in the bucket and measurement of a data
+    /// source.
+    async fn discovery(&self, data_source: DataSource) -> Result<Vec<String>, Failure> {
+        let rows = self
+            .query::<TagValueRow>(DISCOVERY_QUERY, &data_source)
+            .await?;
+        Ok(rows.into_iter().map(|row| row.value).collect())
+    }
+
+    /// Loads the API token files, if configured, and spawns a task reloading
+    /// them on change, which terminates once all clients have been dropped.
+    pub(crate) fn handle_token_file(&self) -> anyhow::Result<Option<JoinHandle<()>>> {
+        if self.token_files.is_empty() {
+            return Ok(None);
+        }
+        for token_file in self.token_files.iter() {
+            let token = read_secret(&token_file.path)?;
+            token_file
+                .auth_header_tx
+                .send_replace(auth_header_value(&token));
+        }
+
+        let token_files = self.token_files.clone();
+        let task = tokio::spawn(
+            async move {
+                info!(status = "started");
+
+                join_all(token_files.iter().map(|token_file| {
+                    poll_changes(
+                        &token_file.auth_header_tx,
+                        token_file.reload_interval,
+                        &token_file.subject,
+                        || read_secret(&token_file.path).map(|token| auth_header_value(&token)),
+                    )
+                }))
+                .await;
+
+                info!(status = "terminating");
+            }
+            .instrument(info_span!("influxdb_token_file_handler")),
+        );
+
+        Ok(Some(task))
     }
 
-    pub(crate) fn handle_health(&self) -> (HealthChannel, JoinHandle<()>) {
-        let (tx, mut rx) = roundtrip_channel(10);
-        let http_client = self.http_client.clone();
-        let url = self.base_url.join("/health").unwrap();
+    /// Loads the Flux queries directory, if configured, and spawns a task
+    /// reloading it whenever `reload` yields, which terminates once all
+    /// clients have been dropped or `reload` has ended.
+    pub(crate) fn handle_flux_queries(
+        &self,
+        mut reload: impl Stream<Item = ()> + Send + Unpin + 'static,
+    ) -> anyhow::Result<Option<JoinHandle<()>>> {
+        let Some(flux_queries_dir) = self.flux_queries_dir.clone() else {
+            return Ok(None);
+        };
+        let templates = Templates::load(&flux_queries_dir.path)?;
+        flux_queries_dir
+            .templates_tx
+            .send_replace(Arc::new(templates));
 
         let task = tokio::spawn(
             async move {
                 info!(status = "started");
 
-                while let Some((_, _, reply_tx)) = rx.recv().await {
-                    let response = match http_client.get(url.clone()).send().await {
-                        Ok(resp) => resp,
+                loop {
+                    tokio::select! {
+                        _ = flux_queries_dir.templates_tx.closed() => break,
+                        reloaded = reload.next() => {
+                            if reloaded.is_none() {
+                                break;
+                            }
+                        }
+                    }
+                    match Templates::load(&flux_queries_dir.path) {
+                        Ok(templates) => {
+                            flux_queries_dir
+                                .templates_tx
+                                .send_replace(Arc::new(templates));
+                            info!(msg = "Flux queries reloaded");
+                        }
                         Err(err) => {
-                            error!(kind = "request sending", %err);
-                            continue;
+                            error!(kind = "reloading Flux queries", err = %format!("{err:#}"));
                         }
-                    };
-                    if reply_tx.send(response.status()).is_err() {
-                        error!(kind = "response channel sending");
                     }
                 }
 
                 info!(status = "terminating");
             }
-            .instrument(info_span!("influxdb_health_handler")),
+            .instrument(info_span!("influxdb_flux_queries_handler")),
         );
 
-        (tx, task)
+        Ok(Some(task))
     }
 
-    pub(crate) fn handle_timeline(&self) -> (TimelineChannel, JoinHandle<()>) {
-        const FLUX_QUERY: &str = include_str!("timeline.flux");
-        let (tx, mut rx) = roundtrip_channel::<TimelineRequest, TimelineResponse>(10);
+    pub(crate) fn handle_health(
+        &self,
+        concurrency_limit: NonZeroUsize,
+    ) -> (HealthChannel, JoinHandle<()>) {
+        let (tx, rx) = roundtrip_channel(10);
         let cloned_self = self.clone();
 
         let task = tokio::spawn(
             async move {
                 info!(status = "started");
 
-                while let Some((request, cancellation_token, reply_tx)) = rx.recv().await {
-                    let inner_task = async {
-                        let flux_query = FLUX_QUERY
-                            .replace("__idplaceholder__", &request.id)
-                            .replace(
-                                "__targetcycletimeplaceholder__",
-                                &request.target_cycle_time.to_string(),
-                            );
-                        let Ok(mut rows) = cloned_self.query::<TimelineRow>(&flux_query).await
-                        else {
-                            return;
-                        };
-                        if let Some(last_row) = rows.pop() {
-                            rows.dedup_by_key(|row| row.color);
-                            rows.push(last_row);
+                process_concurrently(rx, concurrency_limit, move |name: Option<String>| {
+                    let instance = match &name {
+                        Some(name) => cloned_self.instances.get(name),
+                        None => Some(&cloned_self.default_instance),
+                    };
+                    let request = instance.map(|instance| {
+                        let url = instance.base_url.join("/health").unwrap();
+                        instance.http_client.get(url)
+                    });
+                    async move {
+                        let Some(request) = request else {
+                            error!(kind = "unknown data source", name);
+                            return None;
                         };
-                        let slots = rows
-                            .into_iter()
-                            .map(|TimelineRow { time: start, color }| TimelineSlot { start, color })
-                            .collect::<Vec<_>>();
-                        if reply_tx.send(slots.into()).is_err() {
-                            error!(kind = "response channel sending");
+                        match request.send().await {
+                            Ok(response) => Some(response.status()),
+                            Err(err) => {
+                                error!(kind = "request sending", %err);
+                                None
+                            }
                         }
-                    };
-                    tokio::select! {
-                        _ = cancellation_token.cancelled() => {
-                            info!(msg="request was cancelled");
-                        },
-                        _ = inner_task => {},
                     }
-                }
+                })
+                .await;
+
+                info!(status = "terminating");
+            }
+            .instrument(info_span!("influxdb_health_handler")),
+        );
+
+        (tx, task)
+    }
+
+    pub(crate) fn handle_timeline(
+        &self,
+        concurrency_limit: NonZeroUsize,
+    ) -> (TimelineChannel, JoinHandle<()>) {
+        let (tx, rx) = roundtrip_channel(10);
+        let cloned_self = self.clone();
+
+        let task = tokio::spawn(
+            async move {
+                info!(status = "started");
+
+                process_concurrently(rx, concurrency_limit, move |request: TimelineRequest| {
+                    let client = cloned_self.clone();
+                    async move { Some(client.timeline(request).await) }
+                })
+                .await;
 
                 info!(status = "terminating");
             }
@@ -265,55 +821,22 @@ impl Client {
         (tx, task)
     }
 
-    pub(crate) fn handle_performance(&self) -> (PerformanceChannel, JoinHandle<()>) {
-        const FLUX_QUERY: &str = include_str!("performance.flux");
-        let (tx, mut rx) = roundtrip_channel::<PerformanceRequest, f32>(10);
+    pub(crate) fn handle_performance(
+        &self,
+        concurrency_limit: NonZeroUsize,
+    ) -> (PerformanceChannel, JoinHandle<()>) {
+        let (tx, rx) = roundtrip_channel(10);
         let cloned_self = self.clone();
 
         let task = tokio::spawn(
             async move {
                 info!(status = "started");
 
-                while let Some((request, cancellation_token, reply_tx)) = rx.recv().await {
-                    let inner_task = async {
-                        let (start_time, _) =
-                            find_shift_bounds(&request.timezone, &request.shift_start_times);
-                        let flux_query = FLUX_QUERY
-                            .replace("__idplaceholder__", &request.id)
-                            .replace("__startplaceholder__", &start_time.to_rfc3339());
-                        let Ok(rows) = cloned_self.query::<PerformanceRow>(&flux_query).await
-                        else {
-                            return;
-                        };
-                        let (expected_parts, done_parts) = rows
-                            .into_iter()
-                            .filter(|row| row.elapsed.is_positive())
-                            .fold((0.0, 0), |(expected, done), row| {
-                                let end = row.end.with_timezone(&request.timezone).naive_local();
-                                let duration = Duration::minutes(row.elapsed);
-                                let start = end - duration;
-                                let pause_duration = apply_time_spans(start..end, &request.pauses)
-                                    .into_iter()
-                                    .fold(Duration::zero(), |acc, (span_start, span_end)| {
-                                        acc + (span_end - span_start)
-                                    });
-                                let effective_duration = duration - pause_duration;
-                                let effective_seconds = effective_duration.num_seconds() as f32;
-                                let expected_parts = effective_seconds / request.target_cycle_time;
-                                (expected + expected_parts, done + row.good_parts)
-                            });
-                        let performance = f32::from(done_parts) / expected_parts * 100.0;
-                        if reply_tx.send(performance).is_err() {
-                            error!(kind = "response channel sending");
-                        }
-                    };
-                    tokio::select! {
-                        _ = cancellation_token.cancelled() => {
-                            info!(msg="request was cancelled");
-                        },
-                        _ = inner_task => {},
-                    }
-                }
+                process_concurrently(rx, concurrency_limit, move |request: PerformanceRequest| {
+                    let client = cloned_self.clone();
+                    async move { Some(client.performance(request).await) }
+                })
+                .await;
 
                 info!(status = "terminating");
             }
@@ -322,17 +845,84 @@ impl Client {
 
         (tx, task)
     }
+
+    pub(crate) fn handle_custom(
+        &self,
+        concurrency_limit: NonZeroUsize,
+    ) -> (CustomChannel, JoinHandle<()>) {
+        let (tx, rx) = roundtrip_channel(10);
+        let cloned_self = self.clone();
+
+        let task = tokio::spawn(
+            async move {
+                info!(status = "started");
+
+                process_concurrently(rx, concurrency_limit, move |request: CustomRequest| {
+                    let client = cloned_self.clone();
+                    async move { Some(client.custom(request).await) }
+                })
+                .await;
+
+                info!(status = "terminating");
+            }
+            .instrument(info_span!("influxdb_custom_handler")),
+        );
+
+        (tx, task)
+    }
+
+    pub(crate) fn handle_discovery(
+        &self,
+        concurrency_limit: NonZeroUsize,
+    ) -> (DiscoveryChannel, JoinHandle<()>) {
+        let (tx, rx) = roundtrip_channel(10);
+        let cloned_self = self.clone();
+
+        let task = tokio::spawn(
+            async move {
+                info!(status = "started");
+
+                process_concurrently(rx, concurrency_limit, move |data_source| {
+                    let client = cloned_self.clone();
+                    async move { Some(client.discovery(data_source).await) }
+                })
+                .await;
+
+                info!(status = "terminating");
+            }
+            .instrument(info_span!("influxdb_discovery_handler")),
+        );
+
+        (tx, task)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+
     use super::*;
+    use crate::upstream::BreakerState;
 
     mod client {
         use mockito::{Matcher, Mock, Server};
 
         use super::*;
 
+        fn named_data_source(name: &str, url: &str) -> NamedDataSource {
+            NamedDataSource {
+                name: name.to_string(),
+                url: url.parse().unwrap(),
+                org: Some("otherorg".to_string()),
+                api_token: Some("othertoken".to_string()),
+                api_token_file: None,
+                ca_cert: None,
+                client_cert: None,
+                client_key: None,
+                tls_insecure_skip_verify: false,
+            }
+        }
+
         mod query {
             use super::*;
 
@@ -346,22 +936,34 @@ mod tests {
                     .match_header("Accept", "application/csv")
                     .match_header("Accept-Encoding", "gzip")
                     .match_header("Authorization", "Token sometoken")
-                    .match_header("Content-Type", "application/vnd.flux")
-                    .match_body("some Flux query with somebucket and somemeasurement")
+                    .match_header("Content-Type", "application/json")
+                    .match_body(Matcher::PartialJsonString(
+                        r#"{
+                            "query": "some Flux query with somebucket and somemeasurement",
+                            "dialect": {"annotations": ["datatype", "group", "default"]}
+                        }"#
+                        .to_string(),
+                    ))
             }
 
             #[tokio::test]
             async fn request_send_failure() {
                 let config = Config {
                     influxdb_url: "ftp://example.com".parse().unwrap(),
-                    influxdb_api_token: "sometoken".to_string(),
+                    influxdb_api_token: Some("sometoken".to_string()),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
                     influxdb_org: "someorg".to_string(),
                     influxdb_bucket: "somebucket".to_string(),
                     influxdb_measurement: "somemeasurement".to_string(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
                 };
                 let http_client = HttpClient::new();
-                let client = Client::new(&config, http_client);
-                let result = client.query::<()>(FLUX_QUERY).await;
+                let client = Client::new(&config, http_client, Resilience::disabled());
+                let result = client.query::<()>(FLUX_QUERY, &Default::default()).await;
                 assert!(result.is_err());
             }
 
@@ -374,14 +976,20 @@ mod tests {
                     .await;
                 let config = Config {
                     influxdb_url: server.url().parse().unwrap(),
-                    influxdb_api_token: "sometoken".to_string(),
+                    influxdb_api_token: Some("sometoken".to_string()),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
                     influxdb_org: "someorg".to_string(),
                     influxdb_bucket: "somebucket".to_string(),
                     influxdb_measurement: "somemeasurement".to_string(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
                 };
                 let http_client = HttpClient::new();
-                let client = Client::new(&config, http_client);
-                let result = client.query::<()>(FLUX_QUERY).await;
+                let client = Client::new(&config, http_client, Resilience::disabled());
+                let result = client.query::<()>(FLUX_QUERY, &Default::default()).await;
                 mock.assert_async().await;
                 assert!(result.is_err());
             }
@@ -396,14 +1004,22 @@ mod tests {
                     .await;
                 let config = Config {
                     influxdb_url: server.url().parse().unwrap(),
-                    influxdb_api_token: "sometoken".to_string(),
+                    influxdb_api_token: Some("sometoken".to_string()),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
                     influxdb_org: "someorg".to_string(),
                     influxdb_bucket: "somebucket".to_string(),
                     influxdb_measurement: "somemeasurement".to_string(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
                 };
                 let http_client = HttpClient::new();
-                let client = Client::new(&config, http_client);
-                let result = client.query::<(String, u8)>(FLUX_QUERY).await;
+                let client = Client::new(&config, http_client, Resilience::disabled());
+                let result = client
+                    .query::<(String, u8)>(FLUX_QUERY, &Default::default())
+                    .await;
                 mock.assert_async().await;
                 assert!(result.is_err());
             }
@@ -418,35 +1034,372 @@ mod tests {
                     .await;
                 let config = Config {
                     influxdb_url: server.url().parse().unwrap(),
-                    influxdb_api_token: "sometoken".to_string(),
+                    influxdb_api_token: Some("sometoken".to_string()),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
                     influxdb_org: "someorg".to_string(),
                     influxdb_bucket: "somebucket".to_string(),
                     influxdb_measurement: "somemeasurement".to_string(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
                 };
                 let http_client = HttpClient::new();
-                let client = Client::new(&config, http_client);
-                let rows = client.query::<(String, u8)>(FLUX_QUERY).await.unwrap();
+                let client = Client::new(&config, http_client, Resilience::disabled());
+                let rows = client
+                    .query::<(String, u8)>(FLUX_QUERY, &Default::default())
+                    .await
+                    .unwrap();
                 mock.assert_async().await;
                 assert_eq!(rows, [("one".to_string(), 1), ("two".to_string(), 2)]);
             }
+
+            #[tokio::test]
+            async fn data_source_override() {
+                let mut server = Server::new_async().await;
+                let mock = server
+                    .mock("POST", "/api/v2/query")
+                    .match_query(Matcher::UrlEncoded("org".into(), "otherorg".into()))
+                    .match_header("Authorization", "Token othertoken")
+                    .match_body(Matcher::PartialJsonString(
+                        r#"{"query": "some Flux query with otherbucket and othermeasurement on machine"}"#
+                            .to_string(),
+                    ))
+                    .with_status(200)
+                    .with_body("first_member,second_member\none,1")
+                    .create_async()
+                    .await;
+                let config = Config {
+                    influxdb_url: "http://default-influxdb".parse().unwrap(),
+                    influxdb_api_token: Some("sometoken".to_string()),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
+                    influxdb_org: "someorg".to_string(),
+                    influxdb_bucket: "somebucket".to_string(),
+                    influxdb_measurement: "somemeasurement".to_string(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
+                };
+                let http_client = HttpClient::new();
+                let client = Client::new(&config, http_client, Resilience::disabled())
+                    .with_data_sources(
+                        &[named_data_source("plant2", &server.url())],
+                        Timeouts::default(),
+                    )
+                    .unwrap();
+                let data_source = DataSource {
+                    name: Some("plant2".to_string()),
+                    bucket: Some("otherbucket".parse().unwrap()),
+                    measurement: Some("othermeasurement".parse().unwrap()),
+                    schema: Schema {
+                        id_tag: Some("machine".parse().unwrap()),
+                        ..Default::default()
+                    },
+                };
+                let rows = client
+                    .query::<(String, u8)>(
+                        &format!("{FLUX_QUERY} on __idtagplaceholder__"),
+                        &data_source,
+                    )
+                    .await
+                    .unwrap();
+                mock.assert_async().await;
+                assert_eq!(rows, [("one".to_string(), 1)]);
+            }
+
+            #[tokio::test]
+            async fn data_source_circuit_breaker() {
+                let mut server = Server::new_async().await;
+                let mock = server_mock(&mut server)
+                    .with_status(200)
+                    .with_body("first_member,second_member\none,1")
+                    .create_async()
+                    .await;
+                let mut failing_server = Server::new_async().await;
+                let failing_mock = failing_server
+                    .mock("POST", "/api/v2/query")
+                    .match_query(Matcher::Any)
+                    .with_status(503)
+                    .expect(1)
+                    .create_async()
+                    .await;
+                let config = Config {
+                    influxdb_url: server.url().parse().unwrap(),
+                    influxdb_api_token: Some("sometoken".to_string()),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
+                    influxdb_org: "someorg".to_string(),
+                    influxdb_bucket: "somebucket".to_string(),
+                    influxdb_measurement: "somemeasurement".to_string(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
+                };
+                let http_client = HttpClient::new();
+                let client = Client::new(&config, http_client, Resilience::breaking(1))
+                    .with_data_sources(
+                        &[named_data_source("plant2", &failing_server.url())],
+                        Timeouts::default(),
+                    )
+                    .unwrap();
+                let data_source = DataSource {
+                    name: Some("plant2".to_string()),
+                    ..Default::default()
+                };
+                for failure in [Failure::Transient, Failure::CircuitOpen] {
+                    let result = client.query::<(String, u8)>(FLUX_QUERY, &data_source).await;
+                    assert_eq!(result, Err(failure));
+                }
+                let circuit_breakers = client.data_source_circuit_breakers();
+                assert_eq!(circuit_breakers["plant2"].state(), BreakerState::Open);
+                let rows = client
+                    .query::<(String, u8)>(FLUX_QUERY, &Default::default())
+                    .await
+                    .unwrap();
+                assert_eq!(rows, [("one".to_string(), 1)]);
+                mock.assert_async().await;
+                failing_mock.assert_async().await;
+            }
+
+            #[tokio::test]
+            async fn unknown_data_source() {
+                let config = Config {
+                    influxdb_url: "http://default-influxdb".parse().unwrap(),
+                    influxdb_api_token: Some("sometoken".to_string()),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
+                    influxdb_org: "someorg".to_string(),
+                    influxdb_bucket: "somebucket".to_string(),
+                    influxdb_measurement: "somemeasurement".to_string(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
+                };
+                let http_client = HttpClient::new();
+                let client = Client::new(&config, http_client, Resilience::disabled());
+                let data_source = DataSource {
+                    name: Some("plant2".to_string()),
+                    ..Default::default()
+                };
+                let result = client.query::<(String, u8)>(FLUX_QUERY, &data_source).await;
+                assert_eq!(result, Err(Failure::Permanent));
+            }
+        }
+
+        mod handle_token_file {
+            use super::*;
+            use crate::testing::temp_path;
+
+            fn config(token_file: Option<PathBuf>) -> Config {
+                Config {
+                    influxdb_url: "http://example.com".parse().unwrap(),
+                    influxdb_api_token: None,
+                    influxdb_api_token_file: token_file,
+                    influxdb_api_token_reload_interval: time::Duration::from_millis(10).into(),
+                    influxdb_org: Default::default(),
+                    influxdb_bucket: Default::default(),
+                    influxdb_measurement: Default::default(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
+                }
+            }
+
+            #[test]
+            fn not_configured() {
+                let config = config(None);
+                let client = Client::new(&config, HttpClient::new(), Resilience::disabled());
+                assert!(client.handle_token_file().unwrap().is_none());
+            }
+
+            #[test]
+            fn missing_file() {
+                let config = config(Some("/nonexistent/token".into()));
+                let client = Client::new(&config, HttpClient::new(), Resilience::disabled());
+                assert!(client.handle_token_file().is_err());
+            }
+
+            #[tokio::test]
+            async fn reload() {
+                let path = temp_path("token");
+                fs::write(&path, "firsttoken\n").unwrap();
+                let config = config(Some(path.clone()));
+                let client = Client::new(&config, HttpClient::new(), Resilience::disabled());
+                let task = client.handle_token_file().unwrap().unwrap();
+                assert_eq!(
+                    client.default_instance.auth_header.borrow().as_ref(),
+                    "Token firsttoken"
+                );
+
+                fs::write(&path, "").unwrap();
+                tokio::time::sleep(time::Duration::from_millis(30)).await;
+                assert_eq!(
+                    client.default_instance.auth_header.borrow().as_ref(),
+                    "Token firsttoken"
+                );
+
+                fs::write(&path, "secondtoken\n").unwrap();
+                tokio::time::sleep(time::Duration::from_millis(30)).await;
+                assert_eq!(
+                    client.default_instance.auth_header.borrow().as_ref(),
+                    "Token secondtoken"
+                );
+
+                drop(client);
+                tokio::time::timeout(time::Duration::from_millis(100), task)
+                    .await
+                    .expect("task should terminate")
+                    .unwrap();
+                fs::remove_file(&path).unwrap();
+            }
+
+            #[tokio::test]
+            async fn data_source() {
+                let path = temp_path("data-source-token");
+                fs::write(&path, "firsttoken\n").unwrap();
+                let data_source = NamedDataSource {
+                    api_token: None,
+                    api_token_file: Some(path.clone()),
+                    ..named_data_source("plant2", "http://plant2-influxdb")
+                };
+                let client = Client::new(&config(None), HttpClient::new(), Resilience::disabled())
+                    .with_data_sources(&[data_source], Timeouts::default())
+                    .unwrap();
+                let task = client.handle_token_file().unwrap().unwrap();
+                let instance = &client.instances["plant2"];
+                assert_eq!(instance.auth_header.borrow().as_ref(), "Token firsttoken");
+
+                fs::write(&path, "secondtoken\n").unwrap();
+                tokio::time::sleep(time::Duration::from_millis(30)).await;
+                assert_eq!(instance.auth_header.borrow().as_ref(), "Token secondtoken");
+
+                drop(client);
+                tokio::time::timeout(time::Duration::from_millis(100), task)
+                    .await
+                    .expect("task should terminate")
+                    .unwrap();
+                fs::remove_file(&path).unwrap();
+            }
+        }
+
+        mod handle_flux_queries {
+            use futures_util::stream;
+            use tokio::sync::mpsc;
+
+            use super::*;
+            use crate::testing::temp_path;
+
+            const TIMELINE: &str =
+                "__bucketplaceholder__ __idplaceholder__ __targetcycletimeplaceholder__";
+
+            fn config(flux_queries_dir: Option<PathBuf>) -> Config {
+                Config {
+                    influxdb_url: "http://example.com".parse().unwrap(),
+                    influxdb_api_token: Default::default(),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
+                    influxdb_org: Default::default(),
+                    influxdb_bucket: Default::default(),
+                    influxdb_measurement: Default::default(),
+                    schema: Default::default(),
+                    flux_queries_dir,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
+                }
+            }
+
+            #[test]
+            fn not_configured() {
+                let config = config(None);
+                let client = Client::new(&config, HttpClient::new(), Resilience::disabled());
+                let reload = stream::pending();
+                assert!(client.handle_flux_queries(reload).unwrap().is_none());
+                assert_eq!(**client.templates.borrow(), Templates::default());
+            }
+
+            #[test]
+            fn invalid_dir() {
+                let config = config(Some("/nonexistent".into()));
+                let client = Client::new(&config, HttpClient::new(), Resilience::disabled());
+                assert!(client.handle_flux_queries(stream::pending()).is_err());
+            }
+
+            #[tokio::test]
+            async fn reload() {
+                let dir = temp_path("flux-reload");
+                fs::create_dir_all(&dir).unwrap();
+                let config = config(Some(dir.clone()));
+                let client = Client::new(&config, HttpClient::new(), Resilience::disabled());
+                let (reload_tx, mut reload_rx) = mpsc::unbounded_channel();
+                let reload = stream::poll_fn(move |cx| reload_rx.poll_recv(cx));
+                let task = client.handle_flux_queries(reload).unwrap().unwrap();
+                assert_eq!(**client.templates.borrow(), Templates::default());
+
+                fs::write(dir.join("timeline.flux"), TIMELINE).unwrap();
+                reload_tx.send(()).unwrap();
+                tokio::time::sleep(time::Duration::from_millis(50)).await;
+                let template = client.templates.borrow().get(Template::Timeline);
+                assert_eq!(template.as_ref(), TIMELINE);
+
+                fs::write(dir.join("timeline.flux"), "invalid").unwrap();
+                reload_tx.send(()).unwrap();
+                tokio::time::sleep(time::Duration::from_millis(50)).await;
+                let template = client.templates.borrow().get(Template::Timeline);
+                assert_eq!(template.as_ref(), TIMELINE);
+
+                drop(client);
+                tokio::time::timeout(time::Duration::from_millis(100), task)
+                    .await
+                    .expect("task should terminate")
+                    .unwrap();
+                fs::remove_dir_all(&dir).unwrap();
+            }
         }
 
         mod handle_health {
             use super::*;
 
+            fn config() -> Config {
+                Config {
+                    influxdb_url: "http://example.com".parse().unwrap(),
+                    influxdb_api_token: None,
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
+                    influxdb_org: "someorg".to_string(),
+                    influxdb_bucket: "somebucket".to_string(),
+                    influxdb_measurement: "somemeasurement".to_string(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
+                }
+            }
+
             #[tokio::test]
             async fn request_send_failure() {
                 let config = Config {
                     influxdb_url: "ftp://example.com".parse().unwrap(),
-                    influxdb_api_token: "sometoken".to_string(),
+                    influxdb_api_token: Some("sometoken".to_string()),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
                     influxdb_org: "someorg".to_string(),
                     influxdb_bucket: "somebucket".to_string(),
                     influxdb_measurement: "somemeasurement".to_string(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
                 };
                 let http_client = HttpClient::new();
-                let client = Client::new(&config, http_client);
-                let (health_channel, task) = client.handle_health();
-                assert!(health_channel.roundtrip(()).await.is_err());
+                let client = Client::new(&config, http_client, Resilience::disabled());
+                let (health_channel, task) = client.handle_health(NonZeroUsize::MIN);
+                assert!(health_channel.roundtrip(None).await.is_err());
                 assert!(!task.is_finished());
             }
 
@@ -461,14 +1414,20 @@ mod tests {
                 let config = Config {
                     influxdb_url: server.url().parse().unwrap(),
                     influxdb_api_token: Default::default(),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
                     influxdb_org: Default::default(),
                     influxdb_bucket: Default::default(),
                     influxdb_measurement: Default::default(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
                 };
                 let http_client = HttpClient::new();
-                let client = Client::new(&config, http_client);
-                let (health_channel, task) = client.handle_health();
-                let status_code = health_channel.roundtrip(()).await.unwrap();
+                let client = Client::new(&config, http_client, Resilience::disabled());
+                let (health_channel, task) = client.handle_health(NonZeroUsize::MIN);
+                let status_code = health_channel.roundtrip(None).await.unwrap();
                 assert_eq!(status_code, 503);
                 mock.assert_async().await;
                 assert!(!task.is_finished());
@@ -485,18 +1444,55 @@ mod tests {
                 let config = Config {
                     influxdb_url: server.url().parse().unwrap(),
                     influxdb_api_token: Default::default(),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
                     influxdb_org: Default::default(),
                     influxdb_bucket: Default::default(),
                     influxdb_measurement: Default::default(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
                 };
                 let http_client = HttpClient::new();
-                let client = Client::new(&config, http_client);
-                let (health_channel, task) = client.handle_health();
-                let status_code = health_channel.roundtrip(()).await.unwrap();
+                let client = Client::new(&config, http_client, Resilience::disabled());
+                let (health_channel, task) = client.handle_health(NonZeroUsize::MIN);
+                let status_code = health_channel.roundtrip(None).await.unwrap();
                 assert_eq!(status_code, 200);
                 mock.assert_async().await;
                 assert!(!task.is_finished());
             }
+
+            #[tokio::test]
+            async fn data_source() {
+                let mut server = Server::new_async().await;
+                let mock = server
+                    .mock("GET", "/health")
+                    .with_status(503)
+                    .create_async()
+                    .await;
+                let data_source = named_data_source("plant2", &server.url());
+                let client = Client::new(&config(), HttpClient::new(), Resilience::disabled())
+                    .with_data_sources(&[data_source], Timeouts::default())
+                    .unwrap();
+                let (health_channel, task) = client.handle_health(NonZeroUsize::MIN);
+                let status_code = health_channel
+                    .roundtrip(Some("plant2".to_string()))
+                    .await
+                    .unwrap();
+                assert_eq!(status_code, 503);
+                mock.assert_async().await;
+                assert!(!task.is_finished());
+            }
+
+            #[tokio::test]
+            async fn unknown_data_source() {
+                let client = Client::new(&config(), HttpClient::new(), Resilience::disabled());
+                let (health_channel, task) = client.handle_health(NonZeroUsize::MIN);
+                let reply = health_channel.roundtrip(Some("plant2".to_string())).await;
+                assert!(reply.is_err());
+                assert!(!task.is_finished());
+            }
         }
 
         mod handle_timeline {
@@ -510,7 +1506,7 @@ mod tests {
                     .match_query(Matcher::UrlEncoded("org".into(), "".into()))
                     .match_body(Matcher::AllOf(vec![
                         Matcher::Regex(r"stoppedTime = 1\.2 \*".to_string()),
-                        Matcher::Regex(r#"r\.id == "someid""#.to_string()),
+                        Matcher::Regex(r#"r\.id == \\"someid\\""#.to_string()),
                     ]))
             }
 
@@ -524,18 +1520,29 @@ mod tests {
                 let config = Config {
                     influxdb_url: server.url().parse().unwrap(),
                     influxdb_api_token: Default::default(),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
                     influxdb_org: Default::default(),
                     influxdb_bucket: Default::default(),
                     influxdb_measurement: Default::default(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
                 };
                 let http_client = HttpClient::new();
-                let client = Client::new(&config, http_client);
+                let client = Client::new(&config, http_client, Resilience::disabled());
                 let request = TimelineRequest {
                     id: "someid".to_string(),
                     target_cycle_time: 1.2,
+                    data_source: Default::default(),
+                    bypass_cache: false,
                 };
-                let (timeline_channel, task) = client.handle_timeline();
-                assert!(timeline_channel.roundtrip(request).await.is_err());
+                let (timeline_channel, task) = client.handle_timeline(NonZeroUsize::MIN);
+                assert!(matches!(
+                    timeline_channel.roundtrip(request).await,
+                    Ok(Err(Failure::Transient))
+                ));
                 mock.assert_async().await;
                 assert!(!task.is_finished());
             }
@@ -551,18 +1558,26 @@ mod tests {
                 let config = Config {
                     influxdb_url: server.url().parse().unwrap(),
                     influxdb_api_token: Default::default(),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
                     influxdb_org: Default::default(),
                     influxdb_bucket: Default::default(),
                     influxdb_measurement: Default::default(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
                 };
                 let http_client = HttpClient::new();
-                let client = Client::new(&config, http_client);
+                let client = Client::new(&config, http_client, Resilience::disabled());
                 let request = TimelineRequest {
                     id: "someid".to_string(),
                     target_cycle_time: 1.2,
+                    data_source: Default::default(),
+                    bypass_cache: false,
                 };
-                let (timeline_channel, task) = client.handle_timeline();
-                let slots = timeline_channel.roundtrip(request).await.unwrap();
+                let (timeline_channel, task) = client.handle_timeline(NonZeroUsize::MIN);
+                let slots = timeline_channel.roundtrip(request).await.unwrap().unwrap();
                 assert_eq!(slots.into_inner(), vec![]);
                 mock.assert_async().await;
                 assert!(!task.is_finished());
@@ -591,18 +1606,26 @@ mod tests {
                 let config = Config {
                     influxdb_url: server.url().parse().unwrap(),
                     influxdb_api_token: Default::default(),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
                     influxdb_org: Default::default(),
                     influxdb_bucket: Default::default(),
                     influxdb_measurement: Default::default(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
                 };
                 let http_client = HttpClient::new();
-                let client = Client::new(&config, http_client);
+                let client = Client::new(&config, http_client, Resilience::disabled());
                 let request = TimelineRequest {
                     id: "someid".to_string(),
                     target_cycle_time: 1.2,
+                    data_source: Default::default(),
+                    bypass_cache: false,
                 };
-                let (timeline_channel, task) = client.handle_timeline();
-                let slots = timeline_channel.roundtrip(request).await.unwrap();
+                let (timeline_channel, task) = client.handle_timeline(NonZeroUsize::MIN);
+                let slots = timeline_channel.roundtrip(request).await.unwrap().unwrap();
                 assert_eq!(
                     slots.into_inner(),
                     [
@@ -627,6 +1650,84 @@ mod tests {
                 mock.assert_async().await;
                 assert!(!task.is_finished());
             }
+
+            #[tokio::test]
+            async fn cache_hit() {
+                let mut server = Server::new_async().await;
+                let mock = server_mock(&mut server)
+                    .with_status(200)
+                    .with_body("_time,color\n1984-12-09T04:30:00Z,1\n")
+                    .expect(1)
+                    .create_async()
+                    .await;
+                let config = Config {
+                    influxdb_url: server.url().parse().unwrap(),
+                    influxdb_api_token: Default::default(),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
+                    influxdb_org: Default::default(),
+                    influxdb_bucket: Default::default(),
+                    influxdb_measurement: Default::default(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: std::time::Duration::from_secs(60).into(),
+                    performance_cache_expiration: Default::default(),
+                };
+                let http_client = HttpClient::new();
+                let client = Client::new(&config, http_client, Resilience::disabled());
+                let (timeline_channel, task) = client.handle_timeline(NonZeroUsize::MIN);
+                for _ in 0..3 {
+                    let request = TimelineRequest {
+                        id: "someid".to_string(),
+                        target_cycle_time: 1.2,
+                        data_source: Default::default(),
+                        bypass_cache: false,
+                    };
+                    let slots = timeline_channel.roundtrip(request).await.unwrap().unwrap();
+                    assert_eq!(slots.into_inner().len(), 1);
+                }
+                mock.assert_async().await;
+                assert!(!task.is_finished());
+            }
+
+            #[tokio::test]
+            async fn cache_bypass() {
+                let mut server = Server::new_async().await;
+                let mock = server_mock(&mut server)
+                    .with_status(200)
+                    .with_body("_time,color\n1984-12-09T04:30:00Z,1\n")
+                    .expect(2)
+                    .create_async()
+                    .await;
+                let config = Config {
+                    influxdb_url: server.url().parse().unwrap(),
+                    influxdb_api_token: Default::default(),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
+                    influxdb_org: Default::default(),
+                    influxdb_bucket: Default::default(),
+                    influxdb_measurement: Default::default(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: std::time::Duration::from_secs(60).into(),
+                    performance_cache_expiration: Default::default(),
+                };
+                let http_client = HttpClient::new();
+                let client = Client::new(&config, http_client, Resilience::disabled());
+                let (timeline_channel, task) = client.handle_timeline(NonZeroUsize::MIN);
+                for bypass_cache in [false, true, false] {
+                    let request = TimelineRequest {
+                        id: "someid".to_string(),
+                        target_cycle_time: 1.2,
+                        data_source: Default::default(),
+                        bypass_cache,
+                    };
+                    let slots = timeline_channel.roundtrip(request).await.unwrap().unwrap();
+                    assert_eq!(slots.into_inner().len(), 1);
+                }
+                mock.assert_async().await;
+                assert!(!task.is_finished());
+            }
         }
 
         mod handle_performance {
@@ -642,7 +1743,7 @@ mod tests {
                     .mock("POST", "/api/v2/query")
                     .match_query(Matcher::UrlEncoded("org".into(), "".into()))
                     .match_body(Matcher::AllOf(vec![
-                        Matcher::Regex(r#"r\.id == "otherid""#.to_string()),
+                        Matcher::Regex(r#"r\.id == \\"otherid\\""#.to_string()),
                         Matcher::Regex(r"range\(start: 1984-12-09T00:00:00\+02:00".to_string()),
                     ]))
             }
@@ -669,21 +1770,32 @@ mod tests {
                 let config = Config {
                     influxdb_url: server.url().parse().unwrap(),
                     influxdb_api_token: Default::default(),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
                     influxdb_org: Default::default(),
                     influxdb_bucket: Default::default(),
                     influxdb_measurement: Default::default(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
                 };
                 let http_client = HttpClient::new();
-                let client = Client::new(&config, http_client);
+                let client = Client::new(&config, http_client, Resilience::disabled());
                 let request = PerformanceRequest {
                     id: "otherid".to_string(),
                     shift_start_times: shift_start_times(),
                     pauses: pauses(),
                     timezone: GMTMinus2,
                     target_cycle_time: 21.3,
+                    data_source: Default::default(),
+                    bypass_cache: false,
                 };
-                let (performance_channel, task) = client.handle_performance();
-                assert!(performance_channel.roundtrip(request).await.is_err());
+                let (performance_channel, task) = client.handle_performance(NonZeroUsize::MIN);
+                assert_eq!(
+                    performance_channel.roundtrip(request).await,
+                    Ok(Err(Failure::Transient))
+                );
                 mock.assert_async().await;
                 assert!(!task.is_finished());
             }
@@ -700,21 +1812,33 @@ mod tests {
                 let config = Config {
                     influxdb_url: server.url().parse().unwrap(),
                     influxdb_api_token: Default::default(),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
                     influxdb_org: Default::default(),
                     influxdb_bucket: Default::default(),
                     influxdb_measurement: Default::default(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
                 };
                 let http_client = HttpClient::new();
-                let client = Client::new(&config, http_client);
+                let client = Client::new(&config, http_client, Resilience::disabled());
                 let request = PerformanceRequest {
                     id: "otherid".to_string(),
                     shift_start_times: shift_start_times(),
                     pauses: pauses(),
                     timezone: GMTMinus2,
                     target_cycle_time: 21.3,
+                    data_source: Default::default(),
+                    bypass_cache: false,
                 };
-                let (performance_channel, task) = client.handle_performance();
-                let performance_ratio = performance_channel.roundtrip(request).await.unwrap();
+                let (performance_channel, task) = client.handle_performance(NonZeroUsize::MIN);
+                let performance_ratio = performance_channel
+                    .roundtrip(request)
+                    .await
+                    .unwrap()
+                    .unwrap();
                 assert!(performance_ratio.is_nan());
                 mock.assert_async().await;
                 assert!(!task.is_finished());
@@ -740,25 +1864,257 @@ mod tests {
                 let config = Config {
                     influxdb_url: server.url().parse().unwrap(),
                     influxdb_api_token: Default::default(),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
                     influxdb_org: Default::default(),
                     influxdb_bucket: Default::default(),
                     influxdb_measurement: Default::default(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
                 };
                 let http_client = HttpClient::new();
-                let client = Client::new(&config, http_client);
+                let client = Client::new(&config, http_client, Resilience::disabled());
                 let request = PerformanceRequest {
                     id: "otherid".to_string(),
                     shift_start_times: shift_start_times(),
                     pauses: pauses(),
                     timezone: GMTMinus2,
                     target_cycle_time: 21.3,
+                    data_source: Default::default(),
+                    bypass_cache: false,
                 };
-                let (performance_channel, task) = client.handle_performance();
-                let performance_ratio = performance_channel.roundtrip(request).await.unwrap();
+                let (performance_channel, task) = client.handle_performance(NonZeroUsize::MIN);
+                let performance_ratio = performance_channel
+                    .roundtrip(request)
+                    .await
+                    .unwrap()
+                    .unwrap();
                 assert!(60.0 < performance_ratio && performance_ratio < 60.1);
                 mock.assert_async().await;
                 assert!(!task.is_finished());
             }
         }
+
+        mod handle_custom {
+            use chrono_tz::Etc::GMTMinus2;
+            use indoc::indoc;
+
+            use crate::time::override_now;
+
+            use super::*;
+
+            fn server_mock(server: &mut Server) -> Mock {
+                server
+                    .mock("POST", "/api/v2/query")
+                    .match_query(Matcher::UrlEncoded("org".into(), "".into()))
+                    .match_body(Matcher::AllOf(vec![
+                        Matcher::Regex(r#"r\.id == \\"otherid\\""#.to_string()),
+                        Matcher::Regex(r"range\(start: 1984-12-09T00:00:00\+02:00".to_string()),
+                    ]))
+            }
+
+            fn client(server: &Server) -> Client {
+                let config = Config {
+                    influxdb_url: server.url().parse().unwrap(),
+                    influxdb_api_token: Default::default(),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
+                    influxdb_org: Default::default(),
+                    influxdb_bucket: Default::default(),
+                    influxdb_measurement: Default::default(),
+                    schema: Default::default(),
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
+                };
+                Client::new(&config, HttpClient::new(), Resilience::disabled())
+            }
+
+            fn request(reducer: Reducer) -> CustomRequest {
+                CustomRequest {
+                    id: "otherid".to_string(),
+                    query: r#"range(start: __startplaceholder__) r.id == "__idplaceholder__""#
+                        .into(),
+                    reducer,
+                    shift_start_times: vec![
+                        "00:00:00".parse().unwrap(),
+                        "12:00:00".parse().unwrap(),
+                    ],
+                    timezone: GMTMinus2,
+                    data_source: Default::default(),
+                }
+            }
+
+            #[tokio::test]
+            async fn query_error() {
+                override_now(Some("1984-12-09T02:30:00Z".parse().unwrap()));
+                let mut server = Server::new_async().await;
+                let mock = server_mock(&mut server)
+                    .with_status(500)
+                    .create_async()
+                    .await;
+                let request = request(Reducer::Sum {
+                    column: "_value".to_string(),
+                });
+                let (custom_channel, task) = client(&server).handle_custom(NonZeroUsize::MIN);
+                assert_eq!(
+                    custom_channel.roundtrip(request).await,
+                    Ok(Err(Failure::Transient))
+                );
+                mock.assert_async().await;
+                assert!(!task.is_finished());
+            }
+
+            #[tokio::test]
+            async fn reducer_error() {
+                override_now(Some("1984-12-09T02:30:00Z".parse().unwrap()));
+                let mut server = Server::new_async().await;
+                let mock = server_mock(&mut server)
+                    .with_status(200)
+                    .with_body("_value\nabc\n")
+                    .create_async()
+                    .await;
+                let request = request(Reducer::Sum {
+                    column: "_value".to_string(),
+                });
+                let (custom_channel, task) = client(&server).handle_custom(NonZeroUsize::MIN);
+                assert_eq!(
+                    custom_channel.roundtrip(request).await,
+                    Ok(Err(Failure::Permanent))
+                );
+                mock.assert_async().await;
+                assert!(!task.is_finished());
+            }
+
+            #[tokio::test]
+            async fn success() {
+                override_now(Some("1984-12-09T02:30:00Z".parse().unwrap()));
+                const BODY: &str = indoc! {"
+                    scrap,total
+                    2,10
+                    3,30
+                "};
+                let mut server = Server::new_async().await;
+                let mock = server_mock(&mut server)
+                    .with_status(200)
+                    .with_body(BODY)
+                    .create_async()
+                    .await;
+                let request = request(Reducer::Ratio {
+                    numerator: "scrap".to_string(),
+                    denominator: "total".to_string(),
+                });
+                let (custom_channel, task) = client(&server).handle_custom(NonZeroUsize::MIN);
+                let value = custom_channel.roundtrip(request).await.unwrap().unwrap();
+                assert_eq!(value, Some(0.125));
+                mock.assert_async().await;
+                assert!(!task.is_finished());
+            }
+        }
+
+        mod handle_discovery {
+            use super::*;
+
+            fn client(server: &Server) -> Client {
+                let config = Config {
+                    influxdb_url: server.url().parse().unwrap(),
+                    influxdb_api_token: Default::default(),
+                    influxdb_api_token_file: None,
+                    influxdb_api_token_reload_interval: Default::default(),
+                    influxdb_org: Default::default(),
+                    influxdb_bucket: "somebucket".to_string(),
+                    influxdb_measurement: "somemeasurement".to_string(),
+                    schema: Schema {
+                        id_tag: Some("machine".parse().unwrap()),
+                        ..Default::default()
+                    },
+                    flux_queries_dir: None,
+                    timeline_cache_expiration: Default::default(),
+                    performance_cache_expiration: Default::default(),
+                };
+                Client::new(&config, HttpClient::new(), Resilience::disabled())
+            }
+
+            #[tokio::test]
+            async fn query_error() {
+                let mut server = Server::new_async().await;
+                let mock = server
+                    .mock("POST", "/api/v2/query")
+                    .match_query(Matcher::Any)
+                    .with_status(500)
+                    .create_async()
+                    .await;
+                let (discovery_channel, task) = client(&server).handle_discovery(NonZeroUsize::MIN);
+                assert_eq!(
+                    discovery_channel.roundtrip(Default::default()).await,
+                    Ok(Err(Failure::Transient))
+                );
+                mock.assert_async().await;
+                assert!(!task.is_finished());
+            }
+
+            #[tokio::test]
+            async fn success() {
+                let mut server = Server::new_async().await;
+                let mock = server
+                    .mock("POST", "/api/v2/query")
+                    .match_query(Matcher::Any)
+                    .match_body(Matcher::AllOf(vec![
+                        Matcher::Regex(r#"bucket: \\"somebucket\\""#.to_string()),
+                        Matcher::Regex(r#"measurement: \\"somemeasurement\\""#.to_string()),
+                        Matcher::Regex(r#"tag: \\"machine\\""#.to_string()),
+                    ]))
+                    .with_status(200)
+                    .with_body("_value\nid1\nid2\n")
+                    .create_async()
+                    .await;
+                let (discovery_channel, task) = client(&server).handle_discovery(NonZeroUsize::MIN);
+                let partners = discovery_channel
+                    .roundtrip(Default::default())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(partners, ["id1", "id2"]);
+                mock.assert_async().await;
+                assert!(!task.is_finished());
+            }
+
+            #[tokio::test]
+            async fn data_source_override() {
+                let mut server = Server::new_async().await;
+                let mock = server
+                    .mock("POST", "/api/v2/query")
+                    .match_query(Matcher::Any)
+                    .match_body(Matcher::AllOf(vec![
+                        Matcher::Regex(r#"bucket: \\"otherbucket\\""#.to_string()),
+                        Matcher::Regex(r#"measurement: \\"othermeasurement\\""#.to_string()),
+                        Matcher::Regex(r#"tag: \\"station\\""#.to_string()),
+                    ]))
+                    .with_status(200)
+                    .with_body("_value\nid3\n")
+                    .create_async()
+                    .await;
+                let (discovery_channel, task) = client(&server).handle_discovery(NonZeroUsize::MIN);
+                let data_source = DataSource {
+                    name: None,
+                    bucket: Some("otherbucket".parse().unwrap()),
+                    measurement: Some("othermeasurement".parse().unwrap()),
+                    schema: Schema {
+                        id_tag: Some("station".parse().unwrap()),
+                        ..Default::default()
+                    },
+                };
+                let partners = discovery_channel
+                    .roundtrip(data_source)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(partners, ["id3"]);
+                mock.assert_async().await;
+                assert!(!task.is_finished());
+            }
+        }
     }
 }
@@ -1,21 +1,41 @@
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
+
 use anyhow::Context as _;
 use clap::Parser;
 use clap_verbosity_flag::{InfoLevel, Verbosity};
-use futures_util::StreamExt;
+use futures_util::{StreamExt, stream};
 use influxdb_compute_api::CommonArgs;
 use signal_hook::consts::TERM_SIGNALS;
 use signal_hook::low_level::signal_name;
 use signal_hook_tokio::Signals;
 use tokio::net::TcpListener;
-use tracing::{Instrument, error, info, info_span, instrument};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info, info_span, instrument, warn};
 
+mod annotated_csv;
+mod api_error;
+mod cache;
+mod cache_token;
 mod channel;
 mod config_api;
+mod config_file;
+mod custom_endpoints;
+mod data_sources;
+mod flux;
 mod headers;
 mod http_api;
 mod influxdb;
+mod negotiation;
 mod production_objective;
+mod reload;
+mod sse;
+#[cfg(test)]
+mod testing;
 mod time;
+mod tls;
+mod upstream;
 
 #[derive(Parser)]
 struct Args {
@@ -25,9 +45,40 @@ struct Args {
     #[command(flatten)]
     config_api: config_api::Config,
 
+    #[command(flatten)]
+    config_api_tls: config_api::TlsConfig,
+
+    #[command(flatten)]
+    config_api_auth: config_api::AuthConfig,
+
+    #[command(flatten)]
+    config_api_events: config_api::EventsConfig,
+
+    #[command(flatten)]
+    config_file: config_file::Config,
+
+    #[command(flatten)]
+    config_cache_token: cache_token::Config,
+
     #[command(flatten)]
     influxdb: influxdb::Config,
 
+    #[command(flatten)]
+    influxdb_tls: influxdb::TlsConfig,
+
+    #[command(flatten)]
+    data_sources: data_sources::Config,
+
+    #[command(flatten)]
+    upstream: upstream::Config,
+
+    #[command(flatten)]
+    custom_endpoints: custom_endpoints::Config,
+
+    /// Maximum number of requests processed concurrently by each handler
+    #[arg(env, long, default_value = "8")]
+    handler_concurrency_limit: NonZeroUsize,
+
     #[command(flatten)]
     verbosity: Verbosity<InfoLevel>,
 }
@@ -49,34 +100,160 @@ async fn main() -> anyhow::Result<()> {
         .with_max_level(args.verbosity)
         .init();
 
-    let http_client = reqwest::Client::new();
+    let timeouts = args
+        .upstream
+        .timeouts()
+        .context("error setting up upstream timeouts")?;
+    let config_api_http_client = tls::http_client(
+        "configuration API",
+        &args.config_api_tls.options(),
+        timeouts,
+    )
+    .context("error setting up configuration API TLS")?;
+    let config_api_events_http_client = tls::http_client(
+        "configuration API events",
+        &args.config_api_tls.options(),
+        upstream::Timeouts {
+            request: None,
+            ..timeouts
+        },
+    )
+    .context("error setting up configuration API TLS")?;
+    let influxdb_http_client = tls::http_client("InfluxDB", &args.influxdb_tls.options(), timeouts)
+        .context("error setting up InfluxDB TLS")?;
+    let custom_endpoints =
+        custom_endpoints::load(&args.custom_endpoints).context("error loading custom endpoints")?;
+    let data_sources =
+        data_sources::load(&args.data_sources).context("error loading InfluxDB data sources")?;
+    let data_source_names = data_sources
+        .iter()
+        .map(|data_source| data_source.name.clone())
+        .collect::<HashSet<_>>();
+    let concurrency_limit = args.handler_concurrency_limit;
 
-    let config_api_client = config_api::Client::new(&args.config_api, http_client.clone());
-    let (common_config_channel, common_config_task) = config_api_client.handle_common_config();
-    let (partner_config_channel, partner_config_task) = config_api_client.handle_partner_config();
+    let config_api_resilience = upstream::Resilience::new(&args.upstream, "config_api");
+    let config_api_circuit_breaker = config_api_resilience.circuit_breaker();
+    let shutdown = CancellationToken::new();
+    let config_file_provider = config_file::Provider::load(&args.config_file, &data_source_names)
+        .context("error loading configuration file")?;
+    let config_api_client = config_api::Client::new(
+        &args.config_api,
+        config_api_http_client,
+        config_api_resilience,
+    )
+    .map(|client| client.with_credentials(&args.config_api_auth));
+    let (
+        (config_api_status_channel, config_api_status_task),
+        (common_config_channel, common_config_task),
+        (partner_config_channel, partner_config_task),
+        (partners_channel, partners_task),
+        config_cache_invalidator,
+        config_updates_task,
+        config_api_credentials_task,
+    ) = match (config_file_provider, config_api_client) {
+        (Some(provider), _) => (
+            provider.handle_status(concurrency_limit),
+            provider.handle_common_config(concurrency_limit),
+            provider.handle_partner_config(concurrency_limit),
+            provider.handle_partners(concurrency_limit),
+            None,
+            Some(provider.handle_reload()),
+            None,
+        ),
+        (None, Some(client)) => (
+            // Checks the URL before any handler is started.
+            client
+                .handle_status(concurrency_limit)
+                .context("invalid configuration API URL")?,
+            client.handle_common_config(concurrency_limit),
+            client.handle_partner_config(concurrency_limit),
+            client.handle_partners(concurrency_limit),
+            Some(client.cache_invalidator()),
+            client.handle_events(
+                &args.config_api_events,
+                config_api_events_http_client,
+                shutdown.clone(),
+            ),
+            client
+                .handle_credentials(shutdown.clone())
+                .context("error reading configuration API credentials")?,
+        ),
+        (None, None) => {
+            unreachable!("configuration API URL is required without configuration file")
+        }
+    };
 
-    let influxdb_client = influxdb::Client::new(&args.influxdb, http_client);
-    let (health_channel, health_task) = influxdb_client.handle_health();
-    let (timeline_channel, timeline_task) = influxdb_client.handle_timeline();
-    let (performance_channel, performance_task) = influxdb_client.handle_performance();
+    let influxdb_resilience = upstream::Resilience::new(&args.upstream, "influxdb");
+    let influxdb_circuit_breaker = influxdb_resilience.circuit_breaker();
+    let influxdb_client =
+        influxdb::Client::new(&args.influxdb, influxdb_http_client, influxdb_resilience)
+            .with_data_sources(&data_sources, timeouts)
+            .context("error setting up InfluxDB data sources")?;
+    let data_source_circuit_breakers = influxdb_client.data_source_circuit_breakers();
+    let token_file_task = influxdb_client
+        .handle_token_file()
+        .context("error reading InfluxDB API token file")?;
+    // SIGHUP keeps its default action unless Flux queries are to be reloaded.
+    let flux_queries_reload = if args.influxdb.reloads_flux_queries() {
+        let mut hangup =
+            signal(SignalKind::hangup()).context("error registering Flux queries reload signal")?;
+        stream::poll_fn(move |cx| hangup.poll_recv(cx)).boxed()
+    } else {
+        stream::pending().boxed()
+    };
+    let flux_queries_task = influxdb_client
+        .handle_flux_queries(flux_queries_reload)
+        .context("error loading Flux queries")?;
+    let (health_channel, health_task) = influxdb_client.handle_health(concurrency_limit);
+    let (timeline_channel, timeline_task) = influxdb_client.handle_timeline(concurrency_limit);
+    let (performance_channel, performance_task) =
+        influxdb_client.handle_performance(concurrency_limit);
+    let (custom_channel, custom_task) = influxdb_client.handle_custom(concurrency_limit);
+    let (discovery_channel, discovery_task) = influxdb_client.handle_discovery(concurrency_limit);
+    // Lets the token file and Flux queries tasks terminate along with the handlers.
+    drop(influxdb_client);
 
     let production_objective = production_objective::ProductionObjective;
     let (shift_objective_channel, shift_objective_task) =
-        production_objective.handle_shift_objective();
+        production_objective.handle_shift_objective(concurrency_limit);
     let (week_objective_channel, week_objective_task) =
-        production_objective.handle_week_objective();
+        production_objective.handle_week_objective(concurrency_limit);
+
+    let config_cache_token = cache_token::CacheToken::new(&args.config_cache_token);
+    let config_cache_token_task = match &config_cache_token {
+        Some(token) => token
+            .handle_token_file()
+            .context("error reading configuration cache token file")?,
+        None => None,
+    };
+    if config_cache_invalidator.is_some() && config_cache_token.is_none() {
+        warn!(
+            msg = "configuration cache and webhook routes disabled, no configuration cache token"
+        );
+    }
 
     let signals = Signals::new(TERM_SIGNALS).context("error registering termination signals")?;
     let signals_handle = signals.handle();
 
     let app = http_api::app(http_api::AppState {
         health_channel,
+        config_api_status_channel,
         common_config_channel,
         partner_config_channel,
+        partners_channel,
+        discovery_channel,
         timeline_channel,
         performance_channel,
         shift_objective_channel,
         week_objective_channel,
+        custom_channel,
+        custom_endpoints: custom_endpoints.into(),
+        data_sources: data_source_names.into(),
+        influxdb_circuit_breaker,
+        data_source_circuit_breakers: data_source_circuit_breakers.into(),
+        config_api_circuit_breaker,
+        config_cache_invalidator,
+        config_cache_token,
     });
     async move {
         let listener = match TcpListener::bind(&args.common.listen_address).await {
@@ -101,17 +278,48 @@ async fn main() -> anyhow::Result<()> {
     .await;
 
     signals_handle.close();
+    shutdown.cancel();
 
     tokio::try_join!(
         common_config_task,
         partner_config_task,
+        partners_task,
+        config_api_status_task,
         health_task,
         timeline_task,
         performance_task,
         shift_objective_task,
         week_objective_task,
+        custom_task,
+        discovery_task,
     )
     .context("error joining tasks")?;
 
+    if let Some(config_updates_task) = config_updates_task {
+        config_updates_task
+            .await
+            .context("error joining configuration updates task")?;
+    }
+    if let Some(config_api_credentials_task) = config_api_credentials_task {
+        config_api_credentials_task
+            .await
+            .context("error joining configuration API credentials task")?;
+    }
+    if let Some(config_cache_token_task) = config_cache_token_task {
+        config_cache_token_task
+            .await
+            .context("error joining configuration cache token task")?;
+    }
+    if let Some(token_file_task) = token_file_task {
+        token_file_task
+            .await
+            .context("error joining token file task")?;
+    }
+    if let Some(flux_queries_task) = flux_queries_task {
+        flux_queries_task
+            .await
+            .context("error joining Flux queries task")?;
+    }
+
     Ok(())
 }
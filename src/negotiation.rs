@@ -0,0 +1,276 @@
+use std::convert::Infallible;
+use std::io;
+
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{HeaderValue, header};
+use axum::response::{IntoResponse, Response};
+use mime::Mime;
+use serde::Serialize;
+
+use crate::api_error::ApiError;
+
+/// Response body format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Format {
+    Json,
+    MessagePack,
+    Csv,
+}
+
+impl Format {
+    /// Media types of the format, the first one being the canonical one.
+    fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Self::Json => &["application/json"],
+            Self::MessagePack => &[
+                "application/msgpack",
+                "application/x-msgpack",
+                "application/vnd.msgpack",
+            ],
+            Self::Csv => &["text/csv"],
+        }
+    }
+
+    fn content_type(self) -> HeaderValue {
+        match self {
+            Self::Json => HeaderValue::from_static("application/json"),
+            Self::MessagePack => HeaderValue::from_static("application/msgpack"),
+            Self::Csv => HeaderValue::from_static("text/csv; charset=utf-8"),
+        }
+    }
+
+    /// Media type of the format, as listed in `406 Not Acceptable` errors.
+    pub(crate) fn media_type(self) -> &'static str {
+        self.media_types()[0]
+    }
+}
+
+/// Formats offered by tabular and scalar bodies.
+pub(crate) const TABULAR_FORMATS: &[Format] = &[Format::Json, Format::MessagePack, Format::Csv];
+
+/// Formats offered by object bodies, which have no CSV representation.
+pub(crate) const OBJECT_FORMATS: &[Format] = &[Format::Json, Format::MessagePack];
+
+/// Response body, encoded in the format negotiated with the client.
+pub(crate) trait Body: Serialize {
+    /// Formats the body can be encoded in, the first one being the default.
+    const FORMATS: &'static [Format];
+
+    /// Encodes the body as MessagePack, structs being maps by default.
+    fn to_msgpack(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+        rmp_serde::to_vec_named(self)
+    }
+
+    /// Writes the body as CSV records, a single one by default.
+    fn write_csv<W: io::Write>(&self, writer: &mut csv::Writer<W>) -> csv::Result<()> {
+        writer.serialize(self)
+    }
+}
+
+impl Body for f32 {
+    const FORMATS: &'static [Format] = TABULAR_FORMATS;
+}
+
+impl Body for Option<f64> {
+    const FORMATS: &'static [Format] = TABULAR_FORMATS;
+}
+
+impl<T: Serialize> Body for Vec<T> {
+    const FORMATS: &'static [Format] = TABULAR_FORMATS;
+
+    /// Writes a record per element.
+    fn write_csv<W: io::Write>(&self, writer: &mut csv::Writer<W>) -> csv::Result<()> {
+        self.iter().try_for_each(|record| writer.serialize(record))
+    }
+}
+
+/// Media ranges accepted by the client, from the `Accept` header.
+pub(crate) struct Accept(Vec<(Mime, f32)>);
+
+impl<S: Send + Sync> FromRequestParts<S> for Accept {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ranges = parts
+            .headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|range| range.trim().parse::<Mime>().ok())
+            .map(|range| {
+                let quality = range
+                    .get_param("q")
+                    .and_then(|quality| quality.as_str().parse().ok())
+                    .unwrap_or(1.0);
+                (range, quality)
+            })
+            .collect();
+        Ok(Self(ranges))
+    }
+}
+
+impl Accept {
+    /// Returns the quality given to a media type by its most specific
+    /// matching range, if any.
+    fn quality(&self, media_type: &Mime) -> Option<f32> {
+        self.0
+            .iter()
+            .filter_map(|(range, quality)| {
+                let specificity = if range.type_() == mime::STAR {
+                    0
+                } else if range.type_() != media_type.type_() {
+                    return None;
+                } else if range.subtype() == mime::STAR {
+                    1
+                } else if range.subtype() == media_type.subtype() {
+                    2
+                } else {
+                    return None;
+                };
+                Some((specificity, *quality))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, quality)| quality)
+    }
+
+    /// Picks the body format preferred by the client, ties going to the
+    /// body default format. Without `Accept` header, the default is used.
+    pub(crate) fn negotiate<T: Body>(&self) -> Result<Format, ApiError> {
+        if self.0.is_empty() {
+            return Ok(T::FORMATS[0]);
+        }
+        T::FORMATS
+            .iter()
+            .filter_map(|&format| {
+                let quality = format
+                    .media_types()
+                    .iter()
+                    .filter_map(|media_type| self.quality(&media_type.parse().unwrap()))
+                    .reduce(f32::max)?;
+                Some((format, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .reduce(|best, candidate| {
+                if candidate.1 > best.1 {
+                    candidate
+                } else {
+                    best
+                }
+            })
+            .map(|(format, _)| format)
+            .ok_or_else(|| ApiError::not_acceptable(T::FORMATS))
+    }
+}
+
+/// Body encoded in the negotiated format.
+pub(crate) struct Negotiated<T> {
+    pub(crate) format: Format,
+    pub(crate) body: T,
+}
+
+impl<T: Body> Negotiated<T> {
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        match self.format {
+            Format::Json => serde_json::to_vec(&self.body).map_err(|err| err.to_string()),
+            Format::MessagePack => self.body.to_msgpack().map_err(|err| err.to_string()),
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                self.body
+                    .write_csv(&mut writer)
+                    .map_err(|err| err.to_string())?;
+                writer.into_inner().map_err(|err| err.to_string())
+            }
+        }
+    }
+}
+
+impl<T: Body> IntoResponse for Negotiated<T> {
+    fn into_response(self) -> Response {
+        match self.encode() {
+            Ok(buf) => (
+                [
+                    (header::CONTENT_TYPE, self.format.content_type()),
+                    (header::VARY, HeaderValue::from_static("accept")),
+                ],
+                buf,
+            )
+                .into_response(),
+            Err(err) => ApiError::internal(format!("response encoding: {err}")).into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+
+    use super::*;
+
+    async fn negotiate<T: Body>(accept: Option<&str>) -> Result<Format, ApiError> {
+        let mut req = Request::builder();
+        if let Some(accept) = accept {
+            req = req.header("accept", accept);
+        }
+        let (mut parts, ()) = req.body(()).unwrap().into_parts();
+        let Ok(accept) = Accept::from_request_parts(&mut parts, &()).await;
+        accept.negotiate::<T>()
+    }
+
+    #[tokio::test]
+    async fn default_format() {
+        assert_eq!(negotiate::<f32>(None).await.unwrap(), Format::Json);
+        assert_eq!(negotiate::<f32>(Some("*/*")).await.unwrap(), Format::Json);
+    }
+
+    #[tokio::test]
+    async fn preferred_format() {
+        let format = negotiate::<f32>(Some("application/msgpack")).await.unwrap();
+        assert_eq!(format, Format::MessagePack);
+        let format = negotiate::<f32>(Some("application/x-msgpack"))
+            .await
+            .unwrap();
+        assert_eq!(format, Format::MessagePack);
+        let format = negotiate::<f32>(Some("text/*")).await.unwrap();
+        assert_eq!(format, Format::Csv);
+    }
+
+    #[tokio::test]
+    async fn quality_values() {
+        let accept = "application/json;q=0.5, text/csv, */*;q=0.1";
+        assert_eq!(negotiate::<f32>(Some(accept)).await.unwrap(), Format::Csv);
+        let accept = "*/*, application/json;q=0";
+        let format = negotiate::<f32>(Some(accept)).await.unwrap();
+        assert_eq!(format, Format::MessagePack);
+    }
+
+    #[tokio::test]
+    async fn not_acceptable() {
+        assert!(negotiate::<f32>(Some("text/html")).await.is_err());
+        assert!(
+            negotiate::<f32>(Some("application/json;q=0"))
+                .await
+                .is_err()
+        );
+        assert!(negotiate::<()>(Some("text/csv")).await.is_err());
+    }
+
+    #[test]
+    fn csv_encoding() {
+        let negotiated = Negotiated {
+            format: Format::Csv,
+            body: vec![(1, Some(2)), (3, None)],
+        };
+        assert_eq!(negotiated.encode().unwrap(), b"1,2\n3,\n");
+        let negotiated = Negotiated {
+            format: Format::Csv,
+            body: 42.5_f32,
+        };
+        assert_eq!(negotiated.encode().unwrap(), b"42.5\n");
+    }
+
+    impl Body for () {
+        const FORMATS: &'static [Format] = OBJECT_FORMATS;
+    }
+}
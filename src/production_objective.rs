@@ -1,12 +1,14 @@
 use std::iter;
+use std::num::NonZeroUsize;
 
 use chrono::{Datelike, Days, NaiveDateTime, NaiveTime};
 use chrono_tz::Tz;
 use serde::Serialize;
 use tokio::task::JoinHandle;
-use tracing::{Instrument, error, info, info_span};
+use tracing::{Instrument, info, info_span};
+use utoipa::ToSchema;
 
-use crate::channel::{RoundtripSender, roundtrip_channel};
+use crate::channel::{RoundtripSender, process_concurrently, roundtrip_channel};
 use crate::config_api::WeekStart;
 use crate::time::{apply_time_spans, find_shift_bounds, utc_now};
 
@@ -18,7 +20,7 @@ pub(crate) struct ShiftObjectiveRequest {
     pub(crate) target_efficiency: f32,
 }
 
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
 pub(crate) struct ObjectivePoint {
     #[serde(rename = "t")]
     pub(crate) timestamp: i64,
@@ -96,29 +98,32 @@ impl NaivePoints {
 pub(crate) struct ProductionObjective;
 
 impl ProductionObjective {
-    pub(crate) fn handle_shift_objective(&self) -> (ShiftObjectiveChannel, JoinHandle<()>) {
-        let (tx, mut rx) = roundtrip_channel::<ShiftObjectiveRequest, ObjectiveData>(10);
+    pub(crate) fn handle_shift_objective(
+        &self,
+        concurrency_limit: NonZeroUsize,
+    ) -> (ShiftObjectiveChannel, JoinHandle<()>) {
+        let (tx, rx) = roundtrip_channel::<ShiftObjectiveRequest, ObjectiveData>(10);
 
         let task = tokio::spawn(
             async move {
                 info!(status = "started");
 
-                while let Some((request, _, reply_tx)) = rx.recv().await {
+                process_concurrently(rx, concurrency_limit, |request: ShiftObjectiveRequest| {
                     let shift_span =
                         find_shift_bounds(&request.timezone, &request.shift_start_times);
-                    let shift_start = shift_span.0.naive_local();
-                    let shift_end = shift_span.1.naive_local();
-                    let mut naive_points = NaivePoints::new(
-                        shift_start,
-                        request.target_cycle_time,
-                        request.target_efficiency,
-                    );
-                    naive_points.push_shift(shift_end, true, &request.pauses);
-                    let objective_points = naive_points.into_objective_data(request.timezone);
-                    if reply_tx.send(objective_points).is_err() {
-                        error!(kind = "response channel sending");
+                    async move {
+                        let shift_start = shift_span.0.naive_local();
+                        let shift_end = shift_span.1.naive_local();
+                        let mut naive_points = NaivePoints::new(
+                            shift_start,
+                            request.target_cycle_time,
+                            request.target_efficiency,
+                        );
+                        naive_points.push_shift(shift_end, true, &request.pauses);
+                        Some(naive_points.into_objective_data(request.timezone))
                     }
-                }
+                })
+                .await;
 
                 info!(status = "terminating");
             }
@@ -128,51 +133,56 @@ impl ProductionObjective {
         (tx, task)
     }
 
-    pub(crate) fn handle_week_objective(&self) -> (WeekObjectiveChannel, JoinHandle<()>) {
-        let (tx, mut rx) = roundtrip_channel::<WeekObjectiveRequest, ObjectiveData>(10);
+    pub(crate) fn handle_week_objective(
+        &self,
+        concurrency_limit: NonZeroUsize,
+    ) -> (WeekObjectiveChannel, JoinHandle<()>) {
+        let (tx, rx) = roundtrip_channel::<WeekObjectiveRequest, ObjectiveData>(10);
 
         let task = tokio::spawn(
             async move {
                 info!(status = "started");
 
-                while let Some((request, _, reply_tx)) = rx.recv().await {
+                process_concurrently(rx, concurrency_limit, |request: WeekObjectiveRequest| {
                     let now_naive = utc_now().with_timezone(&request.timezone).date_naive();
-                    let week_start_day = {
-                        let now_weekday = now_naive.weekday().num_days_from_monday();
-                        let week_start_weekday = request.week_start.day.num_days_from_monday();
-                        let days_back = now_weekday - week_start_weekday;
-                        now_naive - Days::new(days_back.into())
-                    };
-                    let mut shifts_iter = request
-                        .shift_start_times
-                        .iter()
-                        .cycle()
-                        .enumerate()
-                        // The first member of the tuple item becomes the number of days to add.
-                        .map(|(i, shift_start)| (i / request.shift_start_times.len(), shift_start))
-                        .skip(request.week_start.shift_index)
-                        .zip(iter::once(true).chain(request.shift_engaged))
-                        .map(|((days_to_add, &shift_start_time), engaged)| {
-                            let date_time = week_start_day
-                                .checked_add_days(Days::new(days_to_add as u64))
-                                .unwrap()
-                                .and_time(shift_start_time);
-                            (date_time, engaged)
-                        });
-                    let (first_datetime, _) = shifts_iter.next().unwrap();
-                    let mut naive_points = NaivePoints::new(
-                        first_datetime,
-                        request.target_cycle_time,
-                        request.target_efficiency,
-                    );
-                    for (shift_end, engaged) in shifts_iter {
-                        naive_points.push_shift(shift_end, engaged, &request.pauses);
+                    async move {
+                        let week_start_day = {
+                            let now_weekday = now_naive.weekday().num_days_from_monday();
+                            let week_start_weekday = request.week_start.day.num_days_from_monday();
+                            let days_back = now_weekday - week_start_weekday;
+                            now_naive - Days::new(days_back.into())
+                        };
+                        let mut shifts_iter = request
+                            .shift_start_times
+                            .iter()
+                            .cycle()
+                            .enumerate()
+                            // The first member of the tuple item becomes the number of days to add.
+                            .map(|(i, shift_start)| {
+                                (i / request.shift_start_times.len(), shift_start)
+                            })
+                            .skip(request.week_start.shift_index)
+                            .zip(iter::once(true).chain(request.shift_engaged))
+                            .map(|((days_to_add, &shift_start_time), engaged)| {
+                                let date_time = week_start_day
+                                    .checked_add_days(Days::new(days_to_add as u64))
+                                    .unwrap()
+                                    .and_time(shift_start_time);
+                                (date_time, engaged)
+                            });
+                        let (first_datetime, _) = shifts_iter.next().unwrap();
+                        let mut naive_points = NaivePoints::new(
+                            first_datetime,
+                            request.target_cycle_time,
+                            request.target_efficiency,
+                        );
+                        for (shift_end, engaged) in shifts_iter {
+                            naive_points.push_shift(shift_end, engaged, &request.pauses);
+                        }
+                        Some(naive_points.into_objective_data(request.timezone))
                     }
-                    let objective_points = naive_points.into_objective_data(request.timezone);
-                    if reply_tx.send(objective_points).is_err() {
-                        error!(kind = "response channel sending");
-                    }
-                }
+                })
+                .await;
 
                 info!(status = "terminating");
             }
@@ -224,7 +234,7 @@ mod tests {
                 target_efficiency: 0.8,
             };
             let actor = ProductionObjective;
-            let (channel, task) = actor.handle_shift_objective();
+            let (channel, task) = actor.handle_shift_objective(NonZeroUsize::MIN);
             let points = channel.roundtrip(request).await.unwrap();
             assert_eq!(
                 points,
@@ -269,7 +279,7 @@ mod tests {
                 target_efficiency: 1.0,
             };
             let actor = ProductionObjective;
-            let (channel, task) = actor.handle_shift_objective();
+            let (channel, task) = actor.handle_shift_objective(NonZeroUsize::MIN);
             let points = channel.roundtrip(request).await.unwrap();
             assert_eq!(
                 points,
@@ -308,7 +318,7 @@ mod tests {
                 target_efficiency: 1.0,
             };
             let actor = ProductionObjective;
-            let (channel, task) = actor.handle_week_objective();
+            let (channel, task) = actor.handle_week_objective(NonZeroUsize::MIN);
             let points = channel.roundtrip(request).await.unwrap();
             assert_eq!(
                 points,
@@ -383,7 +393,7 @@ mod tests {
                 target_efficiency: 1.0,
             };
             let actor = ProductionObjective;
-            let (channel, task) = actor.handle_week_objective();
+            let (channel, task) = actor.handle_week_objective(NonZeroUsize::MIN);
             let points = channel.roundtrip(request).await.unwrap();
             assert_eq!(
                 points,
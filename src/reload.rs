@@ -0,0 +1,84 @@
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context as _, bail};
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info};
+
+/// Reads a secret from a file, trimming surrounding whitespace.
+pub(crate) fn read_secret(path: &Path) -> anyhow::Result<String> {
+    let secret =
+        fs::read_to_string(path).with_context(|| format!("error reading {}", path.display()))?;
+    let secret = secret.trim();
+    if secret.is_empty() {
+        bail!("{} is empty", path.display());
+    }
+    Ok(secret.to_string())
+}
+
+/// Reads a value every `interval`, publishing it whenever it changes, until
+/// all receivers have been dropped. Read errors are logged, the last value
+/// being kept.
+pub(crate) async fn poll_changes<T, E>(
+    tx: &watch::Sender<T>,
+    interval: Duration,
+    subject: &str,
+    mut read: impl FnMut() -> Result<T, E>,
+) where
+    T: PartialEq,
+    E: Display,
+{
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = tx.closed() => break,
+            _ = interval.tick() => {}
+        }
+        match read() {
+            Ok(value) => {
+                let changed = tx.send_if_modified(|current| {
+                    let changed = *current != value;
+                    *current = value;
+                    changed
+                });
+                if changed {
+                    info!(msg = "reloaded", subject);
+                }
+            }
+            Err(err) => {
+                error!(kind = "reloading", subject, err = %err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn poll() {
+        let (tx, rx) = watch::channel(0);
+        let mut reads = [Ok(1), Err("unreadable"), Ok(1), Ok(2)].into_iter();
+        let task = tokio::spawn(async move {
+            poll_changes(&tx, Duration::from_millis(10), "test", || {
+                reads.next().unwrap_or(Ok(2))
+            })
+            .await;
+        });
+        tokio::time::sleep(Duration::from_millis(15)).await;
+        assert_eq!(*rx.borrow(), 1);
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(*rx.borrow(), 2);
+        drop(rx);
+        tokio::time::timeout(Duration::from_millis(100), task)
+            .await
+            .expect("task should terminate")
+            .unwrap();
+    }
+}
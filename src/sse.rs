@@ -0,0 +1,136 @@
+use std::mem;
+
+use tracing::warn;
+
+/// Maximum length of a line, and of the data of an event, longer ones being
+/// discarded for a misbehaving server not to exhaust memory.
+const MAX_LENGTH: usize = 64 * 1024;
+
+/// Incremental server-sent events parser, fed with chunks of an event stream
+/// and only retaining event data.
+#[derive(Default)]
+pub(crate) struct EventParser {
+    line: Vec<u8>,
+    /// Whether the current line is too long, and discarded.
+    line_discarded: bool,
+    /// Whether the last line ended with a carriage return, a following line
+    /// feed being part of the same line ending.
+    after_cr: bool,
+    data: Option<String>,
+    /// Whether the data of the current event is too long, and discarded.
+    data_discarded: bool,
+}
+
+impl EventParser {
+    /// Feeds a chunk of the stream, returning the data of completed events.
+    /// Lines may end with a line feed, a carriage return, or both.
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        let mut events = Vec::new();
+        for &byte in chunk {
+            let after_cr = mem::replace(&mut self.after_cr, byte == b'\r');
+            match byte {
+                b'\n' if after_cr => {}
+                b'\n' | b'\r' => self.end_line(&mut events),
+                _ if self.line.len() < MAX_LENGTH => self.line.push(byte),
+                _ => self.line_discarded = true,
+            }
+        }
+        events
+    }
+
+    fn end_line(&mut self, events: &mut Vec<String>) {
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        if mem::take(&mut self.line_discarded) {
+            warn!(msg = "discarding too long event stream line");
+            return;
+        }
+        if line.is_empty() {
+            if mem::take(&mut self.data_discarded) {
+                warn!(msg = "discarding too long event data");
+            }
+            events.extend(self.data.take());
+            return;
+        }
+        let (field, value) = line.split_once(':').unwrap_or((&line, ""));
+        if field != "data" || self.data_discarded {
+            return;
+        }
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match self.data.as_mut() {
+            Some(data) if data.len() + value.len() >= MAX_LENGTH => {
+                self.data = None;
+                self.data_discarded = true;
+            }
+            Some(data) => {
+                data.push('\n');
+                data.push_str(value);
+            }
+            None => self.data = Some(value.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_event() {
+        let mut parser = EventParser::default();
+        assert_eq!(
+            parser.push(b"data: {\"kind\":\"common\"}\n\n"),
+            [r#"{"kind":"common"}"#]
+        );
+    }
+
+    #[test]
+    fn split_chunks() {
+        let mut parser = EventParser::default();
+        assert!(parser.push(b"event: change\r\nda").is_empty());
+        assert!(parser.push(b"ta:first\r\ndata: second\r\n").is_empty());
+        assert_eq!(parser.push(b"\r\n"), ["first\nsecond"]);
+    }
+
+    #[test]
+    fn carriage_returns() {
+        let mut parser = EventParser::default();
+        assert!(parser.push(b"data: first\rdata: second\r").is_empty());
+        assert_eq!(parser.push(b"\r"), ["first\nsecond"]);
+        assert_eq!(parser.push(b"data: third\r\n\r\n"), ["third"]);
+    }
+
+    #[test]
+    fn split_line_ending() {
+        let mut parser = EventParser::default();
+        assert!(parser.push(b"data: value\r").is_empty());
+        assert!(parser.push(b"\n").is_empty());
+        assert_eq!(parser.push(b"\r\n"), ["value"]);
+    }
+
+    #[test]
+    fn too_long_line() {
+        let mut parser = EventParser::default();
+        let mut stream = b"data: ".to_vec();
+        stream.extend([b'a'; MAX_LENGTH]);
+        stream.extend(b"\n\ndata: value\n\n");
+        assert_eq!(parser.push(&stream), ["value"]);
+        assert!(parser.line.capacity() <= MAX_LENGTH);
+    }
+
+    #[test]
+    fn too_long_data() {
+        let mut parser = EventParser::default();
+        let line = format!("data: {}\n", "a".repeat(MAX_LENGTH / 2));
+        assert!(parser.push(line.repeat(3).as_bytes()).is_empty());
+        assert!(parser.data.is_none());
+        assert_eq!(parser.push(b"\ndata: value\n\n"), ["value"]);
+    }
+
+    #[test]
+    fn ignored_lines() {
+        let mut parser = EventParser::default();
+        let events = parser.push(b": keep-alive\n\nid: 1\nretry: 10\n\ndata: value\n\n");
+        assert_eq!(events, ["value"]);
+    }
+}
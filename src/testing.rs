@@ -0,0 +1,6 @@
+use std::path::PathBuf;
+
+/// Returns a path in the temporary directory, unique to the test process.
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}-{name}", std::process::id()))
+}
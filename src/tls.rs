@@ -0,0 +1,134 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context as _, ensure};
+use reqwest::{Certificate, Client as HttpClient, Identity};
+use tracing::warn;
+
+use crate::upstream::Timeouts;
+
+/// TLS settings of an upstream connection.
+#[derive(Default)]
+pub(crate) struct TlsOptions<'a> {
+    pub(crate) ca_cert: Option<&'a Path>,
+    pub(crate) client_cert: Option<&'a Path>,
+    pub(crate) client_key: Option<&'a Path>,
+    pub(crate) insecure_skip_verify: bool,
+}
+
+fn read_file(path: &Path) -> anyhow::Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("error reading {}", path.display()))
+}
+
+/// Builds an HTTP client for an upstream, with the given TLS settings and
+/// timeouts. Disabling certificate verification is logged as a warning naming
+/// the upstream.
+pub(crate) fn http_client(
+    upstream: &str,
+    options: &TlsOptions,
+    timeouts: Timeouts,
+) -> anyhow::Result<HttpClient> {
+    let mut builder = HttpClient::builder();
+
+    if let Some(timeout) = timeouts.connect {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = timeouts.request {
+        builder = builder.timeout(timeout);
+    }
+
+    if let Some(path) = options.ca_cert {
+        let certs = Certificate::from_pem_bundle(&read_file(path)?)
+            .with_context(|| format!("error parsing CA bundle {}", path.display()))?;
+        ensure!(!certs.is_empty(), "no certificate in {}", path.display());
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    if let (Some(cert_path), Some(key_path)) = (options.client_cert, options.client_key) {
+        let mut pem = read_file(cert_path)?;
+        pem.push(b'\n');
+        pem.extend(read_file(key_path)?);
+        let identity = Identity::from_pem(&pem).with_context(|| {
+            format!(
+                "error loading client identity from {} and {}",
+                cert_path.display(),
+                key_path.display()
+            )
+        })?;
+        builder = builder.identity(identity);
+    }
+
+    if options.insecure_skip_verify {
+        warn!(msg = "TLS certificate verification disabled", upstream);
+        builder = builder.tls_danger_accept_invalid_certs(true);
+    }
+
+    builder.build().context("error building HTTP client")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::testing::temp_path;
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = temp_path(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn default_options() {
+        assert!(http_client("upstream", &TlsOptions::default(), Timeouts::default()).is_ok());
+    }
+
+    #[test]
+    fn insecure_skip_verify() {
+        let options = TlsOptions {
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        assert!(http_client("upstream", &options, Timeouts::default()).is_ok());
+    }
+
+    #[test]
+    fn missing_ca_cert() {
+        let options = TlsOptions {
+            ca_cert: Some(Path::new("/nonexistent/ca.pem")),
+            ..Default::default()
+        };
+        let err = http_client("upstream", &options, Timeouts::default()).unwrap_err();
+        assert_eq!(err.to_string(), "error reading /nonexistent/ca.pem");
+    }
+
+    #[test]
+    fn empty_ca_bundle() {
+        let path = temp_file("empty-ca.pem", "not a certificate\n");
+        let options = TlsOptions {
+            ca_cert: Some(&path),
+            ..Default::default()
+        };
+        let result = http_client("upstream", &options, Timeouts::default());
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn invalid_client_identity() {
+        let cert_path = temp_file("client.crt", "not a certificate\n");
+        let key_path = temp_file("client.key", "not a key\n");
+        let options = TlsOptions {
+            client_cert: Some(&cert_path),
+            client_key: Some(&key_path),
+            ..Default::default()
+        };
+        let result = http_client("upstream", &options, Timeouts::default());
+        fs::remove_file(&cert_path).unwrap();
+        fs::remove_file(&key_path).unwrap();
+        assert!(result.is_err());
+    }
+}
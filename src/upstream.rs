@@ -0,0 +1,668 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::ensure;
+use clap::Args;
+use reqwest::StatusCode;
+use serde::Serialize;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::channel::RECEIVE_TIMEOUT;
+
+#[derive(Args)]
+#[group(skip)]
+pub(crate) struct Config {
+    /// Maximum number of retries of failed idempotent upstream requests
+    #[arg(env, long, default_value = "2")]
+    upstream_max_retries: u32,
+
+    /// Base delay of the jittered exponential backoff between retries
+    #[arg(env, long, default_value = "50ms")]
+    upstream_retry_base_delay: humantime::Duration,
+
+    /// Consecutive upstream failures opening the circuit breaker (zero disables it)
+    #[arg(env, long, default_value = "5")]
+    circuit_breaker_threshold: u32,
+
+    /// Time the circuit breaker stays open before probing the upstream again
+    #[arg(env, long, default_value = "30s")]
+    circuit_breaker_reset_timeout: humantime::Duration,
+
+    /// Timeout of connections to upstreams, including the TLS handshake
+    #[arg(env, long, default_value = "400ms")]
+    upstream_connect_timeout: humantime::Duration,
+
+    /// Timeout of each upstream request attempt, smaller than the 500ms handler timeout
+    #[arg(env, long, default_value = "450ms")]
+    upstream_request_timeout: humantime::Duration,
+}
+
+impl Config {
+    /// Returns the upstream request timeouts, which must expire before the
+    /// handlers are given up on, for the failure to be reported.
+    pub(crate) fn timeouts(&self) -> anyhow::Result<Timeouts> {
+        let connect = self.upstream_connect_timeout.into();
+        let request = self.upstream_request_timeout.into();
+        for (name, timeout) in [("connect", connect), ("request", request)] {
+            ensure!(
+                timeout < RECEIVE_TIMEOUT,
+                "upstream {name} timeout must be smaller than {}",
+                humantime::Duration::from(RECEIVE_TIMEOUT)
+            );
+        }
+        Ok(Timeouts {
+            connect: Some(connect),
+            request: Some(request),
+        })
+    }
+}
+
+/// Timeouts of the requests to an upstream, none by default.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Timeouts {
+    pub(crate) connect: Option<Duration>,
+    /// Covers the whole request, until the response body is read.
+    pub(crate) request: Option<Duration>,
+}
+
+/// Kind of upstream request failure, already logged at the failure site.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Failure {
+    /// Failure that may succeed on retry (server error, broken response...).
+    Transient,
+    /// Upstream could not be reached, which may change on retry.
+    Unreachable,
+    /// Upstream did not answer in time, which may change on retry.
+    Timeout,
+    /// Failure that will not change on retry (bad request, decoding error...).
+    Permanent,
+    /// Requested resource does not exist upstream.
+    NotFound,
+    /// Request not sent because the circuit breaker is open.
+    CircuitOpen,
+    /// Upstream served a configuration failing the given validation rule.
+    Invalid(&'static str),
+}
+
+impl Failure {
+    /// Classifies an unsuccessful upstream response status code.
+    pub(crate) fn from_status(status_code: StatusCode) -> Self {
+        if status_code.is_server_error() || status_code == StatusCode::TOO_MANY_REQUESTS {
+            Self::Transient
+        } else if status_code == StatusCode::NOT_FOUND {
+            Self::NotFound
+        } else {
+            Self::Permanent
+        }
+    }
+
+    /// Classifies an upstream request sending error.
+    pub(crate) fn from_request_error(err: &reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Self::Timeout
+        } else if err.is_connect() {
+            Self::Unreachable
+        } else {
+            Self::Transient
+        }
+    }
+
+    /// Returns whether the failure may succeed on retry, telling that the
+    /// upstream may be down.
+    pub(crate) fn is_transient(self) -> bool {
+        matches!(self, Self::Transient | Self::Unreachable | Self::Timeout)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::Transient => "transient failure",
+            Self::Unreachable => "unreachable",
+            Self::Timeout => "timed out",
+            Self::Permanent => "permanent failure",
+            Self::NotFound => "not found",
+            Self::CircuitOpen => "circuit breaker open",
+            Self::Invalid(reason) => return write!(f, "invalid configuration: {reason}"),
+        };
+        f.write_str(message)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::HalfOpen => "half-open",
+        };
+        f.write_str(state)
+    }
+}
+
+struct BreakerInner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    changed_at: Instant,
+}
+
+/// Circuit breaker failing fast while an upstream is down.
+///
+/// After `threshold` consecutive failures, the breaker opens and rejects
+/// requests. Once `reset_timeout` has elapsed, a single probe request is let
+/// through: its success closes the breaker, its failure opens it again.
+#[derive(Clone)]
+pub(crate) struct CircuitBreaker {
+    upstream: Arc<str>,
+    threshold: u32,
+    reset_timeout: Duration,
+    inner: Arc<Mutex<BreakerInner>>,
+}
+
+impl CircuitBreaker {
+    fn new(upstream: &str, threshold: u32, reset_timeout: Duration) -> Self {
+        Self {
+            upstream: Arc::from(upstream),
+            threshold,
+            reset_timeout,
+            inner: Arc::new(Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                changed_at: Instant::now(),
+            })),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn disabled() -> Self {
+        Self::new("test", 0, Duration::ZERO)
+    }
+
+    pub(crate) fn state(&self) -> BreakerState {
+        self.inner.lock().unwrap().state
+    }
+
+    fn transition(&self, inner: &mut BreakerInner, state: BreakerState) {
+        if inner.state != state {
+            if state == BreakerState::Open {
+                warn!(msg = "circuit breaker state changed", upstream = &*self.upstream, %state);
+            } else {
+                info!(msg = "circuit breaker state changed", upstream = &*self.upstream, %state);
+            }
+        }
+        inner.state = state;
+        inner.changed_at = Instant::now();
+    }
+
+    /// Returns whether a request may be sent to the upstream.
+    fn acquire(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => true,
+            // A probe that never reported back (e.g. cancelled) does not
+            // keep the breaker half-open forever.
+            BreakerState::Open | BreakerState::HalfOpen
+                if inner.changed_at.elapsed() >= self.reset_timeout =>
+            {
+                self.transition(&mut inner, BreakerState::HalfOpen);
+                true
+            }
+            BreakerState::Open | BreakerState::HalfOpen => false,
+        }
+    }
+
+    fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        if inner.state != BreakerState::Closed {
+            self.transition(&mut inner, BreakerState::Closed);
+        }
+    }
+
+    fn record_failure(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+        if inner.state == BreakerState::HalfOpen || inner.consecutive_failures >= self.threshold {
+            self.transition(&mut inner, BreakerState::Open);
+        }
+    }
+}
+
+/// Retry policy and circuit breaker for an upstream service.
+#[derive(Clone)]
+pub(crate) struct Resilience {
+    max_retries: u32,
+    retry_base_delay: Duration,
+    circuit_breaker: CircuitBreaker,
+}
+
+impl Resilience {
+    pub(crate) fn new(config: &Config, upstream: &str) -> Self {
+        Self {
+            max_retries: config.upstream_max_retries,
+            retry_base_delay: config.upstream_retry_base_delay.into(),
+            circuit_breaker: CircuitBreaker::new(
+                upstream,
+                config.circuit_breaker_threshold,
+                config.circuit_breaker_reset_timeout.into(),
+            ),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn disabled() -> Self {
+        Self {
+            max_retries: 0,
+            retry_base_delay: Duration::ZERO,
+            circuit_breaker: CircuitBreaker::disabled(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn retrying(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            ..Self::disabled()
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn breaking(threshold: u32) -> Self {
+        Self {
+            circuit_breaker: CircuitBreaker::new("test", threshold, Duration::from_secs(60)),
+            ..Self::disabled()
+        }
+    }
+
+    pub(crate) fn circuit_breaker(&self) -> CircuitBreaker {
+        self.circuit_breaker.clone()
+    }
+
+    /// Returns the same retry policy for another upstream, with a circuit
+    /// breaker of its own, for the failures of one upstream not to make
+    /// requests to the other fail fast.
+    pub(crate) fn for_upstream(&self, upstream: &str) -> Self {
+        let breaker = &self.circuit_breaker;
+        Self {
+            circuit_breaker: CircuitBreaker::new(
+                upstream,
+                breaker.threshold,
+                breaker.reset_timeout,
+            ),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the delay before the given retry (starting at zero), with
+    /// jitter in the upper half of the exponential backoff.
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .retry_base_delay
+            .saturating_mul(2_u32.saturating_pow(retry));
+        delay / 2 + delay.mul_f64(rand::random_range(0.0..0.5))
+    }
+
+    /// Runs an idempotent request, retrying transient failures until the
+    /// deadline, each attempt being given up on once it is reached.
+    pub(crate) async fn run<T, F, Fut>(
+        &self,
+        deadline: Instant,
+        mut request: F,
+    ) -> Result<T, Failure>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Failure>>,
+    {
+        let mut retry = 0;
+        loop {
+            if !self.circuit_breaker.acquire() {
+                warn!(
+                    msg = "failing fast",
+                    upstream = &*self.circuit_breaker.upstream,
+                    state = %self.circuit_breaker.state()
+                );
+                return Err(Failure::CircuitOpen);
+            }
+            let budget = deadline.saturating_duration_since(Instant::now());
+            let result = tokio::time::timeout(budget, request())
+                .await
+                .unwrap_or_else(|_| {
+                    warn!(
+                        msg = "attempt reached deadline",
+                        upstream = &*self.circuit_breaker.upstream
+                    );
+                    Err(Failure::Timeout)
+                });
+            match result {
+                Ok(value) => {
+                    self.circuit_breaker.record_success();
+                    return Ok(value);
+                }
+                Err(failure) if failure.is_transient() => {
+                    self.circuit_breaker.record_failure();
+                    if retry >= self.max_retries {
+                        return Err(failure);
+                    }
+                    let delay = self.backoff(retry);
+                    if Instant::now() + delay >= deadline {
+                        info!(
+                            msg = "not retrying past deadline",
+                            upstream = &*self.circuit_breaker.upstream
+                        );
+                        return Err(failure);
+                    }
+                    retry += 1;
+                    info!(
+                        msg = "retrying",
+                        upstream = &*self.circuit_breaker.upstream,
+                        retry,
+                        delay = %humantime::Duration::from(delay)
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                // The upstream answered, it is not down.
+                Err(failure) => {
+                    self.circuit_breaker.record_success();
+                    return Err(failure);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    fn resilience(max_retries: u32, threshold: u32, reset_timeout: Duration) -> Resilience {
+        Resilience {
+            max_retries,
+            retry_base_delay: Duration::from_millis(1),
+            circuit_breaker: CircuitBreaker::new("test", threshold, reset_timeout),
+        }
+    }
+
+    /// Returns the configuration from the command line defaults.
+    fn default_config() -> Config {
+        #[derive(clap::Parser)]
+        struct Args {
+            #[command(flatten)]
+            upstream: Config,
+        }
+        <Args as clap::Parser>::parse_from(["test"]).upstream
+    }
+
+    fn far_deadline() -> Instant {
+        Instant::now() + Duration::from_secs(60)
+    }
+
+    async fn run_failing(resilience: &Resilience, failure: fn() -> Failure) -> (Failure, u32) {
+        let attempts = AtomicU32::new(0);
+        let result = resilience
+            .run(far_deadline(), || async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(failure())
+            })
+            .await;
+        (result.unwrap_err(), attempts.into_inner())
+    }
+
+    #[test]
+    fn timeouts() {
+        let config = |connect: u64, request: u64| Config {
+            upstream_max_retries: 0,
+            upstream_retry_base_delay: Duration::ZERO.into(),
+            circuit_breaker_threshold: 0,
+            circuit_breaker_reset_timeout: Duration::ZERO.into(),
+            upstream_connect_timeout: Duration::from_millis(connect).into(),
+            upstream_request_timeout: Duration::from_millis(request).into(),
+        };
+        let timeouts = config(100, 200).timeouts().unwrap();
+        assert_eq!(timeouts.connect, Some(Duration::from_millis(100)));
+        assert_eq!(timeouts.request, Some(Duration::from_millis(200)));
+        let err = config(100, 500).timeouts().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "upstream request timeout must be smaller than 500ms"
+        );
+    }
+
+    #[test]
+    fn default_timeouts() {
+        let timeouts = default_config().timeouts().unwrap();
+        assert_eq!(timeouts.connect, Some(Duration::from_millis(400)));
+        assert_eq!(timeouts.request, Some(Duration::from_millis(450)));
+    }
+
+    #[test]
+    fn failure_from_status() {
+        assert_eq!(
+            Failure::from_status(StatusCode::BAD_GATEWAY),
+            Failure::Transient
+        );
+        assert_eq!(
+            Failure::from_status(StatusCode::TOO_MANY_REQUESTS),
+            Failure::Transient
+        );
+        assert_eq!(
+            Failure::from_status(StatusCode::BAD_REQUEST),
+            Failure::Permanent
+        );
+        assert_eq!(
+            Failure::from_status(StatusCode::NOT_FOUND),
+            Failure::NotFound
+        );
+    }
+
+    #[test]
+    fn backoff() {
+        let resilience = Resilience {
+            max_retries: 3,
+            retry_base_delay: Duration::from_millis(100),
+            circuit_breaker: CircuitBreaker::disabled(),
+        };
+        for (retry, max_ms) in [(0, 100), (1, 200), (2, 400)] {
+            let delay = resilience.backoff(retry);
+            assert!(delay >= Duration::from_millis(max_ms / 2), "{delay:?}");
+            assert!(delay <= Duration::from_millis(max_ms), "{delay:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let resilience = resilience(2, 0, Duration::ZERO);
+        let (failure, attempts) = run_failing(&resilience, || Failure::Transient).await;
+        assert_eq!(failure, Failure::Transient);
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn retries_timeouts() {
+        let resilience = resilience(2, 0, Duration::ZERO);
+        let (failure, attempts) = run_failing(&resilience, || Failure::Timeout).await;
+        assert_eq!(failure, Failure::Timeout);
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn attempts_given_up_at_deadline() {
+        let resilience = resilience(5, 0, Duration::ZERO);
+        let attempts = AtomicU32::new(0);
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(50);
+        let result = resilience
+            .run(deadline, || async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(40)).await;
+                Err::<(), _>(Failure::Transient)
+            })
+            .await;
+        assert_eq!(result, Err(Failure::Timeout));
+        assert_eq!(attempts.into_inner(), 2);
+        assert!(start.elapsed() < Duration::from_millis(80));
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_past_deadline() {
+        let resilience = Resilience {
+            retry_base_delay: Duration::from_millis(100),
+            ..resilience(2, 0, Duration::ZERO)
+        };
+        let attempts = AtomicU32::new(0);
+        let deadline = Instant::now() + Duration::from_millis(40);
+        let result = resilience
+            .run(deadline, || async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(Failure::Transient)
+            })
+            .await;
+        assert_eq!(result, Err(Failure::Transient));
+        assert_eq!(attempts.into_inner(), 1);
+    }
+
+    #[tokio::test]
+    async fn default_config_retries() {
+        let resilience = Resilience::new(&default_config(), "test");
+        let attempts = AtomicU32::new(0);
+        let result = resilience
+            .run(crate::channel::reply_deadline(), || async {
+                // A slow failure, as an upstream server error may be.
+                attempts.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                Err::<(), _>(Failure::Transient)
+            })
+            .await;
+        assert_eq!(result, Err(Failure::Transient));
+        assert_eq!(attempts.into_inner(), 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_permanent_failures() {
+        let resilience = resilience(2, 0, Duration::ZERO);
+        let (failure, attempts) = run_failing(&resilience, || Failure::Permanent).await;
+        assert_eq!(failure, Failure::Permanent);
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn success_after_retry() {
+        let resilience = resilience(2, 0, Duration::ZERO);
+        let attempts = AtomicU32::new(0);
+        let result = resilience
+            .run(far_deadline(), || async {
+                match attempts.fetch_add(1, Ordering::Relaxed) {
+                    0 => Err(Failure::Transient),
+                    _ => Ok(42),
+                }
+            })
+            .await;
+        assert_eq!(result, Ok(42));
+        assert_eq!(attempts.into_inner(), 2);
+    }
+
+    #[tokio::test]
+    async fn breaker_disabled() {
+        let resilience = resilience(0, 0, Duration::ZERO);
+        for _ in 0..10 {
+            run_failing(&resilience, || Failure::Transient).await;
+        }
+        assert_eq!(resilience.circuit_breaker().state(), BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn breaker_opens_and_fails_fast() {
+        let resilience = resilience(1, 3, Duration::from_secs(60));
+        let (_, attempts) = run_failing(&resilience, || Failure::Transient).await;
+        assert_eq!(attempts, 2);
+        assert_eq!(resilience.circuit_breaker().state(), BreakerState::Closed);
+        let (failure, attempts) = run_failing(&resilience, || Failure::Transient).await;
+        assert_eq!(failure, Failure::CircuitOpen);
+        assert_eq!(attempts, 1);
+        assert_eq!(resilience.circuit_breaker().state(), BreakerState::Open);
+        let (failure, attempts) = run_failing(&resilience, || Failure::Transient).await;
+        assert_eq!(failure, Failure::CircuitOpen);
+        assert_eq!(attempts, 0);
+    }
+
+    #[tokio::test]
+    async fn breaker_permanent_failures_count_as_success() {
+        let resilience = resilience(0, 2, Duration::from_secs(60));
+        for _ in 0..5 {
+            run_failing(&resilience, || Failure::Transient).await;
+            run_failing(&resilience, || Failure::Permanent).await;
+        }
+        assert_eq!(resilience.circuit_breaker().state(), BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn breaker_probe_failure() {
+        let resilience = resilience(0, 1, Duration::from_millis(10));
+        run_failing(&resilience, || Failure::Transient).await;
+        assert_eq!(resilience.circuit_breaker().state(), BreakerState::Open);
+        tokio::time::sleep(Duration::from_millis(15)).await;
+        let (failure, attempts) = run_failing(&resilience, || Failure::Transient).await;
+        assert_eq!(failure, Failure::Transient);
+        assert_eq!(attempts, 1);
+        assert_eq!(resilience.circuit_breaker().state(), BreakerState::Open);
+        let (failure, attempts) = run_failing(&resilience, || Failure::Transient).await;
+        assert_eq!(failure, Failure::CircuitOpen);
+        assert_eq!(attempts, 0);
+    }
+
+    #[tokio::test]
+    async fn breaker_probe_success() {
+        let resilience = resilience(0, 1, Duration::from_millis(10));
+        run_failing(&resilience, || Failure::Transient).await;
+        assert_eq!(resilience.circuit_breaker().state(), BreakerState::Open);
+        tokio::time::sleep(Duration::from_millis(15)).await;
+        let breaker = resilience.circuit_breaker();
+        let result = resilience
+            .run(far_deadline(), || async {
+                assert_eq!(breaker.state(), BreakerState::HalfOpen);
+                Ok(())
+            })
+            .await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(resilience.circuit_breaker().state(), BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn breaker_per_upstream() {
+        let resilience = resilience(0, 1, Duration::from_secs(60));
+        let other = resilience.for_upstream("other");
+        run_failing(&resilience, || Failure::Transient).await;
+        assert_eq!(resilience.circuit_breaker().state(), BreakerState::Open);
+        assert_eq!(other.circuit_breaker().state(), BreakerState::Closed);
+        let (failure, attempts) = run_failing(&other, || Failure::Transient).await;
+        assert_eq!(failure, Failure::Transient);
+        assert_eq!(attempts, 1);
+        assert_eq!(other.circuit_breaker().state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn breaker_single_probe() {
+        let breaker = CircuitBreaker::new("test", 1, Duration::from_millis(10));
+        breaker.record_failure();
+        assert!(!breaker.acquire());
+        std::thread::sleep(Duration::from_millis(15));
+        assert!(breaker.acquire());
+        assert!(!breaker.acquire());
+        std::thread::sleep(Duration::from_millis(15));
+        assert!(breaker.acquire());
+    }
+}